@@ -0,0 +1,46 @@
+// Measures emulation speed on examples/workload.S, a copy and checksum loop
+// with a timer interrupt every 10000 instructions. Time is virtual, so every
+// run executes the same instructions.
+//
+//   cargo run --release --example mips -- [instructions] [interpreter|threaded]
+
+use std::time::Instant;
+
+use riscv_emu::bus::clint::TimeSource;
+use riscv_emu::{Engine, Machine, StopReason};
+
+const WORKLOAD: &[u8] = include_bytes!("workload.bin");
+const RUNS: usize = 3;
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let instructions = args
+        .next()
+        .map(|arg| arg.parse().expect("instructions must be a number"))
+        .unwrap_or(100_000_000);
+    let engine = match args.next().as_deref() {
+        None | Some("interpreter") => Engine::Interpreter,
+        Some("threaded") => Engine::Threaded,
+        Some(other) => panic!("Unknown engine {}", other),
+    };
+
+    for run in 1..=RUNS {
+        let mut machine = Machine::builder()
+            .kernel(WORKLOAD.to_vec())
+            .time_source(TimeSource::Virtual(1))
+            .engine(engine)
+            .build()
+            .expect("Failed to build the machine");
+        let start = Instant::now();
+        let reason = machine.run_until(instructions);
+        let elapsed = start.elapsed();
+        assert_eq!(reason, StopReason::LimitReached);
+        println!(
+            "run {}: {} instructions in {:.2?} ({:.2} MIPS)",
+            run,
+            machine.instructions(),
+            elapsed,
+            machine.instructions() as f64 / elapsed.as_secs_f64() / 1e6
+        );
+    }
+}
@@ -1,17 +1,28 @@
 #!/bin/bash
+# Extra arguments are passed to the emulator, e.g. --engine threaded
 
 cargo build -r
 
 failures=0
 
-for test_binary in tests/rv32*; do
-  printf "%-45s" "Running test: $test_binary..."
-  if target/release/riscv_emu --elf "$test_binary" --test > /dev/null 2>&1; then
+run_test() {
+  printf "%-45s" "Running test: $1..."
+  if target/release/riscv_emu --elf "$1" --test "${@:2}" > /dev/null 2>&1; then
     echo "[ OK ]"
   else
     echo "[FAIL]"
     failures=$((failures + 1))
   fi
+}
+
+for test_binary in tests/rv32*; do
+  run_test "$test_binary" "$@"
+done
+
+echo
+echo "Local tests, not part of the upstream riscv-tests (see tests/local):"
+for test_binary in tests/local-*; do
+  run_test "$test_binary" "$@"
 done
 
 echo
@@ -0,0 +1,387 @@
+use core::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::bus::plic::SOURCES;
+use crate::bus::{clint, plic, uart, DeviceLayout, UART_IRQ};
+use crate::cpu::csr::{BASE_Z_EXTENSIONS, MISA_A, MISA_C, MISA_D, MISA_F, MISA_M, Z_EXTENSIONS};
+use crate::cpu::RAM_START;
+use crate::machine::{MachineBuilder, RAM_SIZE};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BoardError {
+    Io(String),
+    Parse(String),
+    Invalid(String),
+}
+
+impl fmt::Display for BoardError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "Failed to read machine description: {}", e),
+            Self::Parse(e) => write!(f, "Invalid machine description: {}", e),
+            Self::Invalid(e) => write!(f, "Unsupported machine: {}", e),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub enum DeviceKind {
+    #[serde(rename = "ns16550a")]
+    Uart,
+    #[serde(rename = "clint")]
+    Clint,
+    #[serde(rename = "plic")]
+    Plic,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MemoryRegion {
+    #[serde(default = "default_memory_name")]
+    pub name: String,
+    pub base: usize,
+    pub size: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceConfig {
+    #[serde(rename = "type")]
+    pub kind: DeviceKind,
+    // Defaults to where the device is on the built-in machine
+    pub base: Option<usize>,
+    // PLIC source, only for the UART
+    pub irq: Option<usize>,
+}
+
+// Paths are relative to the description file
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BootImages {
+    pub kernel: Option<PathBuf>,
+    pub elf: Option<PathBuf>,
+    pub dtb: Option<PathBuf>,
+    pub initrd: Option<PathBuf>,
+}
+
+// Description of a SoC, read from TOML or JSON. Everything that is left out
+// is the same as on the built-in machine.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Board {
+    #[serde(default = "default_harts")]
+    pub harts: usize,
+    #[serde(default = "default_isa")]
+    pub isa: String,
+    pub timebase_frequency: Option<u64>,
+    // The first region holds the boot images and the DTB
+    #[serde(default = "default_memory")]
+    pub memory: Vec<MemoryRegion>,
+    #[serde(default = "default_devices")]
+    pub devices: Vec<DeviceConfig>,
+    #[serde(default)]
+    pub boot: BootImages,
+}
+
+fn default_harts() -> usize {
+    1
+}
+
+fn default_isa() -> String {
+    "rv32imafdc_zba_zbb_zbc_zbs".to_string()
+}
+
+fn default_memory_name() -> String {
+    "ram".to_string()
+}
+
+fn default_memory() -> Vec<MemoryRegion> {
+    vec![MemoryRegion {
+        name: default_memory_name(),
+        base: RAM_START,
+        size: RAM_SIZE,
+    }]
+}
+
+fn default_devices() -> Vec<DeviceConfig> {
+    [DeviceKind::Uart, DeviceKind::Clint, DeviceKind::Plic]
+        .iter()
+        .map(|kind| DeviceConfig {
+            kind: *kind,
+            base: None,
+            irq: None,
+        })
+        .collect()
+}
+
+// Optional extensions in an ISA string like "rv32imafdc_zba_zbb", where G
+// stands for IMAFD
+pub fn parse_isa(isa: &str) -> Result<u32, BoardError> {
+    let lower = isa.to_lowercase();
+    let mut parts = lower.split('_');
+    let base = parts.next().unwrap_or_default();
+    let (general, letters) = match (base.strip_prefix("rv32i"), base.strip_prefix("rv32g")) {
+        (Some(letters), _) => (0, letters),
+        (_, Some(letters)) => (MISA_M | MISA_A | MISA_F | MISA_D, letters),
+        _ => return Err(BoardError::Invalid(format!("{} is not an RV32I ISA", isa))),
+    };
+    let mut extensions = letters.chars().try_fold(general, |extensions, letter| {
+        let extension = match letter {
+            'm' => MISA_M,
+            'a' => MISA_A,
+            'f' => MISA_F,
+            'd' => MISA_D,
+            'c' => MISA_C,
+            _ => {
+                return Err(BoardError::Invalid(format!(
+                    "extension '{}' of {} is not implemented",
+                    letter, isa
+                )))
+            }
+        };
+        Ok(extensions | extension)
+    })?;
+    for name in parts.filter(|name| !BASE_Z_EXTENSIONS.contains(name)) {
+        let (_, extension) = Z_EXTENSIONS
+            .iter()
+            .find(|(known, _)| *known == name)
+            .ok_or_else(|| {
+                BoardError::Invalid(format!(
+                    "extension '{}' of {} is not implemented",
+                    name, isa
+                ))
+            })?;
+        extensions |= extension;
+    }
+    if extensions & MISA_D != 0 && extensions & MISA_F == 0 {
+        return Err(BoardError::Invalid(format!(
+            "{} has the D extension without F",
+            isa
+        )));
+    }
+    Ok(extensions)
+}
+
+impl Board {
+    pub fn from_toml(text: &str) -> Result<Self, BoardError> {
+        let board: Self = toml::from_str(text).map_err(|e| BoardError::Parse(e.to_string()))?;
+        board.validate()?;
+        Ok(board)
+    }
+
+    pub fn from_json(text: &str) -> Result<Self, BoardError> {
+        let board: Self =
+            serde_json::from_str(text).map_err(|e| BoardError::Parse(e.to_string()))?;
+        board.validate()?;
+        Ok(board)
+    }
+
+    // JSON if the file ends in .json, TOML otherwise
+    pub fn load(path: &Path) -> Result<Self, BoardError> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| BoardError::Io(format!("{}: {}", path.display(), e)))?;
+        let mut board = match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => Self::from_json(&text)?,
+            _ => Self::from_toml(&text)?,
+        };
+        let dir = path.parent().unwrap_or(Path::new(""));
+        let mut images = [
+            &mut board.boot.kernel,
+            &mut board.boot.elf,
+            &mut board.boot.dtb,
+            &mut board.boot.initrd,
+        ];
+        for image in images.iter_mut().filter_map(|image| image.as_mut()) {
+            *image = dir.join(&*image);
+        }
+        Ok(board)
+    }
+
+    fn validate(&self) -> Result<(), BoardError> {
+        if self.harts != 1 {
+            return Err(BoardError::Invalid(format!(
+                "{} harts, only single-hart machines are emulated",
+                self.harts
+            )));
+        }
+        parse_isa(&self.isa)?;
+        if let Some(frequency) = self.timebase_frequency {
+            if !clint::valid_timebase_frequency(frequency) {
+                return Err(BoardError::Invalid(format!(
+                    "timebase frequency {} Hz is not between 1 and {} Hz",
+                    frequency,
+                    u32::MAX
+                )));
+            }
+        }
+        if self.memory.is_empty() {
+            return Err(BoardError::Invalid("no memory regions".to_string()));
+        }
+        for (index, device) in self.devices.iter().enumerate() {
+            if self.devices[..index].iter().any(|d| d.kind == device.kind) {
+                return Err(BoardError::Invalid(format!(
+                    "more than one {:?} device",
+                    device.kind
+                )));
+            }
+            match (device.kind, device.irq) {
+                (_, None) => {}
+                (DeviceKind::Uart, Some(irq)) if irq > 0 && irq < SOURCES => {}
+                (DeviceKind::Uart, Some(irq)) => {
+                    return Err(BoardError::Invalid(format!(
+                        "UART IRQ {} is not a PLIC source between 1 and {}",
+                        irq,
+                        SOURCES - 1
+                    )))
+                }
+                (kind, Some(_)) => {
+                    return Err(BoardError::Invalid(format!(
+                        "{:?} does not have an IRQ",
+                        kind
+                    )))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn layout(&self) -> DeviceLayout {
+        let base = |kind: DeviceKind, default: usize| {
+            self.devices
+                .iter()
+                .find(|device| device.kind == kind)
+                .map(|device| device.base.unwrap_or(default))
+        };
+        let uart_irq = self
+            .devices
+            .iter()
+            .find_map(|device| device.irq)
+            .unwrap_or(UART_IRQ);
+        DeviceLayout {
+            uart: base(DeviceKind::Uart, uart::BASE_ADDR),
+            uart_irq,
+            clint: base(DeviceKind::Clint, clint::BASE_ADDR),
+            plic: base(DeviceKind::Plic, plic::BASE_ADDR),
+        }
+    }
+
+    // Apply everything but the boot images, which the caller loads
+    pub fn configure(&self, mut builder: MachineBuilder) -> MachineBuilder {
+        let ram = &self.memory[0];
+        builder = builder
+            .ram_base(ram.base)
+            .ram_size(ram.size)
+            .layout(self.layout())
+            .extensions(parse_isa(&self.isa).unwrap_or(0));
+        for region in &self.memory[1..] {
+            builder = builder.ram_region(&region.name, region.base, region.size);
+        }
+        if let Some(frequency) = self.timebase_frequency {
+            builder = builder.timebase_frequency(frequency);
+        }
+        builder
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::csr::{EXT_ZBA, EXT_ZBB, EXT_ZBS};
+
+    #[test]
+    fn test_parse_board() {
+        let board = Board::from_toml(
+            r#"
+            isa = "rv32imc"
+            timebase-frequency = 10_000_000
+
+            [[memory]]
+            base = 0x4000_0000
+            size = 0x10_0000
+
+            [[memory]]
+            name = "sram"
+            base = 0x2000_0000
+            size = 0x1000
+
+            [[devices]]
+            type = "ns16550a"
+            base = 0x3000_0000
+            irq = 3
+
+            [[devices]]
+            type = "plic"
+
+            [boot]
+            kernel = "Image"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(board.harts, 1);
+        assert_eq!(parse_isa(&board.isa), Ok(MISA_M | MISA_C));
+        assert_eq!(board.memory[1].name, "sram");
+        assert_eq!(board.boot.kernel, Some(PathBuf::from("Image")));
+        assert_eq!(
+            board.layout(),
+            DeviceLayout {
+                uart: Some(0x3000_0000),
+                uart_irq: 3,
+                clint: None,
+                plic: Some(plic::BASE_ADDR),
+            }
+        );
+
+        let json =
+            Board::from_json(r#"{"isa": "rv32imac", "memory": [{"base": 0, "size": 4096}]}"#)
+                .unwrap();
+        assert_eq!(json.devices, default_devices());
+        assert_eq!(json.memory[0].name, "ram");
+    }
+    #[test]
+    fn test_parse_isa() {
+        assert_eq!(
+            parse_isa("rv32gc_zba_zbb"),
+            Ok(MISA_M | MISA_A | MISA_F | MISA_D | MISA_C | EXT_ZBA | EXT_ZBB)
+        );
+        // As in GCC's -march and current device trees
+        assert_eq!(
+            parse_isa("rv32imac_zicsr_zifencei"),
+            Ok(MISA_M | MISA_A | MISA_C)
+        );
+        assert_eq!(parse_isa("rv32i_zicntr_zihpm_zbs"), Ok(EXT_ZBS));
+    }
+    #[test]
+    fn test_invalid_board() {
+        assert!(matches!(
+            Board::from_toml("harts = 2"),
+            Err(BoardError::Invalid(_))
+        ));
+        assert!(matches!(
+            Board::from_toml("isa = \"rv32imadc\""),
+            Err(BoardError::Invalid(_))
+        ));
+        assert!(matches!(
+            Board::from_toml("isa = \"rv32gc_zbkb\""),
+            Err(BoardError::Invalid(_))
+        ));
+        assert!(matches!(
+            Board::from_toml("[[devices]]\ntype = \"clint\"\nirq = 1"),
+            Err(BoardError::Invalid(_))
+        ));
+        assert!(matches!(
+            Board::from_toml("timebase-frequency = 0"),
+            Err(BoardError::Invalid(_))
+        ));
+        assert!(matches!(
+            Board::from_toml("timebase-frequency = 0x1_0000_0000"),
+            Err(BoardError::Invalid(_))
+        ));
+        assert!(matches!(
+            Board::from_toml("cores = 1"),
+            Err(BoardError::Parse(_))
+        ));
+    }
+}
@@ -1,12 +1,19 @@
 pub mod clint;
+pub mod map;
+pub mod plic;
 pub mod ram;
 pub mod uart;
 
 use self::clint::Clint;
+use self::map::{MapError, MemoryMap, Region};
+use self::plic::{Plic, CONTEXT_MACHINE, CONTEXT_SUPERVISOR};
 use self::ram::Ram;
-use self::uart::Uart;
+use self::uart::{Uart, UartInput, UartOutput};
+use crate::cpu::csr::CSRFile;
 
 use core::fmt;
+use std::cell::RefCell;
+use std::rc::Rc;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum BusError {
@@ -39,7 +46,7 @@ macro_rules! buswidth {
             const WIDTH: usize = std::mem::size_of::<$t>();
 
             fn is_aligned(addr: usize) -> bool {
-                (addr as usize % Self::WIDTH) == 0
+                addr.is_multiple_of(Self::WIDTH)
             }
 
             fn to_mem(val: $t, dst: &mut [u8]) {
@@ -56,6 +63,7 @@ macro_rules! buswidth {
     };
 }
 
+buswidth!(u64);
 buswidth!(u32);
 buswidth!(i32);
 buswidth!(u16);
@@ -65,7 +73,7 @@ buswidth!(i8);
 
 pub trait BusDevice {
     fn addr_space(&self) -> (usize, usize);
-    fn load<T: BusWidth<T> + std::fmt::Display>(&self, addr: usize) -> Result<T, BusError>;
+    fn load<T: BusWidth<T> + std::fmt::Display>(&mut self, addr: usize) -> Result<T, BusError>;
     fn store<T: BusWidth<T> + std::fmt::Display>(
         &mut self,
         addr: usize,
@@ -73,43 +81,184 @@ pub trait BusDevice {
     ) -> Result<(), BusError>;
 }
 
+// Object-safe view of a BusDevice, so that the bus can dispatch to devices
+// registered at runtime. Addresses are absolute, values are zero-extended.
+pub trait Device {
+    fn read(&mut self, addr: usize, width: usize) -> Result<u32, BusError>;
+    fn write(&mut self, addr: usize, width: usize, value: u32) -> Result<(), BusError>;
+}
+
+impl<D: BusDevice> Device for D {
+    fn read(&mut self, addr: usize, width: usize) -> Result<u32, BusError> {
+        match width {
+            1 => self.load::<u8>(addr).map(u32::from),
+            2 => self.load::<u16>(addr).map(u32::from),
+            _ => self.load::<u32>(addr),
+        }
+    }
+
+    fn write(&mut self, addr: usize, width: usize, value: u32) -> Result<(), BusError> {
+        match width {
+            1 => self.store::<u8>(addr, value as u8),
+            2 => self.store::<u16>(addr, value as u16),
+            _ => self.store::<u32>(addr, value),
+        }
+    }
+}
+
+// Interrupt sources at the PLIC
+pub const UART_IRQ: usize = 10;
+
+// Where the built-in devices are mapped, None leaves a device out
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceLayout {
+    pub uart: Option<usize>,
+    pub uart_irq: usize,
+    pub clint: Option<usize>,
+    pub plic: Option<usize>,
+}
+
+impl Default for DeviceLayout {
+    fn default() -> Self {
+        Self {
+            uart: Some(uart::BASE_ADDR),
+            uart_irq: UART_IRQ,
+            clint: Some(clint::BASE_ADDR),
+            plic: Some(plic::BASE_ADDR),
+        }
+    }
+}
+
 pub struct Bus {
-    uart: Uart,
+    // RAM is checked before the memory map, as most accesses go there
     pub ram: Ram,
-    pub clint: Clint,
+    map: MemoryMap,
+    layout: DeviceLayout,
+    uart: Rc<RefCell<Uart>>,
+    pub clint: Rc<RefCell<Clint>>,
+    pub plic: Rc<RefCell<Plic>>,
 }
 
 impl Bus {
-    pub fn new(ram: Vec<u8>, ram_start: usize) -> Self {
-        Self {
+    pub fn new(ram: Vec<u8>, ram_start: usize) -> Result<Self, MapError> {
+        Self::with_layout(ram, ram_start, DeviceLayout::default())
+    }
+
+    pub fn with_layout(
+        ram: Vec<u8>,
+        ram_start: usize,
+        layout: DeviceLayout,
+    ) -> Result<Self, MapError> {
+        let mut bus = Self {
             ram: Ram::new(ram, ram_start),
-            uart: Uart::new(),
-            clint: Clint::new(),
+            map: MemoryMap::new(),
+            layout: layout.clone(),
+            uart: Rc::new(RefCell::new(Uart::new())),
+            clint: Rc::new(RefCell::new(Clint::new())),
+            plic: Rc::new(RefCell::new(Plic::new())),
+        };
+        let (ram_lower, ram_upper) = bus.ram.addr_space();
+        bus.map
+            .register("ram", ram_lower, ram_upper - ram_lower, None)?;
+        if let Some(base) = layout.uart {
+            bus.uart.borrow_mut().set_base(base);
+            let (lower, upper) = bus.uart.borrow().addr_space();
+            bus.register_device("uart", lower, upper - lower, bus.uart.clone())?;
+        }
+        if let Some(base) = layout.clint {
+            bus.clint.borrow_mut().set_base(base);
+            let (lower, upper) = bus.clint.borrow().addr_space();
+            bus.register_device("clint", lower, upper - lower, bus.clint.clone())?;
+        }
+        if let Some(base) = layout.plic {
+            bus.plic.borrow_mut().set_base(base);
+            let (lower, upper) = bus.plic.borrow().addr_space();
+            bus.register_device("plic", lower, upper - lower, bus.plic.clone())?;
+        }
+        Ok(bus)
+    }
+
+    // Additional RAM outside of the fast path, e.g. SRAM next to DRAM
+    pub fn add_ram(&mut self, name: &str, base: usize, size: usize) -> Result<(), MapError> {
+        let ram = Rc::new(RefCell::new(Ram::new(vec![0; size], base)));
+        self.register_device(name, base, size, ram)
+    }
+
+    // Map `size` bytes at `base` to a device. Devices occupy whole pages.
+    pub fn register_device(
+        &mut self,
+        name: &str,
+        base: usize,
+        size: usize,
+        device: Rc<RefCell<dyn Device>>,
+    ) -> Result<(), MapError> {
+        self.map.register(name, base, size, Some(device))
+    }
+
+    pub fn memory_map(&self) -> &[Region] {
+        self.map.regions()
+    }
+
+    fn device(&self, addr: usize) -> Result<&Rc<RefCell<dyn Device>>, BusError> {
+        self.map
+            .find(addr)
+            .and_then(|region| region.device.as_ref())
+            .ok_or(BusError::AddressUnmapped(addr))
+    }
+
+    pub fn set_uart_output(&mut self, output: UartOutput) {
+        self.uart.borrow_mut().set_output(output);
+    }
+
+    pub fn set_uart_input(&mut self, input: UartInput) {
+        self.uart.borrow_mut().set_input(input);
+    }
+
+    // Called instead of executing an instruction while the hart waits for an interrupt
+    pub fn idle(&mut self, csrfile: &CSRFile) {
+        self.clint.borrow_mut().idle(csrfile.timer_enabled());
+        self.uart.borrow_mut().poll();
+    }
+
+    // Advance devices by the given number of executed instructions
+    pub fn tick(&mut self, csrfile: &mut CSRFile, instructions: u32) {
+        // Devices that aren't mapped can't raise interrupts either
+        if self.layout.clint.is_some() {
+            self.clint.borrow_mut().tick(csrfile, instructions as u64);
+        }
+        let mut uart = self.uart.borrow_mut();
+        uart.tick(instructions);
+
+        // Route device interrupts through the PLIC into MEIP/SEIP
+        if self.layout.plic.is_some() {
+            let mut plic = self.plic.borrow_mut();
+            plic.set_irq(self.layout.uart_irq, uart.interrupt());
+            csrfile.set_meip(plic.interrupt(CONTEXT_MACHINE));
+            csrfile.set_seip(plic.interrupt(CONTEXT_SUPERVISOR));
         }
     }
 }
 
 impl BusDevice for Bus {
-    fn load<T: BusWidth<T> + std::fmt::Display>(&self, addr: usize) -> Result<T, BusError> {
+    fn load<T: BusWidth<T> + std::fmt::Display>(&mut self, addr: usize) -> Result<T, BusError> {
         if !T::is_aligned(addr) {
             return Err(BusError::AddressMisaligned(addr));
         }
-        // TODO: Iterate Bus Devices
         let (ram_lower, ram_upper) = self.ram.addr_space();
-        if addr >= ram_lower && addr < ram_upper {
+        if addr >= ram_lower && addr + T::WIDTH <= ram_upper {
             return self.ram.load(addr);
         }
-        let (uart_lower, uart_upper) = self.uart.addr_space();
-        if addr >= uart_lower && addr < uart_upper {
-            return self.uart.load(addr);
-        }
-        let (clint_lower, clint_upper) = self.clint.addr_space();
-        if addr >= clint_lower && addr < clint_upper {
-            return self.clint.load(addr);
+        // Devices are at most 32 bits wide, wider accesses are split
+        let mut bytes = [0; 8];
+        for offset in (0..T::WIDTH).step_by(4) {
+            let width = T::WIDTH.min(4);
+            let value = self
+                .device(addr + offset)?
+                .borrow_mut()
+                .read(addr + offset, width)?;
+            bytes[offset..offset + width].copy_from_slice(&value.to_le_bytes()[..width]);
         }
-
-        // Load from unmapped address
-        Err(BusError::AddressUnmapped(addr))
+        Ok(T::from_mem(&bytes[..T::WIDTH]))
     }
 
     fn store<T: BusWidth<T> + std::fmt::Display>(
@@ -121,24 +270,89 @@ impl BusDevice for Bus {
             return Err(BusError::AddressMisaligned(addr));
         }
         let (ram_lower, ram_upper) = self.ram.addr_space();
-        // TODO: Iterate Bus Devices
-        if addr >= ram_lower && addr < ram_upper {
+        if addr >= ram_lower && addr + T::WIDTH <= ram_upper {
             return self.ram.store(addr, data);
         }
-        let (uart_lower, uart_upper) = self.uart.addr_space();
-        if addr >= uart_lower && addr < uart_upper {
-            return self.uart.store(addr, data);
+        let mut bytes = [0; 8];
+        T::to_mem(data, &mut bytes[..T::WIDTH]);
+        for offset in (0..T::WIDTH).step_by(4) {
+            let width = T::WIDTH.min(4);
+            let mut value = [0; 4];
+            value[..width].copy_from_slice(&bytes[offset..offset + width]);
+            self.device(addr + offset)?.borrow_mut().write(
+                addr + offset,
+                width,
+                u32::from_le_bytes(value),
+            )?;
         }
-        let (clint_lower, clint_upper) = self.clint.addr_space();
-        if addr >= clint_lower && addr < clint_upper {
-            return self.clint.store(addr, data);
+        Ok(())
+    }
+
+    fn addr_space(&self) -> (usize, usize) {
+        (0, usize::MAX)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A single scratch register
+    struct Scratch(u32);
+
+    impl BusDevice for Scratch {
+        fn addr_space(&self) -> (usize, usize) {
+            (0x2000_0000, 0x2000_0004)
+        }
+
+        fn load<T: BusWidth<T> + std::fmt::Display>(&mut self, _: usize) -> Result<T, BusError> {
+            Ok(T::from_mem(&self.0.to_le_bytes()[..T::WIDTH]))
         }
 
-        // Store to unmapped address
-        Err(BusError::AddressUnmapped(addr))
+        fn store<T: BusWidth<T> + std::fmt::Display>(
+            &mut self,
+            _: usize,
+            data: T,
+        ) -> Result<(), BusError> {
+            let mut bytes = [0; 4];
+            T::to_mem(data, &mut bytes[..T::WIDTH]);
+            self.0 = u32::from_le_bytes(bytes);
+            Ok(())
+        }
     }
 
-    fn addr_space(&self) -> (usize, usize) {
-        return (0, usize::MAX);
+    #[test]
+    fn test_register_device() {
+        let mut bus = Bus::new(vec![0; 0x1000], 0x8000_0000).unwrap();
+        let scratch = Rc::new(RefCell::new(Scratch(0)));
+        bus.register_device("scratch", 0x2000_0000, 4, scratch.clone())
+            .unwrap();
+        assert!(bus
+            .register_device("again", 0x2000_0800, 4, scratch.clone())
+            .is_err());
+
+        bus.store::<u32>(0x2000_0000, 0xffff_fff0).unwrap();
+        assert_eq!(scratch.borrow().0, 0xffff_fff0);
+        assert_eq!(bus.load::<i8>(0x2000_0000), Ok(-16));
+        assert_eq!(bus.load::<u16>(0x2000_0000), Ok(0xfff0));
+        assert_eq!(
+            bus.load::<u32>(0x2000_0004),
+            Err(BusError::AddressUnmapped(0x2000_0004))
+        );
+        // 64-bit accesses are split into two 32-bit ones
+        assert_eq!(
+            bus.load::<u64>(0x2000_0000),
+            Err(BusError::AddressUnmapped(0x2000_0004))
+        );
+        bus.store::<u64>(0x8000_0ff8, 0x0123_4567_89ab_cdef)
+            .unwrap();
+        assert_eq!(bus.load::<u32>(0x8000_0ffc), Ok(0x0123_4567));
+    }
+    #[test]
+    fn test_ram_overlap() {
+        assert_eq!(
+            Bus::new(vec![0; 0x1000], 0x0c00_0000).err(),
+            Some(MapError::Overlap("plic".to_string(), "ram".to_string()))
+        );
     }
 }
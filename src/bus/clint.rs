@@ -1,72 +1,154 @@
 use crate::cpu::csr::CSRFile;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use super::BusDevice;
 use enum_primitive_derive::Primitive;
 use num_traits::FromPrimitive;
 
 // https://chromitem-soc.readthedocs.io/en/latest/clint.html
-const BASE_ADDR: usize = 0x1100_0000;
-const SIZE: usize = 0xBFFF;
+pub const BASE_ADDR: usize = 0x1100_0000;
+pub const SIZE: usize = 0xc000;
 
 #[derive(Debug, Clone, Eq, Hash, PartialEq, Primitive)]
 #[allow(non_camel_case_types)]
 pub enum ClintRegisters {
+    MSIP = 0x0000,
     MTIMECMP_H = 0x4004,
     MTIMECMP_L = 0x4000,
     MTIME_H = 0xBFFC,
     MTIME_L = 0xBFF8,
 }
 
+// Where mtime comes from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimeSource {
+    // Follows the host clock at the timebase frequency
+    WallClock,
+    // Advances by the given number of ticks per instruction, so runs are reproducible
+    Virtual(u64),
+}
+
+// Longest stretch that a waiting hart sleeps on the host clock before it
+// checks its other interrupt sources again
+const MAX_IDLE_SLEEP: Duration = Duration::from_millis(1);
+
+// Matches the 1 us resolution that mtime always had
+pub const DEFAULT_TIMEBASE_FREQUENCY: u64 = 1_000_000;
+
+// mtime has to advance, and the DTB holds its rate in a single 32-bit cell
+pub fn valid_timebase_frequency(frequency: u64) -> bool {
+    frequency > 0 && frequency <= u32::MAX as u64
+}
+
 pub struct Clint {
+    base: usize,
     start_time: Instant,
+    time_source: TimeSource,
+    timebase_frequency: u64,
+    // Only bit 0 is implemented, it drives MSIP of hart 0
+    msip: u32,
     mtimecmp: u64,
     mtime: u64,
+    // Ticks that passed while the hart was waiting for an interrupt
+    idle_ticks: u64,
 }
 
 impl Clint {
     pub fn new() -> Self {
         Self {
+            base: BASE_ADDR,
             start_time: Instant::now(),
+            time_source: TimeSource::WallClock,
+            timebase_frequency: DEFAULT_TIMEBASE_FREQUENCY,
+            msip: 0,
             mtimecmp: u32::MAX as u64,
             mtime: 0,
+            idle_ticks: 0,
         }
     }
 
-    pub fn tick(&mut self, csrfile: &mut CSRFile) {
-        self.mtime = self.start_time.elapsed().as_micros() as u64;
+    pub fn set_base(&mut self, base: usize) {
+        self.base = base;
+    }
+
+    pub fn set_time_source(&mut self, time_source: TimeSource) {
+        self.time_source = time_source;
+    }
+
+    pub fn set_timebase_frequency(&mut self, timebase_frequency: u64) {
+        self.timebase_frequency = timebase_frequency;
+    }
+
+    pub fn idle_ticks(&self) -> u64 {
+        self.idle_ticks
+    }
+
+    // Let time pass towards mtimecmp while the hart waits for an interrupt
+    pub fn idle(&mut self, timer_enabled: bool) {
+        let remaining = match timer_enabled {
+            true => self.mtimecmp.saturating_sub(self.mtime),
+            false => u64::MAX,
+        };
+        match self.time_source {
+            TimeSource::WallClock => {
+                let nanos = remaining as u128 * 1_000_000_000 / self.timebase_frequency as u128;
+                let sleep = MAX_IDLE_SLEEP.min(Duration::from_nanos(nanos as u64));
+                let start = Instant::now();
+                std::thread::sleep(sleep);
+                let slept = start.elapsed().as_nanos();
+                self.idle_ticks += (slept * self.timebase_frequency as u128 / 1_000_000_000) as u64;
+            }
+            // Without the timer, virtual time keeps going at its normal pace
+            TimeSource::Virtual(_) if timer_enabled => {
+                self.mtime += remaining;
+                self.idle_ticks += remaining;
+            }
+            TimeSource::Virtual(_) => (),
+        }
+    }
+
+    // Account for a number of instructions that were executed since the last tick
+    pub fn tick(&mut self, csrfile: &mut CSRFile, instructions: u64) {
+        self.mtime = match self.time_source {
+            TimeSource::WallClock => {
+                let nanos = self.start_time.elapsed().as_nanos();
+                (nanos * self.timebase_frequency as u128 / 1_000_000_000) as u64
+            }
+            TimeSource::Virtual(ticks) => self.mtime.wrapping_add(ticks * instructions),
+        };
+        csrfile.counters.set_time(self.mtime);
         if self.mtime >= self.mtimecmp {
             csrfile.set_mtip(true);
         } else {
             csrfile.set_mtip(false);
         }
+        csrfile.set_msip(self.msip & 1 != 0);
+    }
+}
+
+impl Default for Clint {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 impl BusDevice for Clint {
     fn load<T: super::BusWidth<T> + std::fmt::Display>(
-        &self,
+        &mut self,
         addr: usize,
     ) -> Result<T, super::BusError> {
-        let offset = addr - BASE_ADDR;
+        let offset = addr - self.base;
         if !T::is_aligned(offset) {
             return Err(super::BusError::AddressMisaligned(addr));
         }
 
         match ClintRegisters::from_usize(offset) {
-            Some(ClintRegisters::MTIMECMP_H) => {
-                return Ok(T::from_mem(&self.mtimecmp.to_le_bytes()[4..]));
-            }
-            Some(ClintRegisters::MTIMECMP_L) => {
-                return Ok(T::from_mem(&self.mtimecmp.to_le_bytes()[..4]));
-            }
-            Some(ClintRegisters::MTIME_H) => {
-                return Ok(T::from_mem(&self.mtime.to_le_bytes()[4..]));
-            }
-            Some(ClintRegisters::MTIME_L) => {
-                return Ok(T::from_mem(&self.mtime.to_le_bytes()[..4]));
-            }
-            None => return Ok(T::from_mem(&[0])),
+            Some(ClintRegisters::MSIP) => Ok(T::from_mem(&self.msip.to_le_bytes())),
+            Some(ClintRegisters::MTIMECMP_H) => Ok(T::from_mem(&self.mtimecmp.to_le_bytes()[4..])),
+            Some(ClintRegisters::MTIMECMP_L) => Ok(T::from_mem(&self.mtimecmp.to_le_bytes()[..4])),
+            Some(ClintRegisters::MTIME_H) => Ok(T::from_mem(&self.mtime.to_le_bytes()[4..])),
+            Some(ClintRegisters::MTIME_L) => Ok(T::from_mem(&self.mtime.to_le_bytes()[..4])),
+            None => Ok(T::from_mem(&[0])),
         }
     }
 
@@ -75,11 +157,16 @@ impl BusDevice for Clint {
         addr: usize,
         data: T,
     ) -> Result<(), super::BusError> {
-        let offset = addr - BASE_ADDR;
+        let offset = addr - self.base;
         if !T::is_aligned(offset) {
             return Err(super::BusError::AddressMisaligned(addr));
         }
         match ClintRegisters::from_usize(offset) {
+            Some(ClintRegisters::MSIP) => {
+                let mut new_msip = [0u8; 4];
+                T::to_mem(data, &mut new_msip);
+                self.msip = u32::from_le_bytes(new_msip) & 1;
+            }
             Some(ClintRegisters::MTIMECMP_H) => {
                 let mut new_high = [0u8; 4];
                 T::to_mem(data, &mut new_high);
@@ -98,6 +185,67 @@ impl BusDevice for Clint {
     }
 
     fn addr_space(&self) -> (usize, usize) {
-        (BASE_ADDR, BASE_ADDR + SIZE)
+        (self.base, self.base + SIZE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::csr::ArchCSRs;
+
+    #[test]
+    fn test_msip() {
+        let mut dut = Clint::new();
+        let mut csrfile = CSRFile::new();
+        dut.store::<u32>(BASE_ADDR, 0xffff_ffff).unwrap();
+        assert_eq!(dut.load::<u32>(BASE_ADDR), Ok(1));
+
+        dut.tick(&mut csrfile, 1);
+        assert_eq!(csrfile.read(ArchCSRs::mip as i32) & 0x8, 0x8);
+        dut.store::<u32>(BASE_ADDR, 0).unwrap();
+        dut.tick(&mut csrfile, 1);
+        assert_eq!(csrfile.read(ArchCSRs::mip as i32) & 0x8, 0);
+    }
+    #[test]
+    fn test_virtual_time() {
+        let mut dut = Clint::new();
+        let mut csrfile = CSRFile::new();
+        dut.set_time_source(TimeSource::Virtual(10));
+        dut.store::<u32>(BASE_ADDR + ClintRegisters::MTIMECMP_L as usize, 30)
+            .unwrap();
+        dut.store::<u32>(BASE_ADDR + ClintRegisters::MTIMECMP_H as usize, 0)
+            .unwrap();
+
+        for _ in 0..2 {
+            dut.tick(&mut csrfile, 1);
+        }
+        assert_eq!(
+            dut.load::<u32>(BASE_ADDR + ClintRegisters::MTIME_L as usize),
+            Ok(20)
+        );
+        assert_eq!(csrfile.read(ArchCSRs::time as i32), 20);
+        assert_eq!(csrfile.read(ArchCSRs::mip as i32) & 0x80, 0);
+        dut.tick(&mut csrfile, 1);
+        assert_eq!(csrfile.read(ArchCSRs::mip as i32) & 0x80, 0x80);
+    }
+    #[test]
+    fn test_virtual_idle() {
+        let mut dut = Clint::new();
+        let mut csrfile = CSRFile::new();
+        dut.set_time_source(TimeSource::Virtual(1));
+        dut.store::<u32>(BASE_ADDR + ClintRegisters::MTIMECMP_L as usize, 500)
+            .unwrap();
+        dut.store::<u32>(BASE_ADDR + ClintRegisters::MTIMECMP_H as usize, 0)
+            .unwrap();
+        dut.tick(&mut csrfile, 1);
+
+        // Time only jumps ahead if the timer can wake the hart
+        dut.idle(false);
+        assert_eq!(dut.idle_ticks(), 0);
+        dut.idle(true);
+        assert_eq!(dut.idle_ticks(), 499);
+        dut.tick(&mut csrfile, 1);
+        assert_eq!(csrfile.read(ArchCSRs::mip as i32) & 0x80, 0x80);
     }
 }
@@ -0,0 +1,188 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+
+use super::Device;
+
+const PAGE_SHIFT: usize = 12;
+// The lookup table covers the 32-bit physical address space
+const PAGES: usize = 1 << (32 - PAGE_SHIFT);
+// Page table entries are u8, with 0 for unmapped pages
+const MAX_REGIONS: usize = u8::MAX as usize;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MapError {
+    Overlap(String, String),
+    // Devices occupy whole pages, so they can't share one even if they don't overlap
+    SharedPage(String, String),
+    OutOfRange(String),
+    Empty(String),
+    // Pages refer to regions by an 8-bit index
+    TooManyRegions(String),
+}
+
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Overlap(name, other) => {
+                write!(f, "{} overlaps {} in the memory map", name, other)
+            }
+            Self::SharedPage(name, other) => write!(
+                f,
+                "{} shares a 4 KiB page with {}, but every page holds only one device",
+                name, other
+            ),
+            Self::OutOfRange(name) => write!(f, "{} is outside of the 32-bit address space", name),
+            Self::Empty(name) => write!(f, "{} has a size of 0", name),
+            Self::TooManyRegions(name) => write!(
+                f,
+                "{} can't be mapped, the memory map holds at most {} regions",
+                name, MAX_REGIONS
+            ),
+        }
+    }
+}
+
+pub struct Region {
+    pub name: String,
+    pub base: usize,
+    pub end: usize,
+    // RAM is accessed by the bus directly and only registered to catch overlaps
+    pub device: Option<Rc<RefCell<dyn Device>>>,
+}
+
+// Devices by address, with one table entry per 4 KiB page for O(1) lookups
+pub struct MemoryMap {
+    regions: Vec<Region>,
+    // Index into regions plus one, zero for unmapped pages
+    pages: Vec<u8>,
+}
+
+impl MemoryMap {
+    pub fn new() -> Self {
+        Self {
+            regions: Vec::new(),
+            pages: vec![0; PAGES],
+        }
+    }
+
+    pub fn register(
+        &mut self,
+        name: &str,
+        base: usize,
+        size: usize,
+        device: Option<Rc<RefCell<dyn Device>>>,
+    ) -> Result<(), MapError> {
+        let out_of_range = || MapError::OutOfRange(name.to_string());
+        if size == 0 {
+            return Err(MapError::Empty(name.to_string()));
+        }
+        let first = base >> PAGE_SHIFT;
+        let last = base.checked_add(size - 1).ok_or_else(out_of_range)? >> PAGE_SHIFT;
+        if last >= PAGES {
+            return Err(out_of_range());
+        }
+        if let Some(&index) = self.pages[first..=last].iter().find(|index| **index != 0) {
+            let other = &self.regions[index as usize - 1];
+            if base < other.end && other.base < base + size {
+                return Err(MapError::Overlap(name.to_string(), other.name.clone()));
+            }
+            return Err(MapError::SharedPage(name.to_string(), other.name.clone()));
+        }
+        if self.regions.len() == MAX_REGIONS {
+            return Err(MapError::TooManyRegions(name.to_string()));
+        }
+
+        self.regions.push(Region {
+            name: name.to_string(),
+            base,
+            end: base + size,
+            device,
+        });
+        let index = self.regions.len() as u8;
+        self.pages[first..=last].fill(index);
+        Ok(())
+    }
+
+    pub fn find(&self, addr: usize) -> Option<&Region> {
+        let index = *self.pages.get(addr >> PAGE_SHIFT)?;
+        let region = self.regions.get((index as usize).checked_sub(1)?)?;
+        (addr >= region.base && addr < region.end).then_some(region)
+    }
+
+    pub fn regions(&self) -> &[Region] {
+        &self.regions
+    }
+}
+
+impl Default for MemoryMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::uart::Uart;
+
+    #[test]
+    fn test_lookup() {
+        let mut dut = MemoryMap::new();
+        dut.register("ram", 0x8000_0000, 0x2000, None).unwrap();
+        dut.register(
+            "uart",
+            0x1000_0000,
+            0x100,
+            Some(Rc::new(RefCell::new(Uart::new()))),
+        )
+        .unwrap();
+
+        assert_eq!(dut.find(0x8000_1fff).map(|r| r.name.as_str()), Some("ram"));
+        assert_eq!(dut.find(0x1000_0005).map(|r| r.name.as_str()), Some("uart"));
+        // Within the page, but past the end of the device
+        assert!(dut.find(0x1000_0100).is_none());
+        assert!(dut.find(0x8000_2000).is_none());
+        assert!(dut.find(0x1_0000_0000).is_none());
+    }
+    #[test]
+    fn test_overlap() {
+        let mut dut = MemoryMap::new();
+        dut.register("ram", 0x8000_0000, 0x2000, None).unwrap();
+        assert_eq!(
+            dut.register("rom", 0x8000_1800, 0x1000, None),
+            Err(MapError::Overlap("rom".to_string(), "ram".to_string()))
+        );
+        assert_eq!(
+            dut.register("high", 0xffff_f000, 0x2000, None),
+            Err(MapError::OutOfRange("high".to_string()))
+        );
+        assert_eq!(
+            dut.register("empty", 0, 0, None),
+            Err(MapError::Empty("empty".to_string()))
+        );
+        assert_eq!(
+            dut.register("wrap", usize::MAX, 2, None),
+            Err(MapError::OutOfRange("wrap".to_string()))
+        );
+        // Sub-page devices don't overlap, but can't share a page
+        assert_eq!(dut.register("uart", 0x8000_2000, 0x100, None), Ok(()));
+        assert_eq!(
+            dut.register("gpio", 0x8000_2100, 0x100, None),
+            Err(MapError::SharedPage("gpio".to_string(), "uart".to_string()))
+        );
+        assert_eq!(dut.regions().len(), 2);
+    }
+    #[test]
+    fn test_too_many_regions() {
+        let mut dut = MemoryMap::new();
+        for index in 0..MAX_REGIONS {
+            dut.register("ram", index << PAGE_SHIFT, 0x1000, None)
+                .unwrap();
+        }
+        assert_eq!(
+            dut.register("rom", 0x8000_0000, 0x1000, None),
+            Err(MapError::TooManyRegions("rom".to_string()))
+        );
+    }
+}
@@ -0,0 +1,254 @@
+use super::BusDevice;
+
+// Register layout of the SiFive PLIC, as used by Linux' riscv,plic0 driver
+pub const BASE_ADDR: usize = 0x0c00_0000;
+pub const SIZE: usize = 0x0400_0000;
+
+const PRIORITY: usize = 0x0000;
+const PENDING: usize = 0x1000;
+const ENABLE: usize = 0x2000;
+const ENABLE_STRIDE: usize = 0x80;
+const CONTEXT: usize = 0x20_0000;
+const CONTEXT_STRIDE: usize = 0x1000;
+
+// Source 0 is reserved to mean "no interrupt"
+pub const SOURCES: usize = 32;
+// Hart 0 in M-mode and S-mode
+pub const CONTEXTS: usize = 2;
+pub const CONTEXT_MACHINE: usize = 0;
+pub const CONTEXT_SUPERVISOR: usize = 1;
+
+const PRIORITY_MASK: u32 = 0x7;
+
+pub struct Plic {
+    base: usize,
+    priority: [u32; SOURCES],
+    pending: u32,
+    // Claimed sources don't become pending again until they are completed
+    claimed: u32,
+    enable: [u32; CONTEXTS],
+    threshold: [u32; CONTEXTS],
+}
+
+impl Plic {
+    pub fn new() -> Self {
+        Self {
+            base: BASE_ADDR,
+            priority: [0; SOURCES],
+            pending: 0,
+            claimed: 0,
+            enable: [0; CONTEXTS],
+            threshold: [0; CONTEXTS],
+        }
+    }
+
+    pub fn set_base(&mut self, base: usize) {
+        self.base = base;
+    }
+
+    // Level-triggered interrupt line from a device
+    pub fn set_irq(&mut self, source: usize, level: bool) {
+        let bit = 1 << source;
+        if level && self.claimed & bit == 0 {
+            self.pending |= bit;
+        } else if !level {
+            self.pending &= !bit;
+        }
+    }
+
+    // Whether the context's external interrupt line (MEIP/SEIP) is raised
+    pub fn interrupt(&self, context: usize) -> bool {
+        self.best_source(context) != 0
+    }
+
+    // Highest priority pending source above the threshold, lowest ID wins ties
+    fn best_source(&self, context: usize) -> usize {
+        let candidates = self.pending & self.enable[context];
+        if candidates == 0 {
+            return 0;
+        }
+        (1..SOURCES)
+            .filter(|source| candidates & (1 << source) != 0)
+            .filter(|source| self.priority[*source] > self.threshold[context])
+            .fold(0, |best, source| {
+                if best == 0 || self.priority[source] > self.priority[best] {
+                    source
+                } else {
+                    best
+                }
+            })
+    }
+
+    fn claim(&mut self, context: usize) -> u32 {
+        let source = self.best_source(context);
+        if source != 0 {
+            self.pending &= !(1 << source);
+            self.claimed |= 1 << source;
+        }
+        source as u32
+    }
+
+    fn complete(&mut self, context: usize, source: u32) {
+        // Completions for sources that the context can't see are ignored
+        if (source as usize) < SOURCES && self.enable[context] & (1 << source) != 0 {
+            self.claimed &= !(1 << source);
+        }
+    }
+
+    fn read_register(&mut self, offset: usize) -> u32 {
+        match offset {
+            PRIORITY..PENDING => self.priority.get(offset / 4).cloned().unwrap_or_default(),
+            PENDING => self.pending,
+            ENABLE..CONTEXT => {
+                let context = (offset - ENABLE) / ENABLE_STRIDE;
+                match (context, (offset - ENABLE) % ENABLE_STRIDE) {
+                    (context, 0) if context < CONTEXTS => self.enable[context],
+                    _ => 0,
+                }
+            }
+            _ if offset >= CONTEXT => {
+                let context = (offset - CONTEXT) / CONTEXT_STRIDE;
+                match (context, (offset - CONTEXT) % CONTEXT_STRIDE) {
+                    (context, 0) if context < CONTEXTS => self.threshold[context],
+                    (context, 4) if context < CONTEXTS => self.claim(context),
+                    _ => 0,
+                }
+            }
+            _ => 0,
+        }
+    }
+
+    fn write_register(&mut self, offset: usize, value: u32) {
+        match offset {
+            PRIORITY..PENDING => {
+                // Source 0 does not exist, so its priority is hardwired to zero
+                if let Some(priority) = self.priority.get_mut(offset / 4).filter(|_| offset > 0) {
+                    *priority = value & PRIORITY_MASK;
+                }
+            }
+            ENABLE..CONTEXT => {
+                let context = (offset - ENABLE) / ENABLE_STRIDE;
+                if context < CONTEXTS && (offset - ENABLE).is_multiple_of(ENABLE_STRIDE) {
+                    self.enable[context] = value & !1;
+                }
+            }
+            _ if offset >= CONTEXT => {
+                let context = (offset - CONTEXT) / CONTEXT_STRIDE;
+                match (context, (offset - CONTEXT) % CONTEXT_STRIDE) {
+                    (context, 0) if context < CONTEXTS => {
+                        self.threshold[context] = value & PRIORITY_MASK
+                    }
+                    (context, 4) if context < CONTEXTS => self.complete(context, value),
+                    _ => (),
+                }
+            }
+            // The pending bits are read-only
+            _ => (),
+        }
+    }
+}
+
+impl Default for Plic {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BusDevice for Plic {
+    fn load<T: super::BusWidth<T> + std::fmt::Display>(
+        &mut self,
+        addr: usize,
+    ) -> Result<T, super::BusError> {
+        let offset = addr - self.base;
+        // All registers are 32 bits wide
+        if !offset.is_multiple_of(4) || T::WIDTH != 4 {
+            return Err(super::BusError::AddressMisaligned(addr));
+        }
+        Ok(T::from_mem(&self.read_register(offset).to_le_bytes()))
+    }
+
+    fn store<T: super::BusWidth<T> + std::fmt::Display>(
+        &mut self,
+        addr: usize,
+        data: T,
+    ) -> Result<(), super::BusError> {
+        let offset = addr - self.base;
+        if !offset.is_multiple_of(4) || T::WIDTH != 4 {
+            return Err(super::BusError::AddressMisaligned(addr));
+        }
+        let mut value = [0u8; 4];
+        T::to_mem(data, &mut value);
+        self.write_register(offset, u32::from_le_bytes(value));
+        Ok(())
+    }
+
+    fn addr_space(&self) -> (usize, usize) {
+        (self.base, self.base + SIZE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const M_CLAIM: usize = BASE_ADDR + CONTEXT + 4;
+    const S_CLAIM: usize = BASE_ADDR + CONTEXT + CONTEXT_STRIDE + 4;
+
+    #[test]
+    fn test_claim_complete() {
+        let mut dut = Plic::new();
+        dut.store::<u32>(BASE_ADDR + 10 * 4, 1).unwrap();
+        dut.store::<u32>(BASE_ADDR + ENABLE, 1 << 10).unwrap();
+
+        dut.set_irq(10, true);
+        assert!(dut.interrupt(CONTEXT_MACHINE));
+        assert!(!dut.interrupt(CONTEXT_SUPERVISOR));
+        assert_eq!(dut.load::<u32>(BASE_ADDR + PENDING), Ok(1 << 10));
+
+        assert_eq!(dut.load::<u32>(M_CLAIM), Ok(10));
+        assert!(!dut.interrupt(CONTEXT_MACHINE));
+        // The line is still high, but the source stays masked until completion
+        dut.set_irq(10, true);
+        assert_eq!(dut.load::<u32>(M_CLAIM), Ok(0));
+        dut.store::<u32>(M_CLAIM, 10).unwrap();
+        dut.set_irq(10, true);
+        assert!(dut.interrupt(CONTEXT_MACHINE));
+
+        // Lowering the line drops the pending interrupt
+        dut.set_irq(10, false);
+        assert!(!dut.interrupt(CONTEXT_MACHINE));
+    }
+    #[test]
+    fn test_priority_threshold() {
+        let mut dut = Plic::new();
+        dut.store::<u32>(BASE_ADDR + 3 * 4, 2).unwrap();
+        dut.store::<u32>(BASE_ADDR + 5 * 4, 5).unwrap();
+        dut.store::<u32>(BASE_ADDR + 7 * 4, 5).unwrap();
+        dut.store::<u32>(BASE_ADDR + ENABLE + ENABLE_STRIDE, 0xff)
+            .unwrap();
+        for source in [3, 5, 7] {
+            dut.set_irq(source, true);
+        }
+
+        // Equal priorities are resolved by the lower ID
+        assert_eq!(dut.load::<u32>(S_CLAIM), Ok(5));
+        dut.store::<u32>(BASE_ADDR + CONTEXT + CONTEXT_STRIDE, 2)
+            .unwrap();
+        assert_eq!(dut.load::<u32>(S_CLAIM), Ok(7));
+        // Priority 2 does not exceed the threshold
+        assert_eq!(dut.load::<u32>(S_CLAIM), Ok(0));
+        assert!(!dut.interrupt(CONTEXT_SUPERVISOR));
+    }
+    #[test]
+    fn test_reserved_source() {
+        let mut dut = Plic::new();
+        dut.store::<u32>(BASE_ADDR, 7).unwrap();
+        dut.store::<u32>(BASE_ADDR + ENABLE, u32::MAX).unwrap();
+        assert_eq!(dut.load::<u32>(BASE_ADDR), Ok(0));
+        assert_eq!(dut.load::<u32>(BASE_ADDR + ENABLE), Ok(0xffff_fffe));
+        assert_eq!(
+            dut.load::<u16>(BASE_ADDR + 4),
+            Err(crate::bus::BusError::AddressMisaligned(BASE_ADDR + 4))
+        );
+    }
+}
@@ -16,8 +16,11 @@ impl Ram {
 }
 
 impl BusDevice for Ram {
-    fn load<T: super::BusWidth<T> + std::fmt::Display>(&self, addr: usize) -> Result<T, BusError> {
-        let uaddr = addr as usize - self.addr_space.0;
+    fn load<T: super::BusWidth<T> + std::fmt::Display>(
+        &mut self,
+        addr: usize,
+    ) -> Result<T, BusError> {
+        let uaddr = addr - self.addr_space.0;
         let value = T::from_mem(&self.mem[uaddr..uaddr + T::WIDTH]);
         Ok(value)
     }
@@ -27,7 +30,7 @@ impl BusDevice for Ram {
         addr: usize,
         data: T,
     ) -> Result<(), BusError> {
-        let uaddr = addr as usize - self.addr_space.0;
+        let uaddr = addr - self.addr_space.0;
         T::to_mem(data, &mut self.mem[uaddr..uaddr + T::WIDTH]);
         Ok(())
     }
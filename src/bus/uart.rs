@@ -1,26 +1,294 @@
+use std::collections::VecDeque;
+use std::io::Write;
+
 use super::BusDevice;
 
-const BASE_ADDR: usize = 0x1000_0000;
+pub const BASE_ADDR: usize = 0x1000_0000;
+pub const SIZE: usize = 0x100;
+const FIFO_SIZE: usize = 16;
+// Polling the host for input on every instruction would be far too slow
+const POLL_INTERVAL: u32 = 1024;
+
+// Register offsets, DLL and DLM overlay RBR/THR and IER while DLAB is set
+const RBR_THR_DLL: usize = 0;
+const IER_DLM: usize = 1;
+const IIR_FCR: usize = 2;
+const LCR: usize = 3;
+const MCR: usize = 4;
+const LSR: usize = 5;
+const MSR: usize = 6;
+const SCR: usize = 7;
+
+const IER_ERBFI: u8 = 1 << 0;
+const IER_ETBEI: u8 = 1 << 1;
+const IER_ELSI: u8 = 1 << 2;
+const IER_MASK: u8 = 0x0f;
+
+// Interrupt identification, lowest bit set means nothing is pending
+const IIR_NONE: u8 = 0x01;
+const IIR_THRE: u8 = 0x02;
+const IIR_RDA: u8 = 0x04;
+const IIR_RLS: u8 = 0x06;
+const IIR_TIMEOUT: u8 = 0x0c;
+const IIR_FIFO: u8 = 0xc0;
+
+const FCR_ENABLE: u8 = 1 << 0;
+const FCR_CLEAR_RX: u8 = 1 << 1;
+
+const LCR_DLAB: u8 = 1 << 7;
 
+const MCR_LOOP: u8 = 1 << 4;
+const MCR_MASK: u8 = 0x1f;
+
+const LSR_DR: u8 = 1 << 0;
+const LSR_OE: u8 = 1 << 1;
+const LSR_THRE: u8 = 1 << 5;
+const LSR_TEMT: u8 = 1 << 6;
+
+// Receives every byte that the guest transmits
+pub type UartOutput = Box<dyn FnMut(u8)>;
+// Polled for bytes that the guest receives
+pub type UartInput = Box<dyn FnMut() -> Option<u8>>;
+
+// 16550 compatible UART. Transmission is instantaneous, so the transmit
+// holding register is always empty and only the receiver has a FIFO.
 pub struct Uart {
-    buffer: Vec<u8>,
+    base: usize,
+    output: UartOutput,
+    input: Option<UartInput>,
+    poll_countdown: u32,
+    rx_fifo: VecDeque<u8>,
+    ier: u8,
+    fcr: u8,
+    lcr: u8,
+    mcr: u8,
+    scr: u8,
+    dll: u8,
+    dlm: u8,
+    overrun: bool,
+    // The THRE interrupt is cleared by reading IIR, not by the register state
+    thre_pending: bool,
 }
 
 impl Uart {
     pub fn new() -> Self {
-        Self { buffer: Vec::new() }
+        Self::with_output(stdout_output())
+    }
+
+    pub fn with_output(output: UartOutput) -> Self {
+        Self {
+            base: BASE_ADDR,
+            output,
+            input: None,
+            poll_countdown: 0,
+            rx_fifo: VecDeque::with_capacity(FIFO_SIZE),
+            ier: 0,
+            fcr: 0,
+            lcr: 0,
+            mcr: 0,
+            scr: 0,
+            dll: 0,
+            dlm: 0,
+            overrun: false,
+            thre_pending: false,
+        }
+    }
+
+    pub fn set_base(&mut self, base: usize) {
+        self.base = base;
+    }
+
+    pub fn set_output(&mut self, output: UartOutput) {
+        self.output = output;
+    }
+
+    pub fn set_input(&mut self, input: UartInput) {
+        self.input = Some(input);
+    }
+
+    pub fn tick(&mut self, instructions: u32) {
+        if self.poll_countdown >= instructions {
+            self.poll_countdown -= instructions;
+            return;
+        }
+        self.poll();
+    }
+
+    // Fetch pending input from the host right away
+    pub fn poll(&mut self) {
+        self.poll_countdown = POLL_INTERVAL;
+        // Don't read more from the host than the FIFO can hold
+        while self.rx_fifo.len() < self.fifo_size() {
+            match self.input.as_mut().and_then(|input| input()) {
+                Some(byte) => self.receive(byte),
+                None => break,
+            }
+        }
+    }
+
+    // Level of the interrupt line towards the interrupt controller
+    pub fn interrupt(&self) -> bool {
+        self.interrupt_id() != IIR_NONE
+    }
+
+    fn fifo_size(&self) -> usize {
+        if self.fcr & FCR_ENABLE != 0 {
+            FIFO_SIZE
+        } else {
+            1
+        }
+    }
+
+    // Number of received bytes that raise the data available interrupt
+    fn trigger_level(&self) -> usize {
+        if self.fcr & FCR_ENABLE == 0 {
+            return 1;
+        }
+        match self.fcr >> 6 {
+            0 => 1,
+            1 => 4,
+            2 => 8,
+            _ => 14,
+        }
+    }
+
+    fn receive(&mut self, byte: u8) {
+        if self.rx_fifo.len() >= self.fifo_size() {
+            self.overrun = true;
+            return;
+        }
+        self.rx_fifo.push_back(byte);
+    }
+
+    fn transmit(&mut self, byte: u8) {
+        if self.mcr & MCR_LOOP != 0 {
+            self.receive(byte);
+        } else {
+            (self.output)(byte);
+        }
+        self.thre_pending = true;
+    }
+
+    // Highest priority pending interrupt as encoded in IIR
+    fn interrupt_id(&self) -> u8 {
+        if self.ier & IER_ELSI != 0 && self.overrun {
+            IIR_RLS
+        } else if self.ier & IER_ERBFI != 0 && self.rx_fifo.len() >= self.trigger_level() {
+            IIR_RDA
+        } else if self.ier & IER_ERBFI != 0 && !self.rx_fifo.is_empty() {
+            // There is no notion of time here, so the character timeout fires right away
+            IIR_TIMEOUT
+        } else if self.ier & IER_ETBEI != 0 && self.thre_pending {
+            IIR_THRE
+        } else {
+            IIR_NONE
+        }
+    }
+
+    fn line_status(&self) -> u8 {
+        let mut lsr = LSR_THRE | LSR_TEMT;
+        if !self.rx_fifo.is_empty() {
+            lsr |= LSR_DR;
+        }
+        if self.overrun {
+            lsr |= LSR_OE;
+        }
+        lsr
+    }
+
+    fn modem_status(&self) -> u8 {
+        if self.mcr & MCR_LOOP != 0 {
+            // In loopback the modem inputs are wired to the outputs:
+            // DTR->DSR, RTS->CTS, OUT1->RI and OUT2->DCD
+            let mcr = self.mcr;
+            (mcr & 0x01) << 5 | (mcr & 0x02) << 3 | (mcr & 0x04) << 4 | (mcr & 0x08) << 4
+        } else {
+            // CTS, DSR and DCD are always asserted
+            0xb0
+        }
+    }
+
+    fn read_register(&mut self, offset: usize) -> u8 {
+        let dlab = self.lcr & LCR_DLAB != 0;
+        match offset {
+            RBR_THR_DLL if dlab => self.dll,
+            RBR_THR_DLL => self.rx_fifo.pop_front().unwrap_or(0),
+            IER_DLM if dlab => self.dlm,
+            IER_DLM => self.ier,
+            IIR_FCR => {
+                let id = self.interrupt_id();
+                if id == IIR_THRE {
+                    self.thre_pending = false;
+                }
+                let fifo = if self.fcr & FCR_ENABLE != 0 {
+                    IIR_FIFO
+                } else {
+                    0
+                };
+                id | fifo
+            }
+            LCR => self.lcr,
+            MCR => self.mcr,
+            LSR => {
+                let lsr = self.line_status();
+                self.overrun = false;
+                lsr
+            }
+            MSR => self.modem_status(),
+            SCR => self.scr,
+            _ => 0,
+        }
+    }
+
+    fn write_register(&mut self, offset: usize, value: u8) {
+        let dlab = self.lcr & LCR_DLAB != 0;
+        match offset {
+            RBR_THR_DLL if dlab => self.dll = value,
+            RBR_THR_DLL => self.transmit(value),
+            IER_DLM if dlab => self.dlm = value,
+            IER_DLM => {
+                // Enabling the THRE interrupt fires it right away since THR is empty
+                if value & IER_ETBEI != 0 && self.ier & IER_ETBEI == 0 {
+                    self.thre_pending = true;
+                }
+                self.ier = value & IER_MASK;
+            }
+            IIR_FCR => {
+                if value & FCR_CLEAR_RX != 0 || (value ^ self.fcr) & FCR_ENABLE != 0 {
+                    self.rx_fifo.clear();
+                }
+                // The clear bits are self-clearing
+                self.fcr = value & !0x06;
+            }
+            LCR => self.lcr = value,
+            MCR => self.mcr = value & MCR_MASK,
+            SCR => self.scr = value,
+            _ => (),
+        }
+    }
+}
+
+impl Default for Uart {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
+// Write to stdout right away so that prompts without a newline show up
+pub fn stdout_output() -> UartOutput {
+    Box::new(|c| {
+        let mut stdout = std::io::stdout();
+        let _ = stdout.write_all(&[c]);
+        let _ = stdout.flush();
+    })
+}
+
 impl BusDevice for Uart {
     fn load<T: super::BusWidth<T> + std::fmt::Display>(
-        &self,
+        &mut self,
         addr: usize,
     ) -> Result<T, super::BusError> {
-        if addr == BASE_ADDR + 0x5 {
-            return Ok(T::from_mem(&[0x60]));
-        }
-        Ok(T::from_mem(&[0x00]))
+        Ok(T::from_mem(&[self.read_register(addr - self.base)]))
     }
 
     fn store<T: super::BusWidth<T> + std::fmt::Display>(
@@ -28,39 +296,111 @@ impl BusDevice for Uart {
         addr: usize,
         data: T,
     ) -> Result<(), super::BusError> {
-        if addr == BASE_ADDR {
-            // Not very elegant way of extracting u8 from generic type `T`
-            let mut c = [0u8];
-            T::to_mem(data, &mut c);
-            self.buffer.push(c[0]);
-            if c[0] == b'\n' {
-                print!("{}", String::from_utf8_lossy(&self.buffer));
-                self.buffer.clear();
-            }
-        }
+        // Not very elegant way of extracting u8 from generic type `T`
+        let mut c = [0u8];
+        T::to_mem(data, &mut c);
+        self.write_register(addr - self.base, c[0]);
         Ok(())
     }
 
     fn addr_space(&self) -> (usize, usize) {
-        (BASE_ADDR, BASE_ADDR + 0xff)
+        (self.base, self.base + SIZE)
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
 
     use super::*;
 
+    fn input(bytes: &[u8]) -> UartInput {
+        let mut bytes: VecDeque<u8> = bytes.iter().cloned().collect();
+        Box::new(move || bytes.pop_front())
+    }
+
     #[test]
     fn test_write() {
-        let mut dut: Uart = Uart { buffer: vec![] };
+        let written = Rc::new(RefCell::new(Vec::new()));
+        let sink = written.clone();
+        let mut dut = Uart::with_output(Box::new(move |c| sink.borrow_mut().push(c)));
 
         assert_eq!(dut.store::<u8>(BASE_ADDR, b'a'), Ok(()));
+        assert_eq!(dut.store::<u8>(BASE_ADDR + 1, b'b'), Ok(()));
+        assert_eq!(*written.borrow(), vec![b'a']);
     }
     #[test]
     fn test_read() {
-        let dut: Uart = Uart { buffer: vec![] };
+        let mut dut = Uart::new();
 
         assert_eq!(dut.load::<u8>(BASE_ADDR + 0x05), Ok(0x60));
     }
+    #[test]
+    fn test_receive_fifo() {
+        let mut dut = Uart::new();
+        dut.set_input(input(b"hello"));
+        // FIFO enabled with a trigger level of 4 bytes
+        dut.store::<u8>(BASE_ADDR + IIR_FCR, 0x41).unwrap();
+        dut.store::<u8>(BASE_ADDR + IER_DLM, IER_ERBFI).unwrap();
+        assert!(!dut.interrupt());
+
+        dut.tick(1);
+        assert_eq!(dut.load::<u8>(BASE_ADDR + LSR), Ok(0x61));
+        assert_eq!(dut.load::<u8>(BASE_ADDR + IIR_FCR), Ok(0xc4));
+        assert!(dut.interrupt());
+
+        assert_eq!(dut.load::<u8>(BASE_ADDR), Ok(b'h'));
+        assert_eq!(dut.load::<u8>(BASE_ADDR), Ok(b'e'));
+        // Below the trigger level the timeout interrupt takes over
+        assert_eq!(dut.load::<u8>(BASE_ADDR + IIR_FCR), Ok(0xcc));
+        for c in b"llo" {
+            assert_eq!(dut.load::<u8>(BASE_ADDR), Ok(*c));
+        }
+        assert_eq!(dut.load::<u8>(BASE_ADDR + LSR), Ok(0x60));
+        assert!(!dut.interrupt());
+    }
+    #[test]
+    fn test_overrun() {
+        let mut dut = Uart::new();
+        dut.set_input(input(b"ab"));
+        dut.store::<u8>(BASE_ADDR + IER_DLM, IER_ELSI).unwrap();
+        // Without FIFO only a single byte is held
+        dut.tick(1);
+        dut.receive(b'c');
+        assert_eq!(dut.load::<u8>(BASE_ADDR + IIR_FCR), Ok(IIR_RLS));
+        assert_eq!(dut.load::<u8>(BASE_ADDR + LSR), Ok(0x63));
+        assert_eq!(dut.load::<u8>(BASE_ADDR + LSR), Ok(0x61));
+        assert_eq!(dut.load::<u8>(BASE_ADDR), Ok(b'a'));
+    }
+    #[test]
+    fn test_thre_interrupt() {
+        let mut dut = Uart::with_output(Box::new(|_| ()));
+        dut.store::<u8>(BASE_ADDR + IER_DLM, IER_ETBEI).unwrap();
+        assert_eq!(dut.load::<u8>(BASE_ADDR + IIR_FCR), Ok(IIR_THRE));
+        // Reading IIR acknowledges the interrupt
+        assert_eq!(dut.load::<u8>(BASE_ADDR + IIR_FCR), Ok(IIR_NONE));
+        dut.store::<u8>(BASE_ADDR, b'x').unwrap();
+        assert!(dut.interrupt());
+    }
+    #[test]
+    fn test_divisor_and_loopback() {
+        let mut dut = Uart::new();
+        dut.store::<u8>(BASE_ADDR + LCR, LCR_DLAB | 0x03).unwrap();
+        dut.store::<u8>(BASE_ADDR, 0x01).unwrap();
+        dut.store::<u8>(BASE_ADDR + IER_DLM, 0x02).unwrap();
+        assert_eq!(dut.load::<u8>(BASE_ADDR), Ok(0x01));
+        assert_eq!(dut.load::<u8>(BASE_ADDR + IER_DLM), Ok(0x02));
+        dut.store::<u8>(BASE_ADDR + LCR, 0x03).unwrap();
+        assert_eq!(dut.load::<u8>(BASE_ADDR + IER_DLM), Ok(0x00));
+
+        dut.store::<u8>(BASE_ADDR + SCR, 0x5a).unwrap();
+        assert_eq!(dut.load::<u8>(BASE_ADDR + SCR), Ok(0x5a));
+
+        // Loopback with RTS and OUT2 set
+        dut.store::<u8>(BASE_ADDR + MCR, MCR_LOOP | 0x0a).unwrap();
+        assert_eq!(dut.load::<u8>(BASE_ADDR + MSR), Ok(0x90));
+        dut.store::<u8>(BASE_ADDR, b'z').unwrap();
+        assert_eq!(dut.load::<u8>(BASE_ADDR), Ok(b'z'));
+    }
 }
@@ -1,138 +1,404 @@
-use core::time;
+use core::fmt;
+use std::cell::RefCell;
 use std::collections::HashSet;
+use std::rc::Rc;
 
 use enum_primitive_derive::Primitive;
+use goblin::elf::header::EM_RISCV;
+use goblin::elf::program_header::PT_LOAD;
 use goblin::elf::Elf;
+use num_traits::FromPrimitive;
 use tracing::debug;
 use tracing::info;
-use tracing::warn;
 
 use self::alu::exec;
-use self::decoder::decode;
-use self::regfile::RegFile;
-
-use crate::bus::{Bus, BusDevice, BusError};
-use crate::cpu::csr::{ArchCSRs, CSRFile};
+use self::counters::Event;
+use self::debug::{DebugEvent, DebugState};
+use self::decoder::{decode, is_compressed, required_extensions};
+use self::icache::{DecodeCache, PageCache};
+use self::instructions::Instruction;
+use self::mmu::{AccessType, Mmu, PAGE_SIZE};
+use self::regfile::{FRegFile, RegFile};
+use self::threaded::Block;
+
+use crate::bus::clint::TimeSource;
+use crate::bus::map::MapError;
+use crate::bus::uart::{UartInput, UartOutput};
+use crate::bus::{Bus, BusDevice, BusError, BusWidth, Device, DeviceLayout};
+use crate::cpu::csr::{ArchCSRs, CSRFile, EXT_OPTIONAL, MISA_C, MISA_D, MISA_F};
 use crate::cpu::instructions::pretty_register;
+use crate::machine::BuildError;
 use crate::trap::RVException;
-use crate::RAM_SIZE;
 
 pub mod alu;
+pub mod counters;
 pub mod csr;
+pub mod debug;
 pub mod decoder;
+pub mod icache;
 pub mod instructions;
+pub mod mmu;
+pub mod pmp;
 pub mod regfile;
+pub mod softfloat;
+pub mod threaded;
 
 struct MMIORegister {
     value: u32,
     writable: bool,
 }
 
-#[derive(Debug, Clone, PartialEq, Primitive)]
-enum ExecMode {
-    MACHINE = 0b11,
+// Declared from least to most privileged so that modes can be compared
+#[derive(Debug, Clone, PartialEq, PartialOrd, Primitive)]
+pub enum ExecMode {
     USER = 0b00,
+    SUPERVISOR = 0b01,
+    MACHINE = 0b11,
+}
+
+// Why `Cpu::step` stopped instead of continuing with the next instruction
+#[derive(Debug, Clone, PartialEq)]
+pub enum HaltReason {
+    // Result reported by the riscv-tests, the failing test is encoded in the code
+    TestPassed,
+    TestFailed(i32),
+    Debug(DebugEvent),
+    // wfi without any enabled interrupt that could wake the hart up
+    WaitForInterrupt,
+    Fatal(String),
 }
 
 pub struct Cpu {
     regfile: RegFile,
+    fregfile: FRegFile,
     csrfile: CSRFile,
     bus: Bus,
+    mmu: Mmu,
+    icache: DecodeCache,
+    blocks: PageCache<Rc<Block>>,
+    // Bumped whenever translated blocks are dropped
+    code_generation: u64,
     amoreserved: HashSet<usize>,
     mode: ExecMode,
     pub pc: usize,
-    pub delay: u64,
-    pub instruction_count: u64,
+    next_pc: usize,
     pub test: bool,
+    pub debug: DebugState,
+    // Set by instructions that need to hand control back to the caller
+    halt: Option<HaltReason>,
+    // Set by WFI until an interrupt is pending
+    waiting: bool,
+    // Enabled optional extensions as misa bits, plus the EXT_Z* ones
+    extensions: u32,
+}
+
+pub const RAM_START: usize = 0x8000_0000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ElfError {
+    Parse(String),
+    Unsupported,
+    SegmentOutOfRange(usize, usize),
 }
 
-const RAM_START: usize = 0x8000_0000;
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Parse(e) => write!(f, "Invalid ELF file: {}", e),
+            Self::Unsupported => write!(f, "Not a little-endian RV32 ELF file"),
+            Self::SegmentOutOfRange(addr, size) => write!(
+                f,
+                "Segment at {:#010x} with size {} is outside of RAM",
+                addr, size
+            ),
+        }
+    }
+}
 
 impl Cpu {
-    pub fn new(mut kernel: Vec<u8>, ram_size: usize) -> Self {
+    pub fn new(kernel: Vec<u8>, ram_size: usize) -> Result<Self, BuildError> {
+        Self::with_ram_base(kernel, ram_size, RAM_START)
+    }
+
+    pub fn with_ram_base(
+        kernel: Vec<u8>,
+        ram_size: usize,
+        ram_base: usize,
+    ) -> Result<Self, BuildError> {
+        Self::with_layout(kernel, ram_size, ram_base, DeviceLayout::default())
+    }
+
+    pub fn with_layout(
+        mut kernel: Vec<u8>,
+        ram_size: usize,
+        ram_base: usize,
+        layout: DeviceLayout,
+    ) -> Result<Self, BuildError> {
         if kernel.len() > ram_size {
-            panic!("Kernel size exceeds RAM size");
+            return Err(BuildError::Boot("kernel larger than RAM".to_string()));
         }
         info!(
             "Loading binary at {:#10x} with size {}",
-            RAM_START,
+            ram_base,
             kernel.len()
         );
         kernel.extend(vec![0u8; ram_size - kernel.len()]);
 
-        Self {
+        Ok(Self {
             regfile: RegFile::new(),
+            fregfile: FRegFile::new(),
             csrfile: CSRFile::new(),
-            bus: Bus::new(kernel, RAM_START),
+            bus: Bus::with_layout(kernel, ram_base, layout)?,
+            mmu: Mmu::new(),
+            icache: DecodeCache::new(),
+            blocks: PageCache::new(),
+            code_generation: 0,
             amoreserved: HashSet::new(),
             mode: ExecMode::MACHINE,
-            pc: RAM_START,
-            delay: 0,
-            instruction_count: 0,
+            pc: ram_base,
+            next_pc: ram_base,
             test: false,
-        }
+            debug: DebugState::new(),
+            halt: None,
+            waiting: false,
+            extensions: EXT_OPTIONAL,
+        })
     }
 
-    pub fn load_dtb(&mut self, dtb_bytes: Vec<u8>) {
-        let dtb_start = self.bus.ram.addr_space().1 - dtb_bytes.len();
+    // Copy an image into RAM, which has to hold all of it
+    pub fn load_image(&mut self, addr: usize, bytes: &[u8]) {
+        let offset = addr - self.bus.ram.addr_space().0;
+        self.bus.ram.mem[offset..offset + bytes.len()].copy_from_slice(bytes);
+    }
+
+    pub fn load_dtb(&mut self, dtb_start: usize, dtb_bytes: &[u8]) {
         info!(
             "Loading DTB at {:#10x} with size {}",
             dtb_start,
             dtb_bytes.len()
         );
-        self.bus.ram.mem[RAM_SIZE - dtb_bytes.len()..].copy_from_slice(&dtb_bytes);
+        self.load_image(dtb_start, dtb_bytes);
         self.regfile.write(10, 0); // hartid
         self.regfile.write(11, dtb_start as i32); // DTB pointer
     }
 
-    pub fn load_elf(&mut self, elf_bytes: Vec<u8>) {
-        let elf = Elf::parse(&elf_bytes).unwrap();
-
-        // Find all sections starting with .text
-        for section in elf.section_headers.iter() {
-            if let Some(name) = elf.shdr_strtab.get_at(section.sh_name) {
-                if name.starts_with(".text") || name.starts_with(".data") {
-                    let offset = section.sh_offset as usize;
-                    let size = section.sh_size as usize;
-                    let addr = section.sh_addr as usize;
-                    let text_bytes = &elf_bytes[offset..offset + size];
-                    info!(
-                        "Loading {} section at {:#08x} with size {}",
-                        name, addr, size
-                    );
-                    self.bus.ram.mem[addr - RAM_START..addr - RAM_START + size]
-                        .copy_from_slice(text_bytes);
-                }
+    // Returns the end of the highest segment
+    pub fn load_elf(&mut self, elf_bytes: Vec<u8>) -> Result<usize, ElfError> {
+        let elf = Elf::parse(&elf_bytes).map_err(|e| ElfError::Parse(e.to_string()))?;
+        if elf.is_64 || !elf.little_endian || elf.header.e_machine != EM_RISCV {
+            return Err(ElfError::Unsupported);
+        }
+
+        let (ram_start, ram_end) = self.bus.ram.addr_space();
+        let mut end = ram_start;
+        for segment in elf.program_headers.iter() {
+            if segment.p_type != PT_LOAD {
+                continue;
+            }
+            // Load at the physical address, the virtual one may need the MMU
+            let addr = segment.p_paddr as usize;
+            let file_size = segment.p_filesz as usize;
+            let mem_size = segment.p_memsz as usize;
+            let offset = segment.p_offset as usize;
+            if file_size > mem_size
+                || addr < ram_start
+                || addr > ram_end
+                || mem_size > ram_end - addr
+            {
+                return Err(ElfError::SegmentOutOfRange(addr, mem_size));
             }
+            let data = offset
+                .checked_add(file_size)
+                .and_then(|file_end| elf_bytes.get(offset..file_end))
+                .ok_or(ElfError::SegmentOutOfRange(addr, mem_size))?;
+            info!(
+                "Loading segment at {:#010x} with size {} ({} from file)",
+                addr, mem_size, file_size
+            );
+            let mem = &mut self.bus.ram.mem[addr - ram_start..addr - ram_start + mem_size];
+            // Whatever is not backed by the file (.bss) is zero-filled
+            mem[..file_size].copy_from_slice(data);
+            mem[file_size..].fill(0);
+            end = end.max(addr + mem_size);
         }
+
+        self.pc = elf.entry as usize;
+        info!("Entry point at {:#010x}", self.pc);
+        Ok(end)
     }
 
-    pub fn fetch(&self) -> Result<u32, RVException> {
-        self.bus.load::<u32>(self.pc).map_err(|e| match e {
-            BusError::AddressMisaligned(addr) => RVException::InstructionAddressMisaligned(addr),
-            BusError::AddressUnmapped(addr) => RVException::InstructionAccessFault(addr),
-        })
+    // Privilege that memory accesses are checked against
+    fn effective_mode(&self, access: AccessType) -> ExecMode {
+        const MSTATUS_MPRV: u32 = 1 << 17;
+        let mstatus = self.csrfile.read(ArchCSRs::mstatus as i32) as u32;
+        // Loads and stores use the privilege in MPP if MPRV is set
+        if access != AccessType::Fetch && mstatus & MSTATUS_MPRV != 0 {
+            ExecMode::from_u32(self.csrfile.get_mpp()).unwrap()
+        } else {
+            self.mode.clone()
+        }
+    }
+
+    // Translate a virtual address and check the physical access against the PMP
+    pub fn translate(
+        &mut self,
+        vaddr: usize,
+        size: usize,
+        access: AccessType,
+    ) -> Result<usize, RVException> {
+        let mode = self.effective_mode(access);
+        let paddr = self
+            .mmu
+            .translate(&mut self.bus, &self.csrfile, &mode, vaddr, access)?;
+        if !self.csrfile.pmp.check(paddr, size, access, &mode) {
+            return Err(access.access_fault(vaddr));
+        }
+        Ok(paddr)
+    }
+
+    pub fn load<T: BusWidth<T> + std::fmt::Display>(
+        &mut self,
+        vaddr: usize,
+    ) -> Result<T, RVException> {
+        if !T::is_aligned(vaddr) {
+            return Err(RVException::LoadAddressMisaligned(vaddr));
+        }
+        let paddr = self.translate(vaddr, T::WIDTH, AccessType::Load)?;
+        // Watchpoints stop before the access happens
+        if self.debug.check(vaddr, T::WIDTH, false) {
+            return Err(RVException::BreakPoint);
+        }
+        let value = self
+            .bus
+            .load::<T>(paddr)
+            .map_err(|e| AccessType::Load.bus_error(e, vaddr))?;
+        self.csrfile.counters.count(Event::Load);
+        Ok(value)
     }
 
-    fn riscv_tests_ecall(&mut self, exception: &RVException) {
+    pub fn store<T: BusWidth<T> + std::fmt::Display>(
+        &mut self,
+        vaddr: usize,
+        data: T,
+    ) -> Result<(), RVException> {
+        if !T::is_aligned(vaddr) {
+            return Err(RVException::StoreAddressMisaligned(vaddr));
+        }
+        let paddr = self.translate(vaddr, T::WIDTH, AccessType::Store)?;
+        if self.debug.check(vaddr, T::WIDTH, true) {
+            return Err(RVException::BreakPoint);
+        }
+        self.invalidate_code(paddr);
+        self.bus
+            .store::<T>(paddr, data)
+            .map_err(|e| AccessType::Store.bus_error(e, vaddr))?;
+        self.csrfile.counters.count(Event::Store);
+        Ok(())
+    }
+
+    fn fetch_half(&mut self, vaddr: usize) -> Result<u32, RVException> {
+        if !u16::is_aligned(vaddr) {
+            return Err(RVException::InstructionAddressMisaligned(vaddr));
+        }
+        let paddr = self.translate(vaddr, u16::WIDTH, AccessType::Fetch)?;
+        self.bus
+            .load::<u16>(paddr)
+            .map(|half| half as u32)
+            .map_err(|e| AccessType::Fetch.bus_error(e, vaddr))
+    }
+
+    pub fn fetch(&mut self) -> Result<u32, RVException> {
+        // Instructions are only guaranteed to be 16-bit aligned with the C extension,
+        // so fetch in two halves and only load the upper one for 32-bit instructions.
+        // This also translates each half separately if an instruction spans two pages.
+        let lower = self.fetch_half(self.pc)?;
+        if !is_compressed(lower) {
+            let upper = self.fetch_half(self.pc + 2)?;
+            return Ok(upper << 16 | lower);
+        }
+        Ok(lower)
+    }
+
+    // Continue at the target of a jump or taken branch. Without the C extension,
+    // a target that isn't 4-byte aligned traps on the jump itself.
+    fn jump(&mut self, target: usize) -> Result<(), RVException> {
+        if self.extensions & MISA_C == 0 && !u32::is_aligned(target) {
+            return Err(RVException::InstructionAddressMisaligned(target));
+        }
+        self.next_pc = target;
+        Ok(())
+    }
+
+    // Decode an instruction, which is illegal if its extension is disabled
+    fn decode_enabled(&self, raw: u32) -> Result<Instruction, RVException> {
+        let instruction = decode(&raw)?;
+        if required_extensions(raw, &instruction) & !self.extensions != 0 {
+            return Err(RVException::IllegalInstruction(raw));
+        }
+        Ok(instruction)
+    }
+
+    // Stores to devices aren't tracked, so decoded instructions and translated
+    // blocks are only kept for code in RAM
+    fn is_cacheable(&self, paddr: usize) -> bool {
+        let (ram_lower, ram_upper) = self.bus.ram.addr_space();
+        paddr >= ram_lower && paddr < ram_upper
+    }
+
+    // Fetch and decode the instruction at pc, unless it is cached already
+    fn fetch_decoded(&mut self) -> Result<(u32, Instruction), RVException> {
+        let paddr = self.translate(self.pc, u16::WIDTH, AccessType::Fetch)?;
+        if let Some(cached) = self.icache.get(paddr) {
+            return Ok(cached.clone());
+        }
+        let instruction = self.fetch()?;
+        let decoded_instr = self.decode_enabled(instruction)?;
+
+        // Instructions that span two pages aren't cached
+        let in_page = is_compressed(instruction) || paddr % PAGE_SIZE <= PAGE_SIZE - 4;
+        if self.is_cacheable(paddr) && in_page {
+            self.icache
+                .insert(paddr, (instruction, decoded_instr.clone()));
+        }
+        Ok((instruction, decoded_instr))
+    }
+
+    // Drop cached translations of a page that is written to
+    fn invalidate_code(&mut self, paddr: usize) {
+        self.icache.invalidate(paddr);
+        if self.blocks.invalidate(paddr) {
+            self.code_generation += 1;
+        }
+    }
+
+    fn flush_code(&mut self) {
+        self.icache.flush();
+        self.blocks.flush();
+        self.code_generation += 1;
+    }
+
+    fn riscv_tests_ecall(&self, exception: &RVException) -> Option<HaltReason> {
         // Check if ECALL was generated by the RISC-V test suite to signal test end
         if (*exception == RVException::EnvironmentCallU
+            || *exception == RVException::EnvironmentCallS
             || *exception == RVException::EnvironmentCallM)
             && self.regfile.read(17) == 93
         {
             let result = self.regfile.read(10);
             info!("Test Result in a0: {}", result);
-            std::process::exit(result);
+            return match result {
+                0 => Some(HaltReason::TestPassed),
+                code => Some(HaltReason::TestFailed(code)),
+            };
         }
+        None
     }
 
     fn trap_entry(&mut self, exception: RVException) {
         info!("{:#010x} | Exception {:?}", self.pc, exception);
+        self.csrfile.counters.count(Event::Trap);
 
-        // Disable interrupts
-        self.csrfile.disable_irq();
-
-        let mtval = match exception {
+        let tval = match exception {
             RVException::InstructionAddressMisaligned(addr) => addr as u32,
             RVException::InstructionAccessFault(addr) => addr as u32,
             RVException::IllegalInstruction(instruction) => instruction,
@@ -140,21 +406,29 @@ impl Cpu {
             RVException::LoadAccessFault(addr) => addr as u32,
             RVException::StoreAddressMisaligned(addr) => addr as u32,
             RVException::StoreAccessFault(addr) => addr as u32,
+            RVException::InstructionPageFault(addr) => addr as u32,
+            RVException::LoadPageFault(addr) => addr as u32,
+            RVException::StorePageFault(addr) => addr as u32,
             _ => 0,
         };
 
-        if self.test {
-            self.riscv_tests_ecall(&exception);
+        // Traps from below M-mode can be delegated to S-mode
+        let cause = exception.to_ecode();
+        if self.mode != ExecMode::MACHINE && self.csrfile.is_delegated(cause) {
+            self.supervisor_trap_entry(exception, cause, tval);
+            return;
         }
 
+        // Disable interrupts
+        self.csrfile.disable_irq();
+
         let mode = self.mode.clone() as u32;
         self.csrfile.set_mpp(&mode);
 
         self.mode = ExecMode::MACHINE; // Set mode to MACHINE for trap handling
 
-        self.csrfile.write(ArchCSRs::mtval as i32, mtval as i32);
-        self.csrfile
-            .write(ArchCSRs::mcause as i32, exception.to_ecode() as i32);
+        self.csrfile.write(ArchCSRs::mtval as i32, tval as i32);
+        self.csrfile.write(ArchCSRs::mcause as i32, cause as i32);
 
         self.csrfile.write(ArchCSRs::mepc as i32, self.pc as i32);
         self.pc = self.csrfile.read(ArchCSRs::mtvec as i32) as u32 as usize;
@@ -167,57 +441,203 @@ impl Cpu {
         );
     }
 
+    fn supervisor_trap_entry(&mut self, exception: RVException, cause: u32, tval: u32) {
+        // Disable supervisor interrupts
+        self.csrfile.disable_sirq();
+
+        let mode = self.mode.clone() as u32;
+        self.csrfile.set_spp(&mode);
+
+        self.mode = ExecMode::SUPERVISOR;
+
+        self.csrfile.write(ArchCSRs::stval as i32, tval as i32);
+        self.csrfile.write(ArchCSRs::scause as i32, cause as i32);
+
+        self.csrfile.write(ArchCSRs::sepc as i32, self.pc as i32);
+        self.pc = self.csrfile.read(ArchCSRs::stvec as i32) as u32 as usize;
+        info!(
+            "Entering supervisor trap handler for {:?} from {:#010x} at {:#010x} with sstatus: {:#010x}",
+            exception,
+            self.csrfile.read(ArchCSRs::sepc as i32),
+            self.pc,
+            self.csrfile.read(ArchCSRs::sstatus as i32)
+        );
+    }
+
     fn next_instruction(&mut self) -> Result<(), RVException> {
-        // Update CLINT
-        self.bus.clint.tick(&mut self.csrfile);
-        // Raise timer interrupt if enabled
-        self.csrfile.mtimer_interrupt()?;
+        // Update CLINT and UART
+        self.bus.tick(&mut self.csrfile, 1);
+        if self.waiting {
+            if !self.csrfile.wakeup_pending() {
+                // Stay on the instruction after WFI and let time pass
+                self.bus.idle(&self.csrfile);
+                self.next_pc = self.pc;
+                return Ok(());
+            }
+            self.waiting = false;
+        }
+        // Raise interrupts if enabled
+        self.csrfile.pending_interrupt(&self.mode)?;
 
-        // Fetch
-        let instruction = self.fetch()?;
-        // Decode
-        let decoded_instr = decode(&instruction)?;
+        // Fetch and decode
+        let (instruction, decoded_instr) = self.fetch_decoded()?;
+        self.debug.record(self.pc, instruction);
         debug!(
             "{:#010x} | {:#010x} | {:<6}",
             self.pc, instruction, decoded_instr
         );
 
+        // Jumps and branches overwrite this with their target
+        self.next_pc = if is_compressed(instruction) {
+            self.pc + 2
+        } else {
+            self.pc + 4
+        };
+
         // Execute
         exec(self, decoded_instr)?;
 
-        self.csrfile.count_cycle();
+        self.csrfile.counters.retire(1);
 
-        let cycle_count = self.cycle_count();
-        if self.instruction_count > 0 && cycle_count > self.instruction_count {
-            self.dump_state();
-            warn!(
-                "Limit of {} instructions reached. Exiting.",
-                self.instruction_count
-            );
-            std::process::exit(0);
+        Ok(())
+    }
+
+    pub fn step(&mut self) -> Option<HaltReason> {
+        let result = self.next_instruction();
+        self.finish(result)
+    }
+
+    // Trap or halt after an instruction, or advance the pc
+    fn finish(&mut self, result: Result<(), RVException>) -> Option<HaltReason> {
+        match result {
+            // An attached debugger gets breakpoints instead of the trap handler
+            Err(RVException::BreakPoint) if self.debug.attached => {
+                Some(HaltReason::Debug(self.debug.take_event()))
+            }
+            Err(exception) => {
+                let result = match self.test {
+                    true => self.riscv_tests_ecall(&exception),
+                    false => None,
+                };
+                if result.is_none() {
+                    self.trap_entry(exception);
+                }
+                result
+            }
+            // Fatal errors leave the pc at the offending instruction
+            Ok(()) => match self.halt.take() {
+                Some(HaltReason::Fatal(message)) => Some(HaltReason::Fatal(message)),
+                halt => {
+                    self.pc = self.next_pc;
+                    halt
+                }
+            },
         }
+    }
 
-        Ok(())
+    // Whether the hart is stopped in WFI
+    pub fn is_waiting(&self) -> bool {
+        self.waiting
     }
 
-    fn cycle_count(&self) -> u64 {
-        self.csrfile.read(0xC00) as u32 as u64
+    // Timer ticks that were slept or skipped in WFI
+    pub fn idle_ticks(&self) -> u64 {
+        self.bus.clint.borrow().idle_ticks()
     }
 
-    pub fn step(&mut self) {
-        match self.next_instruction() {
-            Err(exception) => self.trap_entry(exception),
-            Ok(()) => self.pc += 4,
-        };
-        std::thread::sleep(time::Duration::from_millis(self.delay));
+    pub fn read_register(&self, num: usize) -> u32 {
+        self.regfile.read(num) as u32
+    }
+
+    pub fn write_register(&mut self, num: usize, value: u32) {
+        self.regfile.write(num, value as i32);
+    }
+
+    // Raw register contents, single-precision values are NaN-boxed
+    pub fn read_fp_register(&self, num: usize) -> u64 {
+        self.fregfile.read(num)
+    }
+
+    pub fn write_fp_register(&mut self, num: usize, value: u64) {
+        self.fregfile.write(num, value);
+    }
+
+    pub fn read_csr(&self, addr: u32) -> u32 {
+        self.csrfile.read(addr as i32) as u32
+    }
+
+    pub fn write_csr(&mut self, addr: u32, value: u32) {
+        self.csrfile.write(addr as i32, value as i32);
+    }
+
+    pub fn mode(&self) -> ExecMode {
+        self.mode.clone()
+    }
+
+    pub fn set_mode(&mut self, mode: ExecMode) {
+        self.mode = mode;
+    }
+
+    // Debugger memory accesses go straight to the bus, bypassing MMU and PMP
+    pub fn read_memory(&mut self, addr: usize) -> Result<u8, BusError> {
+        self.bus.load::<u8>(addr)
+    }
+
+    pub fn write_memory(&mut self, addr: usize, value: u8) -> Result<(), BusError> {
+        self.invalidate_code(addr);
+        self.bus.store::<u8>(addr, value)
+    }
+
+    pub fn set_uart_output(&mut self, output: UartOutput) {
+        self.bus.set_uart_output(output);
+    }
+
+    pub fn set_uart_input(&mut self, input: UartInput) {
+        self.bus.set_uart_input(input);
+    }
+
+    pub fn add_ram(&mut self, name: &str, base: usize, size: usize) -> Result<(), MapError> {
+        self.bus.add_ram(name, base, size)
+    }
+
+    pub fn register_device(
+        &mut self,
+        name: &str,
+        base: usize,
+        size: usize,
+        device: Rc<RefCell<dyn Device>>,
+    ) -> Result<(), MapError> {
+        self.bus.register_device(name, base, size, device)
+    }
+
+    // Optional extensions (misa bits of M, A, F, D and C, plus the Zb* bits)
+    // that the hart implements
+    pub fn set_extensions(&mut self, mut extensions: u32) {
+        // D builds on the registers and CSRs of F
+        if extensions & MISA_F == 0 {
+            extensions &= !MISA_D;
+        }
+        self.extensions = extensions & EXT_OPTIONAL;
+        self.csrfile.set_extensions(extensions);
+        self.flush_code();
+    }
+
+    pub fn set_time_source(&mut self, time_source: TimeSource) {
+        self.bus.clint.borrow_mut().set_time_source(time_source);
+    }
+
+    pub fn set_timebase_frequency(&mut self, timebase_frequency: u64) {
+        self.bus
+            .clint
+            .borrow_mut()
+            .set_timebase_frequency(timebase_frequency);
     }
 
-    #[allow(dead_code)]
     pub fn dump_state(&self) {
         println!("=== CPU State @ PC {:#08x} ===", self.pc);
         for i in 0..32 {
             if i % 5 == 0 && i != 0 {
-                println!("");
+                println!();
             }
             print!(
                 "{:<4}: {:#010x}  ",
@@ -225,14 +645,20 @@ impl Cpu {
                 self.regfile.read(i)
             );
         }
-        println!("");
+        println!();
     }
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
 
     use super::*;
+    use crate::cpu::csr::EXT_ZBB;
+
+    // Little-endian image of 32-bit instructions, shared by the tests of all modules
+    pub(crate) fn program(instructions: &[u32]) -> Vec<u8> {
+        instructions.iter().flat_map(|v| v.to_le_bytes()).collect()
+    }
 
     #[test]
     fn test_srai() {
@@ -240,11 +666,11 @@ mod tests {
             .iter()
             .flat_map(|&v: &u32| v.to_le_bytes())
             .collect();
-        let mut cpu = Cpu::new(ram, 1024);
+        let mut cpu = Cpu::new(ram, 1024).unwrap();
         assert_eq!(cpu.next_instruction(), Ok(()));
         cpu.pc += 4;
         assert_eq!(cpu.next_instruction(), Ok(()));
-        assert_eq!(cpu.regfile.read(1) as i32, -2048);
+        assert_eq!(cpu.regfile.read(1), -2048);
     }
     #[test]
     fn test_lui() {
@@ -252,11 +678,327 @@ mod tests {
             .iter()
             .flat_map(|&v: &u32| v.to_le_bytes())
             .collect();
-        let mut cpu = Cpu::new(ram, 1024);
+        let mut cpu = Cpu::new(ram, 1024).unwrap();
         assert_eq!(cpu.next_instruction(), Ok(()));
         assert_eq!(cpu.regfile.read(1) as u32, 0x80000000);
         cpu.pc += 4;
         assert_eq!(cpu.next_instruction(), Ok(()));
         assert_eq!(cpu.regfile.read(2) as u32, 0xfffff000);
     }
+    #[test]
+    fn test_compressed_pc() {
+        // c.li ra, 1; c.j -2; addi sp, sp, 1
+        let ram: Vec<u8> = [0x4085u16, 0xbffd, 0x0113, 0x0011]
+            .iter()
+            .flat_map(|&v: &u16| v.to_le_bytes())
+            .collect();
+        let mut cpu = Cpu::new(ram, 1024).unwrap();
+        cpu.step();
+        assert_eq!(cpu.pc, RAM_START + 2);
+        assert_eq!(cpu.regfile.read(1), 1);
+        cpu.step();
+        assert_eq!(cpu.pc, RAM_START);
+    }
+    #[test]
+    fn test_misaligned_jump() {
+        // jal ra, 6; jalr ra, 2(a0); beq zero, zero, 6
+        let jumps = [(0x006000efu32, 6), (0x002500e7, 2), (0x00000363, 6)];
+        for (raw, offset) in jumps.iter() {
+            let mut cpu = Cpu::new(raw.to_le_bytes().to_vec(), 1024).unwrap();
+            cpu.set_extensions(EXT_OPTIONAL & !MISA_C);
+            cpu.write_register(10, RAM_START as u32);
+            cpu.step();
+            // The jump traps, not the fetch at the target
+            assert_eq!(cpu.csrfile.read(ArchCSRs::mcause as i32), 0);
+            assert_eq!(cpu.csrfile.read(ArchCSRs::mepc as i32), RAM_START as i32);
+            assert_eq!(
+                cpu.csrfile.read(ArchCSRs::mtval as i32),
+                (RAM_START + offset) as i32
+            );
+            assert_eq!(cpu.read_register(1), 0);
+        }
+
+        // With the C extension the targets are fine
+        let mut cpu = Cpu::new(0x006000efu32.to_le_bytes().to_vec(), 1024).unwrap();
+        cpu.step();
+        assert_eq!(cpu.pc, RAM_START + 6);
+        assert_eq!(cpu.read_register(1), (RAM_START + 4) as u32);
+    }
+    #[test]
+    fn test_float() {
+        // fadd.s fa0, fa1, fa2; fdiv.s fa3, fa1, fa4; fcvt.w.s a0, fa3, rup;
+        // fsw fa3, 0(t0)
+        let ram: Vec<u8> = [0x00c5f553, 0x18e5f6d3, 0xc006b553, 0x00d2a027]
+            .iter()
+            .flat_map(|&v: &u32| v.to_le_bytes())
+            .collect();
+        let mut cpu = Cpu::new(ram, 0x1000).unwrap();
+        cpu.write_fp_register(11, 0xffff_ffff_3f80_0000);
+        // Not NaN-boxed, so it reads as the canonical NaN
+        cpu.write_fp_register(12, 0x3f80_0000);
+        cpu.write_fp_register(14, 0xffff_ffff_4040_0000);
+        cpu.write_register(5, (RAM_START + 0x100) as u32);
+
+        // Illegal until mstatus.FS is turned on
+        cpu.step();
+        assert_eq!(cpu.csrfile.read(ArchCSRs::mcause as i32), 2);
+        cpu.pc = RAM_START;
+        cpu.csrfile.write(ArchCSRs::mstatus as i32, 0x2000);
+
+        for _ in 0..4 {
+            cpu.step();
+        }
+        assert_eq!(cpu.pc, RAM_START + 16);
+        assert_eq!(cpu.read_fp_register(10), 0xffff_ffff_7fc0_0000);
+        assert_eq!(cpu.read_fp_register(13), 0xffff_ffff_3eaa_aaab);
+        assert_eq!(cpu.read_register(10), 1);
+        assert_eq!(cpu.load::<u32>(RAM_START + 0x100), Ok(0x3eaa_aaab));
+        // Only the division was inexact
+        assert_eq!(cpu.csrfile.read(ArchCSRs::fflags as i32), 1);
+        assert_eq!(
+            cpu.csrfile.read(ArchCSRs::mstatus as i32) as u32,
+            0x8000_6000
+        );
+    }
+    #[test]
+    fn test_double() {
+        // fld fa1, 0(t0); fmadd.d fa0, fa1, fa2, fa3; fcvt.s.d fa4, fa0;
+        // fcvt.d.w fa5, a1; fclass.d a2, fa5; fsd fa0, 8(t0)
+        let ram: Vec<u8> = [
+            0x0002b587, 0x6ac5f543, 0x40157753, 0xd20587d3, 0xe2079653, 0x00a2b427,
+        ]
+        .iter()
+        .flat_map(|&v: &u32| v.to_le_bytes())
+        .collect();
+        let mut cpu = Cpu::new(ram, 0x1000).unwrap();
+        cpu.csrfile.write(ArchCSRs::mstatus as i32, 0x2000);
+        cpu.write_register(5, (RAM_START + 0x100) as u32);
+        cpu.write_register(11, -3i32 as u32);
+        // 0.1 * 10.0 - 1.0
+        cpu.store::<u64>(RAM_START + 0x100, 0x3fb9_9999_9999_999a)
+            .unwrap();
+        cpu.write_fp_register(12, 0x4024_0000_0000_0000);
+        cpu.write_fp_register(13, 0xbff0_0000_0000_0000);
+
+        for _ in 0..6 {
+            cpu.step();
+        }
+        assert_eq!(cpu.pc, RAM_START + 24);
+        // Rounding the product first would give exactly 0
+        assert_eq!(cpu.read_fp_register(10), 0x3c90_0000_0000_0000);
+        assert_eq!(cpu.read_fp_register(14), 0xffff_ffff_2480_0000);
+        assert_eq!(cpu.read_fp_register(15), 0xc008_0000_0000_0000);
+        // Negative normal number
+        assert_eq!(cpu.read_register(12), 1 << 1);
+        assert_eq!(
+            cpu.load::<u64>(RAM_START + 0x108),
+            Ok(0x3c90_0000_0000_0000)
+        );
+        assert_eq!(cpu.csrfile.read(ArchCSRs::fflags as i32), 0);
+    }
+    #[test]
+    fn test_bitmanip() {
+        // Each op with rd = a0, rs1 = a1, rs2 = a2 or an immediate
+        let ops: [(u32, u32); 32] = [
+            (0x20c5a533, 0x0024_02d0), // sh1add
+            (0x20c5c533, 0x0048_049c), // sh2add
+            (0x20c5e533, 0x0090_0834), // sh3add
+            (0x40c5f533, 0x8012_00e2), // andn
+            (0x40c5e533, 0xffff_feff), // orn
+            (0x40c5c533, 0x7fed_fe1d), // xnor
+            (0x0ac5e533, 0x0000_0104), // max
+            (0x0ac5f533, 0x8012_00e6), // maxu
+            (0x0ac5c533, 0x8012_00e6), // min
+            (0x0ac5d533, 0x0000_0104), // minu
+            (0x60c59533, 0x0120_0e68), // rol
+            (0x60c5d533, 0x6801_200e), // ror
+            (0x0805c533, 0x0000_00e6), // zext.h
+            (0x0ac59533, 0x1248_e598), // clmul
+            (0x0ac5b533, 0x0000_0082), // clmulh
+            (0x0ac5a533, 0x0000_0104), // clmulr
+            (0x48c59533, 0x8012_00e6), // bclr
+            (0x48c5d533, 0x0000_0000), // bext
+            (0x68c59533, 0x8012_00f6), // binv
+            (0x28c59533, 0x8012_00f6), // bset
+            (0x60059513, 0x0000_0000), // clz
+            (0x60159513, 0x0000_0001), // ctz
+            (0x60259513, 0x0000_0008), // cpop
+            (0x60459513, 0xffff_ffe6), // sext.b
+            (0x60559513, 0x0000_00e6), // sext.h
+            (0x2875d513, 0xffff_00ff), // orc.b
+            (0x6985d513, 0xe600_1280), // rev8
+            (0x6045d513, 0x6801_200e), // rori 4
+            (0x49f59513, 0x0012_00e6), // bclri 31
+            (0x4855d513, 0x0000_0001), // bexti 5
+            (0x68059513, 0x8012_00e7), // binvi 0
+            (0x29e59513, 0xc012_00e6), // bseti 30
+        ];
+        for (raw, result) in ops.iter() {
+            let mut cpu = Cpu::new(raw.to_le_bytes().to_vec(), 1024).unwrap();
+            cpu.write_register(11, 0x8012_00e6);
+            // Shift amounts only use the low 5 bits
+            cpu.write_register(12, 0x104);
+            cpu.step();
+            assert_eq!(cpu.read_register(10), *result, "{:#010x}", raw);
+        }
+
+        // clz traps without Zbb, as does zext.h with rs2 != 0
+        let illegal = [
+            (0x60059513u32, EXT_OPTIONAL & !EXT_ZBB),
+            (0x0815c533, EXT_OPTIONAL),
+        ];
+        for (raw, extensions) in illegal.iter() {
+            let mut cpu = Cpu::new(raw.to_le_bytes().to_vec(), 1024).unwrap();
+            cpu.set_extensions(*extensions);
+            cpu.step();
+            assert_eq!(cpu.csrfile.read(ArchCSRs::mcause as i32), 2);
+        }
+    }
+    #[test]
+    fn test_counters() {
+        // nop; csrr a0, instret; csrr a1, cycleh
+        let ram: Vec<u8> = [0x00000013, 0xc0202573, 0xc80025f3]
+            .iter()
+            .flat_map(|&v: &u32| v.to_le_bytes())
+            .collect();
+        let mut cpu = Cpu::new(ram, 1024).unwrap();
+        for _ in 0..3 {
+            cpu.step();
+        }
+        assert_eq!(cpu.read_register(10), 1);
+        assert_eq!(cpu.read_register(11), 0);
+        assert_eq!(cpu.csrfile.read(ArchCSRs::minstret as i32), 3);
+
+        // U-mode needs the counter enabled in mcounteren and scounteren
+        cpu.csrfile.write(ArchCSRs::pmpaddr0 as i32, -1);
+        cpu.csrfile.write(ArchCSRs::pmpcfg0 as i32, 0x0f);
+        cpu.pc = RAM_START + 4;
+        cpu.mode = ExecMode::USER;
+        cpu.step();
+        assert_eq!(cpu.csrfile.read(ArchCSRs::mcause as i32), 2);
+        cpu.csrfile.write(ArchCSRs::mcounteren as i32, 0b100);
+        cpu.csrfile.write(ArchCSRs::scounteren as i32, 0b100);
+        cpu.pc = RAM_START + 4;
+        cpu.mode = ExecMode::USER;
+        cpu.step();
+        // The attempt that trapped didn't retire
+        assert_eq!(cpu.read_register(10), 3);
+    }
+    #[test]
+    fn test_wfi_trap() {
+        // wfi
+        let mut cpu = Cpu::new(0x10500073u32.to_le_bytes().to_vec(), 1024).unwrap();
+        cpu.csrfile.write(ArchCSRs::pmpaddr0 as i32, -1);
+        cpu.csrfile.write(ArchCSRs::pmpcfg0 as i32, 0x0f);
+        cpu.csrfile.write(ArchCSRs::mie as i32, 1 << 7);
+        cpu.mode = ExecMode::USER;
+        cpu.step();
+        assert_eq!(cpu.csrfile.read(ArchCSRs::mcause as i32), 2);
+
+        // S-mode only traps with mstatus.TW
+        cpu.csrfile.write(ArchCSRs::mcause as i32, 0);
+        cpu.pc = RAM_START;
+        cpu.mode = ExecMode::SUPERVISOR;
+        cpu.step();
+        assert!(cpu.waiting);
+        assert_eq!(cpu.csrfile.read(ArchCSRs::mcause as i32), 0);
+        cpu.waiting = false;
+        cpu.csrfile.write(ArchCSRs::mstatus as i32, 1 << 21);
+        cpu.pc = RAM_START;
+        cpu.mode = ExecMode::SUPERVISOR;
+        cpu.step();
+        assert_eq!(cpu.csrfile.read(ArchCSRs::mcause as i32), 2);
+        assert_eq!(cpu.csrfile.read(ArchCSRs::mepc as i32), RAM_START as i32);
+    }
+    #[test]
+    fn test_pmp_user() {
+        let mut cpu = Cpu::new(vec![0u8; 0x2000], 0x2000).unwrap();
+        // Read-only 4 KiB region at the start of RAM
+        cpu.csrfile
+            .write(ArchCSRs::pmpaddr0 as i32, (RAM_START >> 2 | 0x1ff) as i32);
+        cpu.csrfile.write(ArchCSRs::pmpcfg0 as i32, 0x19);
+        cpu.mode = ExecMode::USER;
+
+        assert_eq!(cpu.load::<u32>(RAM_START), Ok(0));
+        assert_eq!(
+            cpu.store::<u32>(RAM_START, 1),
+            Err(RVException::StoreAccessFault(RAM_START))
+        );
+        assert_eq!(
+            cpu.load::<u32>(RAM_START + 0x1000),
+            Err(RVException::LoadAccessFault(RAM_START + 0x1000))
+        );
+        assert_eq!(
+            cpu.fetch(),
+            Err(RVException::InstructionAccessFault(RAM_START))
+        );
+        // M-mode ignores unlocked entries
+        cpu.mode = ExecMode::MACHINE;
+        assert_eq!(cpu.store::<u32>(RAM_START, 1), Ok(()));
+    }
+    #[test]
+    fn test_uart_external_interrupt() {
+        // nop in the trap handler
+        let mut ram = vec![0u8; 0x100];
+        ram[0x40] = 0x13;
+        let mut cpu = Cpu::new(ram, 0x100).unwrap();
+        let mut input = Some(b'a');
+        cpu.set_uart_input(Box::new(move || input.take()));
+        // UART receive interrupt at priority 1, enabled for the M-mode context
+        cpu.bus.store::<u8>(0x1000_0001, 0x01).unwrap();
+        cpu.bus.store::<u32>(0x0c00_0000 + 4 * 10, 1).unwrap();
+        cpu.bus.store::<u32>(0x0c00_2000, 1 << 10).unwrap();
+        cpu.csrfile
+            .write(ArchCSRs::mtvec as i32, (RAM_START + 0x40) as i32);
+        cpu.csrfile.write(ArchCSRs::mie as i32, 1 << 11);
+        cpu.csrfile.write(ArchCSRs::mstatus as i32, 0x8);
+
+        cpu.step();
+        assert_eq!(cpu.pc, RAM_START + 0x40);
+        assert_eq!(
+            cpu.csrfile.read(ArchCSRs::mcause as i32) as u32,
+            0x8000_000b
+        );
+        // Claiming the interrupt and reading the byte lowers the line
+        assert_eq!(cpu.bus.load::<u32>(0x0c20_0004), Ok(10));
+        assert_eq!(cpu.bus.load::<u8>(0x1000_0000), Ok(b'a'));
+        cpu.bus.store::<u32>(0x0c20_0004, 10).unwrap();
+        cpu.step();
+        assert_eq!(cpu.pc, RAM_START + 0x44);
+    }
+    #[test]
+    fn test_load_elf() {
+        let elf_bytes = include_bytes!("../tests/rv32ui-p-add").to_vec();
+        let mut cpu = Cpu::new(vec![0xffu8; 0x2000], 0x2000).unwrap();
+        cpu.pc = 0;
+        assert_eq!(cpu.load_elf(elf_bytes.clone()), Ok(RAM_START + 0x1048));
+        assert_eq!(cpu.pc, RAM_START);
+        assert_eq!(cpu.bus.ram.mem[..4], elf_bytes[0x1000..0x1004]);
+        // Untouched memory between segments keeps its contents
+        assert_eq!(cpu.bus.ram.mem[0x0ffc], 0xff);
+
+        // The second segment does not fit anymore
+        let mut cpu = Cpu::new(vec![], 0x1000).unwrap();
+        assert_eq!(
+            cpu.load_elf(elf_bytes.clone()),
+            Err(ElfError::SegmentOutOfRange(RAM_START + 0x1000, 0x48))
+        );
+        // Nor does one above the end of RAM or past the end of the file
+        let mut above = elf_bytes.clone();
+        above[0x34 + 2 * 32 + 12..][..4].copy_from_slice(&0x9000_0000u32.to_le_bytes());
+        assert_eq!(
+            cpu.load_elf(above),
+            Err(ElfError::SegmentOutOfRange(0x9000_0000, 0x48))
+        );
+        let mut truncated = elf_bytes;
+        truncated[0x34 + 32 + 4..][..4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(
+            Cpu::new(vec![], 0x2000).unwrap().load_elf(truncated),
+            Err(ElfError::SegmentOutOfRange(RAM_START, 0x6bc))
+        );
+        assert!(matches!(
+            cpu.load_elf(vec![0u8; 16]),
+            Err(ElfError::Parse(_))
+        ));
+    }
 }
@@ -1,25 +1,47 @@
 use num_traits::FromPrimitive;
 use tracing::info;
 
-use super::instructions::{IInstruction, Instruction, RInstruction, SBInstruction, UJInstruction};
+use super::counters::Event;
+use super::instructions::{
+    FInstruction, IInstruction, Instruction, RInstruction, SBInstruction, UJInstruction,
+};
+use super::softfloat::{RoundingMode, DOUBLE, SINGLE};
 use super::Cpu;
-use crate::bus::{BusDevice, BusError};
+use crate::bus::{BusDevice, BusWidth};
 use crate::cpu::csr::ArchCSRs;
-use crate::cpu::ExecMode;
+use crate::cpu::mmu::AccessType;
+use crate::cpu::{ExecMode, HaltReason};
 use crate::trap::RVException;
 
-fn handle_load_error(e: BusError) -> RVException {
-    match e {
-        BusError::AddressMisaligned(addr) => RVException::LoadAddressMisaligned(addr),
-        BusError::AddressUnmapped(addr) => RVException::LoadAccessFault(addr),
+// Floating-point instructions and CSRs trap while mstatus.FS is Off
+fn check_fp(cpu: &Cpu) -> Result<(), RVException> {
+    match cpu.csrfile.fp_enabled() {
+        true => Ok(()),
+        false => Err(RVException::IllegalInstruction(0)),
     }
 }
 
-fn handle_store_error(e: BusError) -> RVException {
-    match e {
-        BusError::AddressMisaligned(addr) => RVException::StoreAddressMisaligned(addr),
-        BusError::AddressUnmapped(addr) => RVException::StoreAccessFault(addr),
-    }
+fn is_fp_csr(addr: i32) -> bool {
+    matches!(
+        ArchCSRs::from_i32(addr),
+        Some(ArchCSRs::fflags | ArchCSRs::frm | ArchCSRs::fcsr)
+    )
+}
+
+// Carry-less product of two words
+fn clmul(a: i32, b: i32) -> u64 {
+    (0..32)
+        .filter(|i| (b as u32) >> i & 1 != 0)
+        .fold(0, |product, i| product ^ (a as u32 as u64) << i)
+}
+
+// Every non-zero byte becomes 0xff
+fn orc_b(value: i32) -> i32 {
+    i32::from_le_bytes(
+        value
+            .to_le_bytes()
+            .map(|byte| if byte == 0 { 0 } else { 0xff }),
+    )
 }
 
 fn exec_i(
@@ -32,6 +54,26 @@ fn exec_i(
     // Load rs1 contents
     let rs1_data = cpu.regfile.read(rs1);
 
+    let csr_access = matches!(
+        inst,
+        IInstruction::csrrw
+            | IInstruction::csrrs
+            | IInstruction::csrrc
+            | IInstruction::csrrwi
+            | IInstruction::csrrsi
+            | IInstruction::csrrci
+    );
+    // The immediate is sign-extended, CSR addresses are not
+    let csr = imm & 0xfff;
+    // csrrs and csrrc with x0, and their immediate forms with 0, only read
+    let csr_write = matches!(inst, IInstruction::csrrw | IInstruction::csrrwi) || rs1 != 0;
+    if csr_access {
+        cpu.csrfile.check_access(csr, &cpu.mode, csr_write)?;
+        if is_fp_csr(csr) {
+            check_fp(cpu)?;
+        }
+    }
+
     // Handle all instructions that write back to rd
     if let Some(result) = match inst {
         // Arithmetic
@@ -57,73 +99,64 @@ fn exec_i(
             }
         }
 
-        // Load
-        IInstruction::lb => Some(
-            cpu.bus
-                .load::<i8>((rs1_data + imm) as u32 as usize)
-                .map_err(|e| handle_load_error(e))? as i32,
-        ),
-        IInstruction::lh => Some(
-            cpu.bus
-                .load::<i16>((rs1_data + imm) as u32 as usize)
-                .map_err(|e| handle_load_error(e))? as i32,
-        ),
-        IInstruction::lw => Some(
-            cpu.bus
-                .load::<i32>((rs1_data + imm) as u32 as usize)
-                .map_err(|e| handle_load_error(e))? as i32,
-        ),
-        IInstruction::lbu => Some(
-            cpu.bus
-                .load::<u8>((rs1_data + imm) as u32 as usize)
-                .map_err(|e| handle_load_error(e))? as i32,
-        ),
-        IInstruction::lhu => Some(
-            cpu.bus
-                .load::<u16>((rs1_data + imm) as u32 as usize)
-                .map_err(|e| handle_load_error(e))? as i32,
-        ),
+        // Zbb and Zbs instructions
+        IInstruction::clz => Some(rs1_data.leading_zeros() as i32),
+        IInstruction::ctz => Some(rs1_data.trailing_zeros() as i32),
+        IInstruction::cpop => Some(rs1_data.count_ones() as i32),
+        IInstruction::sextb => Some(rs1_data as i8 as i32),
+        IInstruction::sexth => Some(rs1_data as i16 as i32),
+        IInstruction::orcb => Some(orc_b(rs1_data)),
+        IInstruction::rev8 => Some(rs1_data.swap_bytes()),
+        IInstruction::rori => Some(rs1_data.rotate_right((imm & 0x1f) as u32)),
+        IInstruction::bclri => Some(rs1_data & !(1 << (imm & 0x1f))),
+        IInstruction::bexti => Some(rs1_data >> (imm & 0x1f) & 1),
+        IInstruction::binvi => Some(rs1_data ^ 1 << (imm & 0x1f)),
+        IInstruction::bseti => Some(rs1_data | 1 << (imm & 0x1f)),
 
-        // Zicsr Instructions
-        IInstruction::csrrw => {
-            let register_data = cpu.csrfile.read(imm);
-            cpu.csrfile.write(imm, rs1_data);
-            Some(register_data)
-        }
-        IInstruction::csrrs => {
-            let register_data = cpu.csrfile.read(imm);
-            cpu.csrfile.write(imm, register_data | rs1_data);
-            Some(register_data)
+        // Load
+        IInstruction::lb => {
+            Some(cpu.load::<i8>(rs1_data.wrapping_add(imm) as u32 as usize)? as i32)
         }
-        IInstruction::csrrc => {
-            let register_data = cpu.csrfile.read(imm);
-            cpu.csrfile.write(imm, register_data & !rs1_data);
-            Some(register_data)
+        IInstruction::lh => {
+            Some(cpu.load::<i16>(rs1_data.wrapping_add(imm) as u32 as usize)? as i32)
         }
-        IInstruction::csrrwi => {
-            let register_data = cpu.csrfile.read(imm);
-            let uimm = rs1 as u32;
-            cpu.csrfile.write(imm, uimm as i32);
-            Some(register_data)
+        IInstruction::lw => Some(cpu.load::<i32>(rs1_data.wrapping_add(imm) as u32 as usize)?),
+        IInstruction::lbu => {
+            Some(cpu.load::<u8>(rs1_data.wrapping_add(imm) as u32 as usize)? as i32)
         }
-        IInstruction::csrrsi => {
-            let register_data = cpu.csrfile.read(imm);
-            let uimm = rs1 as u32;
-            cpu.csrfile.write(imm, register_data | uimm as i32);
-            Some(register_data)
+        IInstruction::lhu => {
+            Some(cpu.load::<u16>(rs1_data.wrapping_add(imm) as u32 as usize)? as i32)
         }
-        IInstruction::csrrci => {
-            let register_data = cpu.csrfile.read(imm);
-            let uimm = rs1 as u32;
-            cpu.csrfile.write(imm, register_data & !(uimm as i32));
+
+        // Zicsr Instructions
+        IInstruction::csrrw
+        | IInstruction::csrrs
+        | IInstruction::csrrc
+        | IInstruction::csrrwi
+        | IInstruction::csrrsi
+        | IInstruction::csrrci => {
+            let register_data = cpu.csrfile.read(csr);
+            // The immediate forms take a 5-bit zero-extended immediate in rs1
+            let operand = match inst {
+                IInstruction::csrrwi | IInstruction::csrrsi | IInstruction::csrrci => rs1 as i32,
+                _ => rs1_data,
+            };
+            let value = match inst {
+                IInstruction::csrrw | IInstruction::csrrwi => operand,
+                IInstruction::csrrs | IInstruction::csrrsi => register_data | operand,
+                _ => register_data & !operand,
+            };
+            if csr_write {
+                cpu.csrfile.write(csr, value);
+            }
             Some(register_data)
         }
 
         // Jump
         IInstruction::jalr => {
-            let old_pc = cpu.pc as i32;
-            cpu.pc = (rs1_data + imm - 4) as u32 as usize;
-            Some(old_pc + 4)
+            let link = cpu.next_pc as i32;
+            cpu.jump((rs1_data.wrapping_add(imm) & !1) as u32 as usize)?;
+            Some(link)
         }
 
         // Handle Ecall and Ebreak instructions separately
@@ -131,47 +164,131 @@ fn exec_i(
     } {
         // Write result back to rd
         cpu.regfile.write(rd, result);
-        return Ok(());
+        Ok(())
     } else {
         match inst {
             IInstruction::ebreak => Err(RVException::BreakPoint),
             IInstruction::ecall => match cpu.mode {
                 ExecMode::MACHINE => Err(RVException::EnvironmentCallM),
+                ExecMode::SUPERVISOR => Err(RVException::EnvironmentCallS),
                 ExecMode::USER => Err(RVException::EnvironmentCallU),
             },
 
             // Sequential execution anyway, so no
             // need to fence anything
             IInstruction::fence => Ok(()),
-            IInstruction::fencei => Ok(()),
+            // Stores already invalidate decoded instructions, this also
+            // covers code that was modified behind the core's back
+            IInstruction::fencei => {
+                cpu.flush_code();
+                Ok(())
+            }
+
+            IInstruction::sfencevma => {
+                const MSTATUS_TVM: i32 = 1 << 20;
+                let tvm = cpu.csrfile.read(ArchCSRs::mstatus as i32) & MSTATUS_TVM != 0;
+                if cpu.mode == ExecMode::USER || (cpu.mode == ExecMode::SUPERVISOR && tvm) {
+                    return Err(RVException::IllegalInstruction(0));
+                }
+                // The TLB is not tagged with ASIDs, so rs2 is ignored
+                if rs1 == 0 {
+                    cpu.mmu.flush(None);
+                } else {
+                    cpu.mmu.flush(Some(rs1_data as u32 as usize));
+                }
+                Ok(())
+            }
 
             IInstruction::mret => {
+                if cpu.mode != ExecMode::MACHINE {
+                    return Err(RVException::IllegalInstruction(0));
+                }
+
                 // Re-enable interrupts
                 cpu.csrfile.enable_irq();
 
                 // Restore PC from mepc
-                cpu.pc = cpu.csrfile.read(ArchCSRs::mepc as i32) as u32 as usize - 4;
+                cpu.next_pc = cpu.csrfile.read(ArchCSRs::mepc as i32) as u32 as usize;
 
                 cpu.mode = ExecMode::from_u32(cpu.csrfile.get_mpp()).unwrap();
-                cpu.csrfile.set_mpp(&(ExecMode::MACHINE as u32));
+                cpu.csrfile.set_mpp(&(ExecMode::USER as u32));
+                if cpu.mode != ExecMode::MACHINE {
+                    cpu.csrfile.clear_mprv();
+                }
                 info!(
                     "Returning from trap to mode {:?}, mstatus: {:#010x}, PC: {:#010x}",
                     cpu.mode,
                     cpu.csrfile.read(ArchCSRs::mstatus as i32),
-                    cpu.pc
+                    cpu.next_pc
                 );
 
                 Ok(())
             }
             IInstruction::sret => {
-                panic!("Supervisor mode not implemented yet");
+                const MSTATUS_TSR: i32 = 1 << 22;
+                let tsr = cpu.csrfile.read(ArchCSRs::mstatus as i32) & MSTATUS_TSR != 0;
+                if cpu.mode == ExecMode::USER || (cpu.mode == ExecMode::SUPERVISOR && tsr) {
+                    return Err(RVException::IllegalInstruction(0));
+                }
+
+                // Re-enable supervisor interrupts
+                cpu.csrfile.enable_sirq();
+
+                // Restore PC from sepc
+                cpu.next_pc = cpu.csrfile.read(ArchCSRs::sepc as i32) as u32 as usize;
+
+                cpu.mode = ExecMode::from_u32(cpu.csrfile.get_spp()).unwrap();
+                cpu.csrfile.set_spp(&(ExecMode::USER as u32));
+                cpu.csrfile.clear_mprv();
+                info!(
+                    "Returning from supervisor trap to mode {:?}, sstatus: {:#010x}, PC: {:#010x}",
+                    cpu.mode,
+                    cpu.csrfile.read(ArchCSRs::sstatus as i32),
+                    cpu.next_pc
+                );
+
+                Ok(())
+            }
+            IInstruction::flw => {
+                check_fp(cpu)?;
+                let value = cpu.load::<u32>(rs1_data.wrapping_add(imm) as u32 as usize)?;
+                cpu.fregfile.write_single(rd, value);
+                cpu.csrfile.set_fp_dirty();
+                Ok(())
+            }
+            IInstruction::fld => {
+                check_fp(cpu)?;
+                let value = cpu.load::<u64>(rs1_data.wrapping_add(imm) as u32 as usize)?;
+                cpu.fregfile.write(rd, value);
+                cpu.csrfile.set_fp_dirty();
+                Ok(())
             }
             IInstruction::wfi => {
-                // Ignore sleep for now
+                // The wait isn't bounded in time, so U-mode always traps and
+                // mstatus.TW traps S-mode
+                const MSTATUS_TW: i32 = 1 << 21;
+                let tw = cpu.csrfile.read(ArchCSRs::mstatus as i32) & MSTATUS_TW != 0;
+                if cpu.mode == ExecMode::USER || (cpu.mode == ExecMode::SUPERVISOR && tw) {
+                    return Err(RVException::IllegalInstruction(0));
+                }
+
+                // Nothing but an interrupt can wake the hart up, so without any
+                // enabled interrupt it would sleep forever
+                if cpu.csrfile.read(ArchCSRs::mie as i32) == 0 {
+                    cpu.halt = Some(HaltReason::WaitForInterrupt);
+                } else {
+                    cpu.waiting = true;
+                }
                 Ok(())
             }
 
-            _ => panic!("Unimplemented instruction: {:?}", inst),
+            _ => {
+                cpu.halt = Some(HaltReason::Fatal(format!(
+                    "Unimplemented instruction: {:?}",
+                    inst
+                )));
+                Ok(())
+            }
         }
     }
 }
@@ -189,14 +306,24 @@ fn exec_r(
     // Generic closure for atomic logic instructions
     // to make the implementation less verbose.
     let mut amo_logic = |operation: fn(i32, i32) -> i32| -> Result<i32, RVException> {
+        // AMOs read and write the same location, faults are reported as stores
+        let addr = rs1_data as u32 as usize;
+        if !i32::is_aligned(addr) {
+            return Err(RVException::StoreAddressMisaligned(addr));
+        }
+        let paddr = cpu.translate(addr, u32::WIDTH, AccessType::Store)?;
+        if cpu.debug.check(addr, u32::WIDTH, true) || cpu.debug.check(addr, u32::WIDTH, false) {
+            return Err(RVException::BreakPoint);
+        }
         let mem_value = cpu
             .bus
-            .load::<i32>(rs1_data as u32 as usize)
-            .map_err(|e| handle_load_error(e))?;
+            .load::<i32>(paddr)
+            .map_err(|e| AccessType::Store.bus_error(e, addr))?;
         let result = operation(mem_value, rs2_data);
+        cpu.invalidate_code(paddr);
         cpu.bus
-            .store::<i32>(rs1_data as u32 as usize, result)
-            .map_err(|e| handle_store_error(e))?;
+            .store::<i32>(paddr, result)
+            .map_err(|e| AccessType::Store.bus_error(e, addr))?;
 
         Ok(mem_value)
     };
@@ -237,19 +364,14 @@ fn exec_r(
         RInstruction::amoSwapW => amo_logic(|_, b| b)?,
         RInstruction::lrw => {
             let addr = rs1_data as u32 as usize;
-            let mem_value = cpu
-                .bus
-                .load::<i32>(addr)
-                .map_err(|e| handle_load_error(e))?;
+            let mem_value = cpu.load::<i32>(addr)?;
             cpu.amoreserved.insert(addr);
             mem_value
         }
         RInstruction::scw => {
             let addr = rs1_data as u32 as usize;
             if cpu.amoreserved.remove(&addr) {
-                cpu.bus
-                    .store::<i32>(addr, rs2_data)
-                    .map_err(|e| handle_store_error(e))?;
+                cpu.store::<i32>(addr, rs2_data)?;
                 0
             } else {
                 1
@@ -289,6 +411,34 @@ fn exec_r(
                 (rs1_data as u32).wrapping_rem(rs2_data as u32) as i32
             }
         }
+
+        // Zba instructions
+        RInstruction::sh1add => (rs1_data << 1).wrapping_add(rs2_data),
+        RInstruction::sh2add => (rs1_data << 2).wrapping_add(rs2_data),
+        RInstruction::sh3add => (rs1_data << 3).wrapping_add(rs2_data),
+
+        // Zbb instructions
+        RInstruction::andn => rs1_data & !rs2_data,
+        RInstruction::orn => rs1_data | !rs2_data,
+        RInstruction::xnor => !(rs1_data ^ rs2_data),
+        RInstruction::max => rs1_data.max(rs2_data),
+        RInstruction::maxu => (rs1_data as u32).max(rs2_data as u32) as i32,
+        RInstruction::min => rs1_data.min(rs2_data),
+        RInstruction::minu => (rs1_data as u32).min(rs2_data as u32) as i32,
+        RInstruction::rol => rs1_data.rotate_left(rs2_data as u32 & 0x1f),
+        RInstruction::ror => rs1_data.rotate_right(rs2_data as u32 & 0x1f),
+        RInstruction::zexth => rs1_data & 0xffff,
+
+        // Zbc instructions
+        RInstruction::clmul => clmul(rs1_data, rs2_data) as i32,
+        RInstruction::clmulh => (clmul(rs1_data, rs2_data) >> 32) as i32,
+        RInstruction::clmulr => (clmul(rs1_data, rs2_data) >> 31) as i32,
+
+        // Zbs instructions
+        RInstruction::bclr => rs1_data & !(1 << (rs2_data & 0x1f)),
+        RInstruction::bext => rs1_data >> (rs2_data & 0x1f) & 1,
+        RInstruction::binv => rs1_data ^ 1 << (rs2_data & 0x1f),
+        RInstruction::bset => rs1_data | 1 << (rs2_data & 0x1f),
     };
     cpu.regfile.write(rd, result);
     Ok(())
@@ -303,26 +453,22 @@ fn exec_s_b(
 ) -> Result<(), RVException> {
     let rs1_data = cpu.regfile.read(rs1);
     let rs2_data = cpu.regfile.read(rs2);
+    let addr = rs1_data.wrapping_add(imm) as u32 as usize;
     if let Some(result) = match inst {
         // Stores
-        SBInstruction::sb => Some(
-            cpu.bus
-                .store::<i8>((rs1_data + imm) as u32 as usize, rs2_data as i8),
-        ),
-        SBInstruction::sh => Some(
-            cpu.bus
-                .store::<i16>((rs1_data + imm) as u32 as usize, rs2_data as i16),
-        ),
-        SBInstruction::sw => Some(
-            cpu.bus
-                .store::<i32>((rs1_data + imm) as u32 as usize, rs2_data),
-        ),
+        SBInstruction::sb => Some(cpu.store::<i8>(addr, rs2_data as i8)),
+        SBInstruction::sh => Some(cpu.store::<i16>(addr, rs2_data as i16)),
+        SBInstruction::sw => Some(cpu.store::<i32>(addr, rs2_data)),
+        // Stores the low bits as they are, whether NaN-boxed or not
+        SBInstruction::fsw => {
+            Some(check_fp(cpu).and_then(|_| cpu.store::<u32>(addr, cpu.fregfile.read(rs2) as u32)))
+        }
+        SBInstruction::fsd => {
+            Some(check_fp(cpu).and_then(|_| cpu.store::<u64>(addr, cpu.fregfile.read(rs2))))
+        }
         _ => None,
     } {
-        return result.map_err(|e| match e {
-            BusError::AddressMisaligned(addr) => RVException::StoreAddressMisaligned(addr),
-            BusError::AddressUnmapped(addr) => RVException::StoreAccessFault(addr),
-        });
+        return result;
     } else {
         // Conditional jumps
         let jump_taken = match inst {
@@ -335,10 +481,8 @@ fn exec_s_b(
             _ => unreachable!(),
         };
         if jump_taken {
-            // Set PC to instruction *before* jump target
-            // PC is incremented unconditionally by 4 after each instruction
-            let jump_target = (cpu.pc as isize) + (imm as isize) - 4;
-            cpu.pc = jump_target as usize;
+            cpu.jump((cpu.pc as u32).wrapping_add(imm as u32) as usize)?;
+            cpu.csrfile.counters.count(Event::BranchTaken);
         }
     }
     Ok(())
@@ -350,19 +494,147 @@ fn exec_u_j(cpu: &mut Cpu, imm: i32, rd: usize, inst: UJInstruction) -> Result<(
         UJInstruction::auipc => old_pc.wrapping_add(imm << 12),
         UJInstruction::lui => imm << 12,
         UJInstruction::jal => {
-            // Set PC to instruction *before* jump target
-            // PC is incremented unconditionally by 4 after each instruction
-            // cpu.pc += imm as usize - 4;
-            let old_pc = cpu.pc as isize;
-            cpu.pc = (old_pc + (imm as isize) - 4) as usize;
-
-            (old_pc + 4) as i32
+            let link = cpu.next_pc as i32;
+            cpu.jump((cpu.pc as u32).wrapping_add(imm as u32) as usize)?;
+            link
         }
     };
     cpu.regfile.write(rd, result);
     Ok(())
 }
 
+// Rounding mode of an instruction, where 0b111 selects the one in frm. The
+// remaining encodings are reserved and illegal.
+fn rounding_mode(cpu: &Cpu, rm: u32) -> Result<RoundingMode, RVException> {
+    let rm = match rm {
+        0b111 => cpu.csrfile.rounding_mode(),
+        rm => rm,
+    };
+    RoundingMode::from_u32(rm).ok_or(RVException::IllegalInstruction(0))
+}
+
+enum FResult {
+    Single(u32),
+    Double(u64),
+    Integer(i32),
+}
+
+fn exec_f(
+    cpu: &mut Cpu,
+    rd: usize,
+    rs1: usize,
+    rs2: usize,
+    rs3: usize,
+    rm: u32,
+    inst: FInstruction,
+) -> Result<(), RVException> {
+    check_fp(cpu)?;
+    // Doubles use the whole register, singles have to be NaN-boxed
+    let double = inst.is_double();
+    let format = if double { DOUBLE } else { SINGLE };
+    let read = |num: usize| match double {
+        true => cpu.fregfile.read(num),
+        false => cpu.fregfile.read_single(num) as u64,
+    };
+    let (a, b, c) = (read(rs1), read(rs2), read(rs3));
+    let sign = format.sign_bit();
+
+    let float = |(value, flags): (u64, u32)| match double {
+        true => (FResult::Double(value), flags),
+        false => (FResult::Single(value as u32), flags),
+    };
+    let integer = |(value, flags): (bool, u32)| (FResult::Integer(value as i32), flags);
+    let (result, flags) = match inst {
+        // Negating before the fused operation keeps a single rounding
+        FInstruction::fmadds | FInstruction::fmaddd => {
+            float(format.fused_mul_add(a, b, c, rounding_mode(cpu, rm)?))
+        }
+        FInstruction::fmsubs | FInstruction::fmsubd => {
+            float(format.fused_mul_add(a, b, c ^ sign, rounding_mode(cpu, rm)?))
+        }
+        FInstruction::fnmsubs | FInstruction::fnmsubd => {
+            float(format.fused_mul_add(a ^ sign, b, c, rounding_mode(cpu, rm)?))
+        }
+        FInstruction::fnmadds | FInstruction::fnmaddd => {
+            float(format.fused_mul_add(a ^ sign, b, c ^ sign, rounding_mode(cpu, rm)?))
+        }
+
+        FInstruction::fadds | FInstruction::faddd => {
+            float(format.add(a, b, rounding_mode(cpu, rm)?))
+        }
+        FInstruction::fsubs | FInstruction::fsubd => {
+            float(format.sub(a, b, rounding_mode(cpu, rm)?))
+        }
+        FInstruction::fmuls | FInstruction::fmuld => {
+            float(format.mul(a, b, rounding_mode(cpu, rm)?))
+        }
+        FInstruction::fdivs | FInstruction::fdivd => {
+            float(format.div(a, b, rounding_mode(cpu, rm)?))
+        }
+        FInstruction::fsqrts | FInstruction::fsqrtd => {
+            float(format.sqrt(a, rounding_mode(cpu, rm)?))
+        }
+
+        // Sign injection only moves bits, even for NaNs
+        FInstruction::fsgnjs | FInstruction::fsgnjd => float((a & !sign | b & sign, 0)),
+        FInstruction::fsgnjns | FInstruction::fsgnjnd => float((a & !sign | !b & sign, 0)),
+        FInstruction::fsgnjxs | FInstruction::fsgnjxd => float((a ^ b & sign, 0)),
+        FInstruction::fmins | FInstruction::fmind => float(format.min(a, b)),
+        FInstruction::fmaxs | FInstruction::fmaxd => float(format.max(a, b)),
+
+        // Conversions between the formats
+        FInstruction::fcvtsd => {
+            let (value, flags) = SINGLE.convert(DOUBLE, a, rounding_mode(cpu, rm)?);
+            (FResult::Single(value as u32), flags)
+        }
+        FInstruction::fcvtds => {
+            let value = cpu.fregfile.read_single(rs1) as u64;
+            float(DOUBLE.convert(SINGLE, value, rounding_mode(cpu, rm)?))
+        }
+
+        FInstruction::fcvtws | FInstruction::fcvtwd => {
+            let (value, flags) = format.to_i32(a, rounding_mode(cpu, rm)?);
+            (FResult::Integer(value), flags)
+        }
+        FInstruction::fcvtwus | FInstruction::fcvtwud => {
+            let (value, flags) = format.to_u32(a, rounding_mode(cpu, rm)?);
+            (FResult::Integer(value as i32), flags)
+        }
+        FInstruction::fcvtsw | FInstruction::fcvtdw => {
+            let value = cpu.regfile.read(rs1) as i64;
+            float(format.from_int(value, rounding_mode(cpu, rm)?))
+        }
+        FInstruction::fcvtswu | FInstruction::fcvtdwu => {
+            let value = cpu.regfile.read(rs1) as u32 as i64;
+            float(format.from_int(value, rounding_mode(cpu, rm)?))
+        }
+        // Moves copy the raw bits, without checking the NaN-boxing
+        FInstruction::fmvxw => (FResult::Integer(cpu.fregfile.read(rs1) as i32), 0),
+        FInstruction::fmvwx => (FResult::Single(cpu.regfile.read(rs1) as u32), 0),
+
+        FInstruction::feqs | FInstruction::feqd => integer(format.eq(a, b)),
+        FInstruction::flts | FInstruction::fltd => integer(format.lt(a, b)),
+        FInstruction::fles | FInstruction::fled => integer(format.le(a, b)),
+        FInstruction::fclasss | FInstruction::fclassd => {
+            (FResult::Integer(format.classify(a) as i32), 0)
+        }
+    };
+
+    cpu.csrfile.accrue_fp_flags(flags);
+    match result {
+        FResult::Single(value) => {
+            cpu.fregfile.write_single(rd, value);
+            cpu.csrfile.set_fp_dirty();
+        }
+        FResult::Double(value) => {
+            cpu.fregfile.write(rd, value);
+            cpu.csrfile.set_fp_dirty();
+        }
+        FResult::Integer(value) => cpu.regfile.write(rd, value),
+    }
+    Ok(())
+}
+
 pub fn exec(cpu: &mut Cpu, instruction: Instruction) -> Result<(), RVException> {
     match instruction {
         Instruction::IType { rd, rs1, imm, inst } => exec_i(cpu, rs1, rd, imm, inst),
@@ -374,5 +646,13 @@ pub fn exec(cpu: &mut Cpu, instruction: Instruction) -> Result<(), RVException>
             inst,
         } => exec_s_b(cpu, imm, rs1, rs2, inst),
         Instruction::UJType { imm, rd, inst } => exec_u_j(cpu, imm, rd, inst),
+        Instruction::FType {
+            rd,
+            rs1,
+            rs2,
+            rs3,
+            rm,
+            inst,
+        } => exec_f(cpu, rd, rs1, rs2, rs3, rm, inst),
     }
 }
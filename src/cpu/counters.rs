@@ -0,0 +1,186 @@
+use super::ExecMode;
+
+// Counters 0 to 2 are cycle, time and instret, followed by hpmcounter3 to 31
+pub const CYCLE: usize = 0;
+pub const TIME: usize = 1;
+pub const INSTRET: usize = 2;
+pub const HPM_FIRST: usize = 3;
+// Only the first few hpmcounters count events, the others are hardwired to 0
+const HPM_COUNTERS: usize = 4;
+
+// Events that can be selected in mhpmevent3 to mhpmevent6
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Event {
+    Load = 1,
+    Store = 2,
+    BranchTaken = 3,
+    Trap = 4,
+}
+const EVENT_LAST: u32 = Event::Trap as u32;
+
+pub struct Counters {
+    cycle: u64,
+    instret: u64,
+    // Shadow of the CLINT's mtime
+    time: u64,
+    hpm: [u64; HPM_COUNTERS],
+    events: [u32; HPM_COUNTERS],
+    // One bit per counter in mcounteren, scounteren and mcountinhibit
+    pub mcounteren: u32,
+    pub scounteren: u32,
+    inhibit: u32,
+}
+
+impl Counters {
+    pub fn new() -> Self {
+        Self {
+            cycle: 0,
+            instret: 0,
+            time: 0,
+            hpm: [0; HPM_COUNTERS],
+            events: [0; HPM_COUNTERS],
+            mcounteren: 0,
+            scounteren: 0,
+            inhibit: 0,
+        }
+    }
+
+    fn is_counting(&self, counter: usize) -> bool {
+        self.inhibit & (1 << counter) == 0
+    }
+
+    pub fn read(&self, counter: usize) -> u64 {
+        match counter {
+            CYCLE => self.cycle,
+            TIME => self.time,
+            INSTRET => self.instret,
+            _ => self.hpm.get(counter - HPM_FIRST).copied().unwrap_or(0),
+        }
+    }
+
+    // The 64-bit counters are written one half at a time
+    pub fn write(&mut self, counter: usize, value: u32, high: bool) {
+        let (mask, value) = match high {
+            true => (0xffff_ffff_0000_0000, (value as u64) << 32),
+            false => (0x0000_0000_ffff_ffff, value as u64),
+        };
+        let counter = match counter {
+            CYCLE => &mut self.cycle,
+            INSTRET => &mut self.instret,
+            TIME => return,
+            _ => match self.hpm.get_mut(counter - HPM_FIRST) {
+                Some(counter) => counter,
+                None => return,
+            },
+        };
+        *counter = *counter & !mask | value;
+    }
+
+    pub fn read_event(&self, counter: usize) -> u32 {
+        self.events.get(counter - HPM_FIRST).copied().unwrap_or(0)
+    }
+
+    // Unknown events select nothing
+    pub fn write_event(&mut self, counter: usize, event: u32) {
+        if let Some(selected) = self.events.get_mut(counter - HPM_FIRST) {
+            *selected = if event <= EVENT_LAST { event } else { 0 };
+        }
+    }
+
+    pub fn read_inhibit(&self) -> u32 {
+        self.inhibit
+    }
+
+    // time can't be inhibited
+    pub fn write_inhibit(&mut self, value: u32) {
+        self.inhibit = value & !(1 << TIME);
+    }
+
+    pub fn set_time(&mut self, time: u64) {
+        self.time = time;
+    }
+
+    // Every instruction takes one cycle
+    pub fn retire(&mut self, instructions: u32) {
+        if self.is_counting(CYCLE) {
+            self.cycle = self.cycle.wrapping_add(instructions as u64);
+        }
+        if self.is_counting(INSTRET) {
+            self.instret = self.instret.wrapping_add(instructions as u64);
+        }
+    }
+
+    pub fn count(&mut self, event: Event) {
+        for counter in 0..HPM_COUNTERS {
+            if self.events[counter] == event as u32 && self.is_counting(HPM_FIRST + counter) {
+                self.hpm[counter] = self.hpm[counter].wrapping_add(1);
+            }
+        }
+    }
+
+    // Lower privilege modes can only read the counters that mcounteren, and
+    // for U-mode also scounteren, make available
+    pub fn is_accessible(&self, counter: usize, mode: &ExecMode) -> bool {
+        let enabled = match mode {
+            ExecMode::MACHINE => u32::MAX,
+            ExecMode::SUPERVISOR => self.mcounteren,
+            ExecMode::USER => self.mcounteren & self.scounteren,
+        };
+        enabled & (1 << counter) != 0
+    }
+}
+
+impl Default for Counters {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_halves() {
+        let mut counters = Counters::new();
+        counters.write(CYCLE, 0xffff_ffff, false);
+        counters.retire(1);
+        assert_eq!(counters.read(CYCLE), 0x1_0000_0000);
+        assert_eq!(counters.read(INSTRET), 1);
+        counters.write(INSTRET, 0x1234, true);
+        assert_eq!(counters.read(INSTRET), 0x1234_0000_0001);
+        // Counters without an event source stay at zero
+        counters.write(31, 1, false);
+        assert_eq!(counters.read(31), 0);
+    }
+    #[test]
+    fn test_events() {
+        let mut counters = Counters::new();
+        counters.write_event(3, Event::Load as u32);
+        counters.write_event(4, Event::Trap as u32);
+        counters.write_event(5, 0x100);
+        assert_eq!(counters.read_event(5), 0);
+        counters.count(Event::Load);
+        counters.count(Event::Load);
+        counters.count(Event::Trap);
+        assert_eq!((counters.read(3), counters.read(4)), (2, 1));
+
+        // mcountinhibit stops everything but time
+        counters.write_inhibit(u32::MAX);
+        assert_eq!(counters.read_inhibit(), !(1 << TIME));
+        counters.count(Event::Load);
+        counters.retire(1);
+        assert_eq!((counters.read(3), counters.read(CYCLE)), (2, 0));
+    }
+    #[test]
+    fn test_access() {
+        let mut counters = Counters::new();
+        counters.mcounteren = 1 << TIME | 1 << INSTRET;
+        counters.scounteren = 1 << TIME | 1 << CYCLE;
+        assert!(counters.is_accessible(CYCLE, &ExecMode::MACHINE));
+        assert!(counters.is_accessible(INSTRET, &ExecMode::SUPERVISOR));
+        assert!(!counters.is_accessible(INSTRET, &ExecMode::USER));
+        assert!(!counters.is_accessible(CYCLE, &ExecMode::USER));
+        assert!(counters.is_accessible(TIME, &ExecMode::USER));
+    }
+}
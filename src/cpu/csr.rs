@@ -1,39 +1,102 @@
+use super::counters::{Counters, HPM_FIRST, TIME};
+use super::pmp::Pmp;
+use super::ExecMode;
 use crate::{cpu::MMIORegister, trap::RVException};
 use enum_primitive_derive::Primitive;
 use num_traits::FromPrimitive;
 use std::collections::HashMap;
-use tracing::warn;
 
 #[derive(Debug, Clone, Eq, Hash, PartialEq, Primitive)]
 #[allow(non_camel_case_types)]
 pub enum ArchCSRs {
+    fflags = 0x001,
+    frm = 0x002,
+    fcsr = 0x003,
+
     mvendorid = 0xf11,
     marchid = 0xf12,
     mimpid = 0xf13,
     mhartid = 0xf14,
 
-    rdcycle = 0xc00,
+    cycle = 0xc00,
+    time = 0xc01,
+    instret = 0xc02,
+    cycleh = 0xc80,
+    timeh = 0xc81,
+    instreth = 0xc82,
+
+    sstatus = 0x100,
+    sie = 0x104,
+    stvec = 0x105,
+    scounteren = 0x106,
+
+    sscratch = 0x140,
+    sepc = 0x141,
+    scause = 0x142,
+    stval = 0x143,
+    sip = 0x144,
+
+    satp = 0x180,
 
     mstatus = 0x300,
     misa = 0x301,
+    medeleg = 0x302,
+    mideleg = 0x303,
     mie = 0x304,
     mtvec = 0x305,
+    mcounteren = 0x306,
+    mcountinhibit = 0x320,
 
     mscratch = 0x340,
     mepc = 0x341,
     mcause = 0x342,
     mtval = 0x343,
     mip = 0x344,
+
+    pmpcfg0 = 0x3a0,
+    pmpcfg1 = 0x3a1,
+    pmpcfg2 = 0x3a2,
+    pmpcfg3 = 0x3a3,
+
+    pmpaddr0 = 0x3b0,
+    pmpaddr1 = 0x3b1,
+    pmpaddr2 = 0x3b2,
+    pmpaddr3 = 0x3b3,
+    pmpaddr4 = 0x3b4,
+    pmpaddr5 = 0x3b5,
+    pmpaddr6 = 0x3b6,
+    pmpaddr7 = 0x3b7,
+    pmpaddr8 = 0x3b8,
+    pmpaddr9 = 0x3b9,
+    pmpaddr10 = 0x3ba,
+    pmpaddr11 = 0x3bb,
+    pmpaddr12 = 0x3bc,
+    pmpaddr13 = 0x3bd,
+    pmpaddr14 = 0x3be,
+    pmpaddr15 = 0x3bf,
+
+    mcycle = 0xb00,
+    minstret = 0xb02,
+    mcycleh = 0xb80,
+    minstreth = 0xb82,
 }
 
-const ARCH_CSRS_ITERABLE: [ArchCSRs; 14] = [
+const ARCH_CSRS_ITERABLE: [ArchCSRs; 22] = [
+    ArchCSRs::fcsr,
     ArchCSRs::mvendorid,
     ArchCSRs::marchid,
     ArchCSRs::mimpid,
     ArchCSRs::mhartid,
-    ArchCSRs::rdcycle,
+    ArchCSRs::stvec,
+    ArchCSRs::sscratch,
+    ArchCSRs::sepc,
+    ArchCSRs::scause,
+    ArchCSRs::stval,
+    ArchCSRs::satp,
     ArchCSRs::mstatus,
     ArchCSRs::misa,
+    ArchCSRs::medeleg,
+    ArchCSRs::mideleg,
     ArchCSRs::mie,
     ArchCSRs::mtvec,
     ArchCSRs::mscratch,
@@ -43,60 +106,397 @@ const ARCH_CSRS_ITERABLE: [ArchCSRs; 14] = [
     ArchCSRs::mip,
 ];
 
+const PMPCFG_FIRST: i32 = ArchCSRs::pmpcfg0 as i32;
+const PMPCFG_LAST: i32 = ArchCSRs::pmpcfg3 as i32;
+const PMPADDR_FIRST: i32 = ArchCSRs::pmpaddr0 as i32;
+const PMPADDR_LAST: i32 = ArchCSRs::pmpaddr15 as i32;
+
+// cycle, time, instret and hpmcounter3 to 31, with their upper halves and
+// machine-mode counterparts
+const COUNTER_FIRST: i32 = ArchCSRs::cycle as i32;
+const COUNTER_LAST: i32 = 0xc1f;
+const COUNTERH_FIRST: i32 = ArchCSRs::cycleh as i32;
+const COUNTERH_LAST: i32 = 0xc9f;
+const MCOUNTER_FIRST: i32 = ArchCSRs::mcycle as i32;
+const MCOUNTER_LAST: i32 = 0xb1f;
+const MCOUNTERH_FIRST: i32 = ArchCSRs::mcycleh as i32;
+const MCOUNTERH_LAST: i32 = 0xb9f;
+// mhpmevent3 to 31
+const MHPMEVENT_FIRST: i32 = 0x323;
+const MHPMEVENT_LAST: i32 = 0x33f;
+const MCOUNTEREN: i32 = ArchCSRs::mcounteren as i32;
+const SCOUNTEREN: i32 = ArchCSRs::scounteren as i32;
+const MCOUNTINHIBIT: i32 = ArchCSRs::mcountinhibit as i32;
+
+// Fields of mstatus that are visible through sstatus
+const SSTATUS_MASK: u32 = 0x800d_e122;
+// Only supervisor-level interrupts (SSIP, STIP, SEIP) can be delegated
+const MIDELEG_MASK: u32 = 0x0000_0222;
+// SSIP is the only bit of sip that supervisor software can write
+const SIP_WRITABLE: u32 = 0x0000_0002;
+// MSIP, MTIP, MEIP and SEIP are driven by the CLINT and the PLIC
+const MIP_WRITABLE: u32 = 0x0000_0022;
+// SIE, MIE, SPIE, MPIE, SPP, MPP, FS, MPRV, SUM, MXR, TVM, TW and TSR. XS
+// has no extension behind it, so it stays 0.
+const MSTATUS_WRITABLE: u32 = 0x007e_79aa;
+const MSTATUS_MPP: u32 = 0b11 << 11;
+const MSTATUS_TVM: u32 = 1 << 20;
+// Environment calls from M-mode can't be delegated, 10 and 14 are reserved
+const MEDELEG_MASK: u32 = 0x0000_b3ff;
+// Enable bits of the six implemented interrupts
+const MIE_MASK: u32 = 0x0000_0aaa;
+// Only direct mode is implemented, so the MODE field of mtvec and stvec is 0
+const TVEC_MASK: u32 = !0b11;
+// Instructions are 2-byte aligned with the C extension, and 4-byte aligned
+// without. Bit 1 is kept, but hidden while C is disabled.
+const EPC_MASK: u32 = !0b1;
+
+// Floating-point state in mstatus, SD summarizes whether FS or XS are dirty
+const MSTATUS_FS: u32 = 0b11 << 13;
+const MSTATUS_XS: u32 = 0b11 << 15;
+const MSTATUS_SD: u32 = 1 << 31;
+// fcsr holds the accrued exception flags and the dynamic rounding mode
+const FCSR_FFLAGS: u32 = 0x1f;
+const FCSR_FRM: u32 = 0xe0;
+
+// Extension bits in misa
+pub const MISA_A: u32 = 1 << 0;
+pub const MISA_C: u32 = 1 << 2;
+pub const MISA_D: u32 = 1 << 3;
+pub const MISA_F: u32 = 1 << 5;
+pub const MISA_I: u32 = 1 << 8;
+pub const MISA_M: u32 = 1 << 12;
+// Extensions that a machine can be configured without
+pub const MISA_OPTIONAL: u32 = MISA_A | MISA_C | MISA_D | MISA_F | MISA_M;
+
+// Multi-letter extensions have no misa bit, so they take the unused bits
+// above Z in the same mask
+pub const EXT_ZBA: u32 = 1 << 26;
+pub const EXT_ZBB: u32 = 1 << 27;
+pub const EXT_ZBC: u32 = 1 << 28;
+pub const EXT_ZBS: u32 = 1 << 29;
+pub const Z_EXTENSIONS: [(&str, u32); 4] = [
+    ("zba", EXT_ZBA),
+    ("zbb", EXT_ZBB),
+    ("zbc", EXT_ZBC),
+    ("zbs", EXT_ZBS),
+];
+pub const EXT_OPTIONAL: u32 = MISA_OPTIONAL | EXT_ZBA | EXT_ZBB | EXT_ZBC | EXT_ZBS;
+// Always implemented, so ISA strings may name them but they can't be disabled
+pub const BASE_Z_EXTENSIONS: [&str; 4] = ["zicsr", "zifencei", "zicntr", "zihpm"];
+
+// ISA string for the device tree, e.g. "rv32imafdc_zba_zbb"
+pub fn isa_string(extensions: u32) -> String {
+    let letters = [
+        ('m', MISA_M),
+        ('a', MISA_A),
+        ('f', MISA_F),
+        ('d', MISA_D),
+        ('c', MISA_C),
+    ];
+    let mut isa = "rv32i".to_string();
+    isa.extend(
+        letters
+            .iter()
+            .filter(|(_, bit)| extensions & bit != 0)
+            .map(|(letter, _)| letter),
+    );
+    for (name, bit) in Z_EXTENSIONS.iter() {
+        if extensions & bit != 0 {
+            isa.push('_');
+            isa.push_str(name);
+        }
+    }
+    isa
+}
+
+// Interrupt bits in mip/mie
+const MIP_SSIP: u32 = 1 << 1;
+const MIP_MSIP: u32 = 1 << 3;
+const MIP_STIP: u32 = 1 << 5;
+const MIP_MTIP: u32 = 1 << 7;
+const MIP_SEIP: u32 = 1 << 9;
+const MIP_MEIP: u32 = 1 << 11;
+
+// Interrupts in the order in which they are taken when several are pending
+const INTERRUPT_PRIORITY: [(u32, RVException); 6] = [
+    (MIP_MEIP, RVException::MachineExternalInterrupt),
+    (MIP_MSIP, RVException::MachineSoftwareInterrupt),
+    (MIP_MTIP, RVException::MachineTimerInterrupt),
+    (MIP_SEIP, RVException::SupervisorExternalInterrupt),
+    (MIP_SSIP, RVException::SupervisorSoftwareInterrupt),
+    (MIP_STIP, RVException::SupervisorTimerInterrupt),
+];
+
 pub struct CSRFile {
     csrs: HashMap<ArchCSRs, MMIORegister>,
+    pub pmp: Pmp,
+    pub counters: Counters,
 }
 
 impl CSRFile {
     pub fn new() -> Self {
         let mut map: HashMap<ArchCSRs, MMIORegister> = HashMap::new();
         for e in ARCH_CSRS_ITERABLE.iter() {
-            let writable = match e {
-                ArchCSRs::mvendorid => false,
-                ArchCSRs::marchid => false,
-                ArchCSRs::mimpid => false,
-                ArchCSRs::mhartid => false,
-                ArchCSRs::rdcycle => false,
-                _ => true,
-            };
+            // misa only changes through set_extensions
+            let writable = !matches!(
+                e,
+                ArchCSRs::mvendorid
+                    | ArchCSRs::marchid
+                    | ArchCSRs::mimpid
+                    | ArchCSRs::mhartid
+                    | ArchCSRs::misa
+            );
             let initial_value = match e {
                 ArchCSRs::mvendorid => 0xff0f_f0ff,
-                ArchCSRs::misa => 0x4040_1101, // (XLEN=32, IMA+X)
+                ArchCSRs::misa => 0x4014_112d, // (XLEN=32, IMAFDCSU)
                 _ => 0x0000_0000,
             };
             map.insert(
                 e.clone(),
                 MMIORegister {
                     value: initial_value,
-                    writable: writable,
+                    writable,
                 },
             );
         }
-        Self { csrs: map }
+        Self {
+            csrs: map,
+            pmp: Pmp::new(),
+            counters: Counters::new(),
+        }
+    }
+
+    // Report only the given optional extensions in misa
+    pub fn set_extensions(&mut self, extensions: u32) {
+        let misa = self.csrs.get_mut(&ArchCSRs::misa).unwrap();
+        misa.value = misa.value & !MISA_OPTIONAL | extensions & MISA_OPTIONAL;
+    }
+
+    // Accesses to CSRs that don't exist or that the current mode can't use
+    // raise an illegal instruction exception. Bits 9:8 of the address hold
+    // the lowest privilege level and read-only CSRs have 0b11 in bits 11:10.
+    pub fn check_access(&self, addr: i32, mode: &ExecMode, write: bool) -> Result<(), RVException> {
+        let privileged = match ExecMode::from_i32(addr >> 8 & 0b11) {
+            Some(privilege) => *mode >= privilege,
+            None => false,
+        };
+        let read_only = addr >> 10 & 0b11 == 0b11;
+        // mstatus.TVM traps S-mode accesses to satp
+        let trapped_satp = addr == ArchCSRs::satp as i32
+            && *mode == ExecMode::SUPERVISOR
+            && self.get(ArchCSRs::mstatus) & MSTATUS_TVM != 0;
+        if !self.exists(addr)
+            || !privileged
+            || (write && read_only)
+            || self.is_counter_disabled(addr, mode)
+            || trapped_satp
+        {
+            return Err(RVException::IllegalInstruction(0));
+        }
+        Ok(())
+    }
+
+    fn exists(&self, addr: i32) -> bool {
+        match addr {
+            PMPCFG_FIRST..=PMPCFG_LAST
+            | PMPADDR_FIRST..=PMPADDR_LAST
+            | COUNTER_FIRST..=COUNTER_LAST
+            | COUNTERH_FIRST..=COUNTERH_LAST
+            | MHPMEVENT_FIRST..=MHPMEVENT_LAST => true,
+            // There is no mtime CSR
+            MCOUNTER_FIRST..=MCOUNTER_LAST | MCOUNTERH_FIRST..=MCOUNTERH_LAST => {
+                (addr & 0x1f) as usize != TIME
+            }
+            _ => ArchCSRs::from_i32(addr).is_some(),
+        }
+    }
+
+    // FS is read-only 0 without the F extension
+    fn mstatus_writable(&self) -> u32 {
+        match self.get(ArchCSRs::misa) & MISA_F {
+            0 => MSTATUS_WRITABLE & !MSTATUS_FS,
+            _ => MSTATUS_WRITABLE,
+        }
     }
 
     pub fn write(&mut self, addr: i32, value: i32) {
+        // PMP registers live in their own register file
+        match addr {
+            PMPCFG_FIRST..=PMPCFG_LAST => {
+                return self
+                    .pmp
+                    .write_cfg((addr - PMPCFG_FIRST) as usize, value as u32)
+            }
+            PMPADDR_FIRST..=PMPADDR_LAST => {
+                return self
+                    .pmp
+                    .write_addr((addr - PMPADDR_FIRST) as usize, value as u32)
+            }
+            // So do the counters, which are read-only below M-mode
+            COUNTER_FIRST..=COUNTER_LAST | COUNTERH_FIRST..=COUNTERH_LAST => return,
+            MCOUNTER_FIRST..=MCOUNTER_LAST => {
+                return self
+                    .counters
+                    .write((addr - MCOUNTER_FIRST) as usize, value as u32, false)
+            }
+            MCOUNTERH_FIRST..=MCOUNTERH_LAST => {
+                return self
+                    .counters
+                    .write((addr - MCOUNTERH_FIRST) as usize, value as u32, true)
+            }
+            MHPMEVENT_FIRST..=MHPMEVENT_LAST => {
+                return self
+                    .counters
+                    .write_event((addr - MHPMEVENT_FIRST) as usize + HPM_FIRST, value as u32)
+            }
+            MCOUNTEREN => {
+                self.counters.mcounteren = value as u32;
+                return;
+            }
+            SCOUNTEREN => {
+                self.counters.scounteren = value as u32;
+                return;
+            }
+            MCOUNTINHIBIT => return self.counters.write_inhibit(value as u32),
+            _ => {}
+        }
         if let Some(register) = ArchCSRs::from_i32(addr) {
+            let value = match register {
+                ArchCSRs::frm => (value as u32) << 5,
+                _ => value as u32,
+            };
+            // Supervisor views write through to the underlying machine register
+            let (register, mask) = match register {
+                ArchCSRs::fflags => (ArchCSRs::fcsr, FCSR_FFLAGS),
+                ArchCSRs::frm => (ArchCSRs::fcsr, FCSR_FRM),
+                ArchCSRs::fcsr => (ArchCSRs::fcsr, FCSR_FFLAGS | FCSR_FRM),
+                ArchCSRs::mstatus => (ArchCSRs::mstatus, self.mstatus_writable()),
+                ArchCSRs::sstatus => (ArchCSRs::mstatus, SSTATUS_MASK & self.mstatus_writable()),
+                ArchCSRs::sie => (ArchCSRs::mie, self.get(ArchCSRs::mideleg)),
+                ArchCSRs::mie => (ArchCSRs::mie, MIE_MASK),
+                ArchCSRs::medeleg => (ArchCSRs::medeleg, MEDELEG_MASK),
+                ArchCSRs::mtvec | ArchCSRs::stvec => (register, TVEC_MASK),
+                ArchCSRs::mepc | ArchCSRs::sepc => (register, EPC_MASK),
+                ArchCSRs::sip => (ArchCSRs::mip, self.get(ArchCSRs::mideleg) & SIP_WRITABLE),
+                ArchCSRs::mip => (ArchCSRs::mip, MIP_WRITABLE),
+                ArchCSRs::mideleg => (ArchCSRs::mideleg, MIDELEG_MASK),
+                _ => (register, u32::MAX),
+            };
             let csr = self.csrs.get_mut(&register).unwrap();
             if csr.writable {
-                csr.value = value as u32;
+                csr.value = (csr.value & !mask) | (value & mask);
+            }
+            match register {
+                ArchCSRs::fcsr => self.set_fp_dirty(),
+                ArchCSRs::mstatus => {
+                    // MPP can't hold the reserved mode 0b10
+                    if self.get_mpp() == 0b10 {
+                        self.set_mpp(&(ExecMode::USER as u32));
+                    }
+                    self.update_sd()
+                }
+                _ => {}
             }
         }
     }
 
     pub fn read(&self, addr: i32) -> i32 {
+        match addr {
+            PMPCFG_FIRST..=PMPCFG_LAST => {
+                return self.pmp.read_cfg((addr - PMPCFG_FIRST) as usize) as i32
+            }
+            PMPADDR_FIRST..=PMPADDR_LAST => {
+                return self.pmp.read_addr((addr - PMPADDR_FIRST) as usize) as i32
+            }
+            COUNTER_FIRST..=COUNTER_LAST | MCOUNTER_FIRST..=MCOUNTER_LAST => {
+                return self.read_counter(addr) as i32
+            }
+            COUNTERH_FIRST..=COUNTERH_LAST | MCOUNTERH_FIRST..=MCOUNTERH_LAST => {
+                return (self.read_counter(addr) >> 32) as i32
+            }
+            MHPMEVENT_FIRST..=MHPMEVENT_LAST => {
+                return self
+                    .counters
+                    .read_event((addr - MHPMEVENT_FIRST) as usize + HPM_FIRST)
+                    as i32
+            }
+            MCOUNTEREN => return self.counters.mcounteren as i32,
+            SCOUNTEREN => return self.counters.scounteren as i32,
+            MCOUNTINHIBIT => return self.counters.read_inhibit() as i32,
+            _ => {}
+        }
         if let Some(register) = ArchCSRs::from_i32(addr) {
-            return self.csrs.get(&register).unwrap().value as i32;
+            let value = match register {
+                ArchCSRs::fflags => self.get(ArchCSRs::fcsr) & FCSR_FFLAGS,
+                ArchCSRs::frm => (self.get(ArchCSRs::fcsr) & FCSR_FRM) >> 5,
+                ArchCSRs::sstatus => self.get(ArchCSRs::mstatus) & SSTATUS_MASK,
+                ArchCSRs::sie => self.get(ArchCSRs::mie) & self.get(ArchCSRs::mideleg),
+                ArchCSRs::sip => self.get(ArchCSRs::mip) & self.get(ArchCSRs::mideleg),
+                ArchCSRs::mepc | ArchCSRs::sepc if self.get(ArchCSRs::misa) & MISA_C == 0 => {
+                    self.get(register) & !0b11
+                }
+                _ => self.get(register),
+            };
+            return value as i32;
         }
         0
     }
 
-    pub fn count_cycle(&mut self) {
-        if let Some(rdcycle) = self.csrs.get_mut(&ArchCSRs::rdcycle) {
-            rdcycle.value = rdcycle.value.wrapping_add(1);
+    // The low five bits select the counter. 0xb01 and 0xb81 don't exist and
+    // read as 0.
+    fn read_counter(&self, addr: i32) -> u64 {
+        let counter = (addr & 0x1f) as usize;
+        match addr & 0xf00 == MCOUNTER_FIRST && counter == TIME {
+            true => 0,
+            false => self.counters.read(counter),
+        }
+    }
+
+    // Whether a CSR is a counter that isn't available in the given mode
+    fn is_counter_disabled(&self, addr: i32, mode: &ExecMode) -> bool {
+        match addr {
+            COUNTER_FIRST..=COUNTER_LAST | COUNTERH_FIRST..=COUNTERH_LAST => {
+                !self.counters.is_accessible((addr & 0x1f) as usize, mode)
+            }
+            _ => false,
         }
     }
 
+    fn get(&self, register: ArchCSRs) -> u32 {
+        self.csrs.get(&register).unwrap().value
+    }
+
+    // Floating-point instructions and CSRs are illegal while FS is Off
+    pub fn fp_enabled(&self) -> bool {
+        self.get(ArchCSRs::mstatus) & MSTATUS_FS != 0
+    }
+
+    // Called for every change to the floating-point registers or fcsr
+    pub fn set_fp_dirty(&mut self) {
+        let mstatus = self.csrs.get_mut(&ArchCSRs::mstatus).unwrap();
+        mstatus.value |= MSTATUS_FS | MSTATUS_SD;
+    }
+
+    fn update_sd(&mut self) {
+        let mstatus = self.csrs.get_mut(&ArchCSRs::mstatus).unwrap();
+        let dirty =
+            mstatus.value & MSTATUS_FS == MSTATUS_FS || mstatus.value & MSTATUS_XS == MSTATUS_XS;
+        mstatus.value = mstatus.value & !MSTATUS_SD | if dirty { MSTATUS_SD } else { 0 };
+    }
+
+    pub fn accrue_fp_flags(&mut self, flags: u32) {
+        if flags != 0 {
+            self.csrs.get_mut(&ArchCSRs::fcsr).unwrap().value |= flags & FCSR_FFLAGS;
+            self.set_fp_dirty();
+        }
+    }
+
+    // Dynamic rounding mode in frm
+    pub fn rounding_mode(&self) -> u32 {
+        (self.get(ArchCSRs::fcsr) & FCSR_FRM) >> 5
+    }
+
     pub fn disable_irq(&mut self) {
         const MSTATUS_MIE: u32 = 1 << 3;
         const MSTATUS_MPIE: u32 = 1 << 7;
@@ -113,18 +513,76 @@ impl CSRFile {
     }
 
     pub fn get_mpp(&self) -> u32 {
-        const MSTATUS_MPP: u32 = 0b11 << 11; // MPP bits in mstatus CSR
         let mstatus = self.csrs.get(&ArchCSRs::mstatus).unwrap();
         (mstatus.value & MSTATUS_MPP) >> 11
     }
 
     pub fn set_mpp(&mut self, mpp: &u32) {
-        const MSTATUS_MPP: u32 = 0b11 << 11; // MPP bits in mstatus CSR
         let mstatus = self.csrs.get_mut(&ArchCSRs::mstatus).unwrap();
         // Clear MPP bits and set new value
         mstatus.value = (mstatus.value & !MSTATUS_MPP) | ((mpp & 0b11) << 11);
     }
 
+    pub fn disable_sirq(&mut self) {
+        const MSTATUS_SIE: u32 = 1 << 1;
+        const MSTATUS_SPIE: u32 = 1 << 5;
+
+        let mstatus = self.csrs.get_mut(&ArchCSRs::mstatus).unwrap();
+        // Save SIE bit to SPIE
+        if (mstatus.value & MSTATUS_SIE) != 0 {
+            mstatus.value |= MSTATUS_SPIE;
+        } else {
+            mstatus.value &= !MSTATUS_SPIE;
+        }
+        // Clear SIE to disable interrupts
+        mstatus.value &= !MSTATUS_SIE;
+    }
+
+    pub fn get_spp(&self) -> u32 {
+        const MSTATUS_SPP: u32 = 1 << 8; // SPP bit in mstatus CSR
+        let mstatus = self.csrs.get(&ArchCSRs::mstatus).unwrap();
+        (mstatus.value & MSTATUS_SPP) >> 8
+    }
+
+    pub fn set_spp(&mut self, spp: &u32) {
+        const MSTATUS_SPP: u32 = 1 << 8; // SPP bit in mstatus CSR
+        let mstatus = self.csrs.get_mut(&ArchCSRs::mstatus).unwrap();
+        // SPP only holds a single bit since it can only be U or S
+        mstatus.value = (mstatus.value & !MSTATUS_SPP) | ((spp & 0b1) << 8);
+    }
+
+    pub fn enable_sirq(&mut self) {
+        const MSTATUS_SIE: u32 = 1 << 1; // SIE bit in mstatus CSR
+        const MSTATUS_SPIE: u32 = 1 << 5; // SPIE bit in mstatus CSR
+
+        let mstatus = self.csrs.get_mut(&ArchCSRs::mstatus).unwrap();
+        // Restore previous SIE state from SPIE
+        if (mstatus.value & MSTATUS_SPIE) != 0 {
+            mstatus.value |= MSTATUS_SIE;
+        } else {
+            mstatus.value &= !MSTATUS_SIE;
+        }
+        // Set SPIE bit
+        mstatus.value |= MSTATUS_SPIE;
+    }
+
+    pub fn clear_mprv(&mut self) {
+        const MSTATUS_MPRV: u32 = 1 << 17; // MPRV bit in mstatus CSR
+        let mstatus = self.csrs.get_mut(&ArchCSRs::mstatus).unwrap();
+        mstatus.value &= !MSTATUS_MPRV;
+    }
+
+    // Check whether a trap with the given cause should be handled in S-mode
+    pub fn is_delegated(&self, cause: u32) -> bool {
+        const INTERRUPT_BIT: u32 = 1 << 31;
+        let deleg = if cause & INTERRUPT_BIT != 0 {
+            self.get(ArchCSRs::mideleg)
+        } else {
+            self.get(ArchCSRs::medeleg)
+        };
+        (deleg >> (cause & !INTERRUPT_BIT)) & 1 != 0
+    }
+
     pub fn enable_irq(&mut self) {
         const MSTATUS_MIE: u32 = 1 << 3; // MIE bit in mstatus CSR
         const MSTATUS_MPIE: u32 = 1 << 7; // MPIE bit in mstatus CSR
@@ -140,34 +598,284 @@ impl CSRFile {
         mstatus.value |= MSTATUS_MPIE;
     }
 
-    pub fn mtimer_interrupt(&self) -> Result<(), RVException> {
-        const MIE_MTIE: u32 = 1 << 7;
-        const MSTATUS_MIE: u32 = 1 << 3;
-        const MIP_MTIP: u32 = 1 << 7;
+    pub fn set_mtip(&mut self, value: bool) {
+        self.set_mip(MIP_MTIP, value);
+    }
 
-        let mie = self.csrs.get(&ArchCSRs::mie).unwrap();
-        let mstatus = self.csrs.get(&ArchCSRs::mstatus).unwrap();
-        let mip = self.csrs.get(&ArchCSRs::mip).unwrap();
-
-        // Check if a timer interrupt is pending
-        // timer interrupts are enabled
-        // and global interrupts are enabled
-        if ((mip.value & MIP_MTIP) != 0)
-            && ((mie.value & MIE_MTIE) != 0)
-            && ((mstatus.value & MSTATUS_MIE) != 0)
-        {
-            return Err(RVException::TimerInterrupt);
-        }
-        Ok(())
+    pub fn set_msip(&mut self, value: bool) {
+        self.set_mip(MIP_MSIP, value);
     }
 
-    pub fn set_mtip(&mut self, value: bool) {
-        const MIP_MTIP: u32 = 1 << 7; // MTIP bit in mip CSR
+    pub fn set_meip(&mut self, value: bool) {
+        self.set_mip(MIP_MEIP, value);
+    }
+
+    pub fn set_seip(&mut self, value: bool) {
+        self.set_mip(MIP_SEIP, value);
+    }
+
+    fn set_mip(&mut self, bit: u32, value: bool) {
         let csr = self.csrs.get_mut(&ArchCSRs::mip).unwrap();
         if value {
-            csr.value |= MIP_MTIP; // Set the MTIP bit
+            csr.value |= bit;
         } else {
-            csr.value &= !MIP_MTIP; // Clear the MTIP bit
+            csr.value &= !bit;
         }
     }
+
+    // Check if a pending and enabled interrupt can be taken in the current mode
+    fn interrupt_enabled(&self, bit: u32, mode: &ExecMode) -> bool {
+        const MSTATUS_SIE: u32 = 1 << 1;
+        const MSTATUS_MIE: u32 = 1 << 3;
+
+        if self.get(ArchCSRs::mip) & self.get(ArchCSRs::mie) & bit == 0 {
+            return false;
+        }
+        let mstatus = self.get(ArchCSRs::mstatus);
+        if self.get(ArchCSRs::mideleg) & bit != 0 {
+            // Delegated interrupts never preempt M-mode
+            match mode {
+                ExecMode::USER => true,
+                ExecMode::SUPERVISOR => mstatus & MSTATUS_SIE != 0,
+                ExecMode::MACHINE => false,
+            }
+        } else {
+            *mode != ExecMode::MACHINE || mstatus & MSTATUS_MIE != 0
+        }
+    }
+
+    // WFI ends once any interrupt is pending and enabled in mie, even if it
+    // can't be taken in the current mode
+    pub fn wakeup_pending(&self) -> bool {
+        self.get(ArchCSRs::mip) & self.get(ArchCSRs::mie) != 0
+    }
+
+    pub fn timer_enabled(&self) -> bool {
+        self.get(ArchCSRs::mie) & MIP_MTIP != 0
+    }
+
+    // Raise the highest priority interrupt that can be taken in the current mode
+    pub fn pending_interrupt(&self, mode: &ExecMode) -> Result<(), RVException> {
+        // Checked on every instruction, so skip the arbitration in the common case
+        if !self.wakeup_pending() {
+            return Ok(());
+        }
+        match INTERRUPT_PRIORITY
+            .iter()
+            .find(|(bit, _)| self.interrupt_enabled(*bit, mode))
+        {
+            Some((_, interrupt)) => Err(interrupt.clone()),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Default for CSRFile {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::counters::Event;
+
+    #[test]
+    fn test_sstatus_view() {
+        let mut dut = CSRFile::new();
+        dut.write(ArchCSRs::mstatus as i32, 0x0000_1888);
+        // MPP, MPIE and MIE are not visible from S-mode
+        assert_eq!(dut.read(ArchCSRs::sstatus as i32), 0);
+
+        // XS is read-only 0, SD follows FS
+        dut.write(ArchCSRs::sstatus as i32, -1);
+        assert_eq!(dut.read(ArchCSRs::sstatus as i32) as u32, 0x800c_6122);
+        assert_eq!(dut.read(ArchCSRs::mstatus as i32) as u32, 0x800c_79aa);
+    }
+    #[test]
+    fn test_fcsr() {
+        let mut dut = CSRFile::new();
+        assert!(!dut.fp_enabled());
+        // FS = Initial
+        dut.write(ArchCSRs::mstatus as i32, 1 << 13);
+        assert!(dut.fp_enabled());
+
+        dut.write(ArchCSRs::frm as i32, 0b011);
+        dut.accrue_fp_flags(0b00101);
+        assert_eq!(dut.read(ArchCSRs::fcsr as i32), 0x65);
+        assert_eq!(dut.read(ArchCSRs::fflags as i32), 0b00101);
+        assert_eq!(dut.rounding_mode(), 0b011);
+        dut.write(ArchCSRs::fcsr as i32, -1);
+        assert_eq!(dut.read(ArchCSRs::fcsr as i32), 0xff);
+        assert_eq!(dut.read(ArchCSRs::frm as i32), 0b111);
+
+        // Writing fcsr made the state dirty
+        assert_eq!(dut.read(ArchCSRs::mstatus as i32) as u32, 0x8000_6000);
+        dut.write(ArchCSRs::mstatus as i32, 0x8000_2000u32 as i32);
+        assert_eq!(dut.read(ArchCSRs::mstatus as i32), 0x2000);
+    }
+    #[test]
+    fn test_sie_view() {
+        let mut dut = CSRFile::new();
+        dut.write(ArchCSRs::mie as i32, 0x0000_0080);
+        dut.write(ArchCSRs::mideleg as i32, -1);
+        assert_eq!(dut.read(ArchCSRs::mideleg as i32), 0x222);
+
+        // Only delegated interrupts can be enabled through sie
+        dut.write(ArchCSRs::sie as i32, -1);
+        assert_eq!(dut.read(ArchCSRs::sie as i32), 0x222);
+        assert_eq!(dut.read(ArchCSRs::mie as i32), 0x2a2);
+    }
+    #[test]
+    fn test_interrupt_enable() {
+        let mut dut = CSRFile::new();
+        dut.write(ArchCSRs::mie as i32, (MIP_MEIP | MIP_SEIP) as i32);
+        dut.set_meip(true);
+        // M-mode only takes interrupts with mstatus.MIE set
+        assert_eq!(dut.pending_interrupt(&ExecMode::MACHINE), Ok(()));
+        assert_eq!(
+            dut.pending_interrupt(&ExecMode::USER),
+            Err(RVException::MachineExternalInterrupt)
+        );
+        dut.set_meip(false);
+
+        // A delegated SEI is masked in M-mode and by sstatus.SIE in S-mode
+        dut.write(ArchCSRs::mideleg as i32, MIP_SEIP as i32);
+        dut.write(ArchCSRs::mstatus as i32, 0x8);
+        dut.set_seip(true);
+        assert_eq!(dut.pending_interrupt(&ExecMode::MACHINE), Ok(()));
+        assert_eq!(dut.pending_interrupt(&ExecMode::SUPERVISOR), Ok(()));
+        assert_eq!(
+            dut.pending_interrupt(&ExecMode::USER),
+            Err(RVException::SupervisorExternalInterrupt)
+        );
+        dut.write(ArchCSRs::sstatus as i32, 0x2);
+        assert_eq!(
+            dut.pending_interrupt(&ExecMode::SUPERVISOR),
+            Err(RVException::SupervisorExternalInterrupt)
+        );
+    }
+    #[test]
+    fn test_interrupt_priority() {
+        let mut dut = CSRFile::new();
+        dut.write(ArchCSRs::mie as i32, 0xaaa);
+        dut.write(ArchCSRs::mstatus as i32, 0x8);
+        // Software can only set the supervisor software and timer bits in mip
+        dut.write(ArchCSRs::mip as i32, -1);
+        assert_eq!(dut.read(ArchCSRs::mip as i32) as u32, MIP_SSIP | MIP_STIP);
+        assert_eq!(
+            dut.pending_interrupt(&ExecMode::MACHINE),
+            Err(RVException::SupervisorSoftwareInterrupt)
+        );
+        dut.set_seip(true);
+        assert_eq!(
+            dut.pending_interrupt(&ExecMode::MACHINE),
+            Err(RVException::SupervisorExternalInterrupt)
+        );
+        dut.set_mtip(true);
+        assert_eq!(
+            dut.pending_interrupt(&ExecMode::MACHINE),
+            Err(RVException::MachineTimerInterrupt)
+        );
+        dut.set_msip(true);
+        assert_eq!(
+            dut.pending_interrupt(&ExecMode::MACHINE),
+            Err(RVException::MachineSoftwareInterrupt)
+        );
+        dut.set_meip(true);
+        assert_eq!(
+            dut.pending_interrupt(&ExecMode::MACHINE),
+            Err(RVException::MachineExternalInterrupt)
+        );
+    }
+    #[test]
+    fn test_counters() {
+        let mut dut = CSRFile::new();
+        dut.write(ArchCSRs::mcycleh as i32, 1);
+        dut.write(ArchCSRs::mcycle as i32, -1);
+        dut.counters.retire(1);
+        assert_eq!(dut.read(ArchCSRs::cycle as i32), 0);
+        assert_eq!(dut.read(ArchCSRs::cycleh as i32), 2);
+        // The unprivileged counters are read-only
+        dut.write(ArchCSRs::instret as i32, 5);
+        assert_eq!(dut.read(ArchCSRs::minstret as i32), 1);
+
+        dut.counters.set_time(7);
+        assert_eq!(dut.read(ArchCSRs::time as i32), 7);
+        assert_eq!(dut.read(0xb01), 0);
+        // mhpmevent3 selects loads for hpmcounter3
+        dut.write(0x323, 1);
+        dut.counters.count(Event::Load);
+        assert_eq!((dut.read(0xc03), dut.read(0xb03)), (1, 1));
+        dut.write(ArchCSRs::mcountinhibit as i32, -1);
+        assert_eq!(dut.read(ArchCSRs::mcountinhibit as i32) as u32, 0xffff_fffd);
+
+        let illegal = Err(RVException::IllegalInstruction(0));
+        let cycle = ArchCSRs::cycle as i32;
+        assert_eq!(dut.check_access(cycle, &ExecMode::USER, false), illegal);
+        dut.write(ArchCSRs::mcounteren as i32, 0b101);
+        dut.write(ArchCSRs::scounteren as i32, 0b001);
+        assert_eq!(
+            dut.check_access(ArchCSRs::cycleh as i32, &ExecMode::USER, false),
+            Ok(())
+        );
+        assert_eq!(dut.check_access(cycle, &ExecMode::USER, true), illegal);
+        let instret = ArchCSRs::instret as i32;
+        assert_eq!(dut.check_access(instret, &ExecMode::USER, false), illegal);
+        assert_eq!(
+            dut.check_access(instret, &ExecMode::SUPERVISOR, false),
+            Ok(())
+        );
+        assert_eq!(dut.check_access(0xb01, &ExecMode::MACHINE, false), illegal);
+    }
+    #[test]
+    fn test_check_access() {
+        let mut dut = CSRFile::new();
+        let illegal = Err(RVException::IllegalInstruction(0));
+        let mstatus = ArchCSRs::mstatus as i32;
+        assert_eq!(dut.check_access(mstatus, &ExecMode::MACHINE, true), Ok(()));
+        assert_eq!(
+            dut.check_access(mstatus, &ExecMode::SUPERVISOR, false),
+            illegal
+        );
+        assert_eq!(
+            dut.check_access(ArchCSRs::sepc as i32, &ExecMode::USER, false),
+            illegal
+        );
+        assert_eq!(dut.check_access(0x744, &ExecMode::MACHINE, false), illegal);
+        let mhartid = ArchCSRs::mhartid as i32;
+        assert_eq!(dut.check_access(mhartid, &ExecMode::MACHINE, false), Ok(()));
+        assert_eq!(dut.check_access(mhartid, &ExecMode::MACHINE, true), illegal);
+
+        let satp = ArchCSRs::satp as i32;
+        assert_eq!(dut.check_access(satp, &ExecMode::SUPERVISOR, true), Ok(()));
+        dut.write(mstatus, MSTATUS_TVM as i32);
+        assert_eq!(dut.check_access(satp, &ExecMode::SUPERVISOR, true), illegal);
+        assert_eq!(dut.check_access(satp, &ExecMode::MACHINE, true), Ok(()));
+    }
+    #[test]
+    fn test_warl() {
+        let mut dut = CSRFile::new();
+        // MPP = 0b10 is reserved and becomes U
+        dut.write(ArchCSRs::mstatus as i32, 0x1000);
+        assert_eq!(dut.get_mpp(), 0);
+        // Without F, FS stays Off
+        dut.set_extensions(MISA_M);
+        dut.write(ArchCSRs::mstatus as i32, -1);
+        assert_eq!(dut.read(ArchCSRs::mstatus as i32) as u32, 0x007e_19aa);
+        dut.write(ArchCSRs::misa as i32, 0);
+        assert_eq!(dut.read(ArchCSRs::misa as i32) as u32, 0x4014_1100);
+
+        dut.write(ArchCSRs::mtvec as i32, 0x8000_0101u32 as i32);
+        assert_eq!(dut.read(ArchCSRs::mtvec as i32) as u32, 0x8000_0100);
+        dut.write(ArchCSRs::sepc as i32, 0x8000_0003u32 as i32);
+        assert_eq!(dut.read(ArchCSRs::sepc as i32) as u32, 0x8000_0000);
+        dut.set_extensions(MISA_C);
+        assert_eq!(dut.read(ArchCSRs::sepc as i32) as u32, 0x8000_0002);
+        dut.write(ArchCSRs::medeleg as i32, -1);
+        assert_eq!(dut.read(ArchCSRs::medeleg as i32), 0xb3ff);
+        dut.write(ArchCSRs::mie as i32, -1);
+        assert_eq!(dut.read(ArchCSRs::mie as i32), 0xaaa);
+    }
 }
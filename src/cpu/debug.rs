@@ -0,0 +1,120 @@
+use std::collections::VecDeque;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WatchKind {
+    Write,
+    Read,
+    Access,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Watchpoint {
+    pub addr: usize,
+    pub len: usize,
+    pub kind: WatchKind,
+}
+
+// Reasons for handing control back to an attached debugger
+#[derive(Debug, Clone, PartialEq)]
+pub enum DebugEvent {
+    Breakpoint,
+    Watchpoint(Watchpoint),
+}
+
+pub struct DebugState {
+    // With a debugger attached, ebreak stops execution instead of trapping
+    pub attached: bool,
+    pub watchpoints: Vec<Watchpoint>,
+    hit: Option<Watchpoint>,
+    // Recently executed (pc, raw instruction) pairs, oldest first
+    pub history: VecDeque<(usize, u32)>,
+    pub history_size: usize,
+}
+
+impl DebugState {
+    pub fn new() -> Self {
+        Self {
+            attached: false,
+            watchpoints: Vec::new(),
+            hit: None,
+            history: VecDeque::new(),
+            history_size: 0,
+        }
+    }
+
+    pub fn record(&mut self, pc: usize, instruction: u32) {
+        if self.history_size == 0 {
+            return;
+        }
+        if self.history.len() == self.history_size {
+            self.history.pop_front();
+        }
+        self.history.push_back((pc, instruction));
+    }
+
+    // Returns true and remembers the watchpoint if the access touches one
+    pub fn check(&mut self, addr: usize, len: usize, write: bool) -> bool {
+        let hit = self.watchpoints.iter().find(|watch| {
+            let kind_matches = match watch.kind {
+                WatchKind::Write => write,
+                WatchKind::Read => !write,
+                WatchKind::Access => true,
+            };
+            kind_matches && addr < watch.addr + watch.len && watch.addr < addr + len
+        });
+        self.hit = hit.cloned();
+        self.hit.is_some()
+    }
+
+    pub fn take_event(&mut self) -> DebugEvent {
+        match self.hit.take() {
+            Some(watch) => DebugEvent::Watchpoint(watch),
+            None => DebugEvent::Breakpoint,
+        }
+    }
+}
+
+impl Default for DebugState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_watchpoints() {
+        let mut dut = DebugState::new();
+        let watch = Watchpoint {
+            addr: 0x8000_1000,
+            len: 4,
+            kind: WatchKind::Write,
+        };
+        dut.watchpoints.push(watch.clone());
+
+        assert!(!dut.check(0x8000_1000, 4, false));
+        assert!(!dut.check(0x8000_0ffc, 4, true));
+        assert!(dut.check(0x8000_1002, 1, true));
+        assert_eq!(dut.take_event(), DebugEvent::Watchpoint(watch));
+        // Without a watchpoint hit the stop came from ebreak
+        assert_eq!(dut.take_event(), DebugEvent::Breakpoint);
+    }
+    #[test]
+    fn test_history() {
+        let mut dut = DebugState::new();
+        // Recording is off until a size is set
+        dut.record(0x8000_0000, 0x13);
+        assert!(dut.history.is_empty());
+
+        dut.history_size = 2;
+        dut.record(0x8000_0000, 0x13);
+        dut.record(0x8000_0004, 0x6f);
+        dut.record(0x8000_0008, 0x73);
+        assert_eq!(
+            dut.history,
+            VecDeque::from(vec![(0x8000_0004, 0x6f), (0x8000_0008, 0x73)])
+        );
+    }
+}
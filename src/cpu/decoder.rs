@@ -1,11 +1,263 @@
 use num_traits::FromPrimitive;
 
-use super::instructions::{IInstruction, RInstruction, SBInstruction, UJInstruction};
+use super::csr::{EXT_ZBA, EXT_ZBB, EXT_ZBC, EXT_ZBS, MISA_A, MISA_C, MISA_D, MISA_F, MISA_M};
+use super::instructions::{FInstruction, IInstruction, RInstruction, SBInstruction, UJInstruction};
 use super::instructions::{Instruction, Opcode};
 use crate::trap::RVException;
 
+// 32-bit instructions have the two lowest bits set, everything else is RVC
+pub fn is_compressed(raw: u32) -> bool {
+    raw & 0b11 != 0b11
+}
+
+// Extension bits (misa or EXT_Z*) that a decoded instruction belongs to
+pub fn required_extensions(raw: u32, instruction: &Instruction) -> u32 {
+    let compressed = if is_compressed(raw) { MISA_C } else { 0 };
+    let extension = match instruction {
+        Instruction::RType { inst, .. } => match inst {
+            RInstruction::mul
+            | RInstruction::mulh
+            | RInstruction::mulhsu
+            | RInstruction::mulhu
+            | RInstruction::div
+            | RInstruction::divu
+            | RInstruction::rem
+            | RInstruction::remu => MISA_M,
+            RInstruction::lrw
+            | RInstruction::scw
+            | RInstruction::amoSwapW
+            | RInstruction::amoAddW
+            | RInstruction::amoXorW
+            | RInstruction::amoAndW
+            | RInstruction::amoOrW
+            | RInstruction::amoMinW
+            | RInstruction::amoMaxW
+            | RInstruction::amoMinUW
+            | RInstruction::amoMaxUW => MISA_A,
+            RInstruction::sh1add | RInstruction::sh2add | RInstruction::sh3add => EXT_ZBA,
+            RInstruction::andn
+            | RInstruction::orn
+            | RInstruction::xnor
+            | RInstruction::max
+            | RInstruction::maxu
+            | RInstruction::min
+            | RInstruction::minu
+            | RInstruction::rol
+            | RInstruction::ror
+            | RInstruction::zexth => EXT_ZBB,
+            RInstruction::clmul | RInstruction::clmulh | RInstruction::clmulr => EXT_ZBC,
+            RInstruction::bclr | RInstruction::bext | RInstruction::binv | RInstruction::bset => {
+                EXT_ZBS
+            }
+            _ => 0,
+        },
+        Instruction::IType {
+            inst:
+                IInstruction::clz
+                | IInstruction::ctz
+                | IInstruction::cpop
+                | IInstruction::sextb
+                | IInstruction::sexth
+                | IInstruction::orcb
+                | IInstruction::rev8
+                | IInstruction::rori,
+            ..
+        } => EXT_ZBB,
+        Instruction::IType {
+            inst:
+                IInstruction::bclri | IInstruction::bexti | IInstruction::binvi | IInstruction::bseti,
+            ..
+        } => EXT_ZBS,
+        Instruction::IType {
+            inst: IInstruction::flw,
+            ..
+        }
+        | Instruction::SBType {
+            inst: SBInstruction::fsw,
+            ..
+        } => MISA_F,
+        Instruction::IType {
+            inst: IInstruction::fld,
+            ..
+        }
+        | Instruction::SBType {
+            inst: SBInstruction::fsd,
+            ..
+        } => MISA_D,
+        Instruction::FType { inst, .. } if inst.is_double() => MISA_D,
+        Instruction::FType { .. } => MISA_F,
+        _ => 0,
+    };
+    compressed | extension
+}
+
+// Expand a 16-bit RVC instruction into its 32-bit equivalent
+fn decode_compressed(raw: &u32) -> Result<Instruction, RVException> {
+    let illegal = Err(RVException::IllegalInstruction(*raw));
+
+    // Extract bit range [hi:lo]
+    let bits = |hi: u32, lo: u32| (raw >> lo) & ((1 << (hi - lo + 1)) - 1);
+    // Sign-extend a value of the given width
+    let sext = |value: u32, width: u32| ((value << (32 - width)) as i32) >> (32 - width);
+
+    // Full and popular (x8-x15) register fields
+    let rd = bits(11, 7) as usize;
+    let rs2 = bits(6, 2) as usize;
+    let rd_p = bits(4, 2) as usize + 8;
+    let rs1_p = bits(9, 7) as usize + 8;
+
+    // Immediates that are shared by several formats
+    let imm_ci = sext(bits(12, 12) << 5 | bits(6, 2), 6);
+    let uimm_clw = (bits(12, 10) << 3 | bits(6, 6) << 2 | bits(5, 5) << 6) as i32;
+    let uimm_cld = (bits(12, 10) << 3 | bits(6, 5) << 6) as i32;
+    let imm_cj = sext(
+        bits(12, 12) << 11
+            | bits(11, 11) << 4
+            | bits(10, 9) << 8
+            | bits(8, 8) << 10
+            | bits(7, 7) << 6
+            | bits(6, 6) << 7
+            | bits(5, 3) << 1
+            | bits(2, 2) << 5,
+        12,
+    );
+    let imm_cb = sext(
+        bits(12, 12) << 8 | bits(11, 10) << 3 | bits(6, 5) << 6 | bits(4, 3) << 1 | bits(2, 2) << 5,
+        9,
+    );
+
+    let itype = |rd: usize, rs1: usize, imm: i32, inst: IInstruction| {
+        Ok(Instruction::IType { rd, rs1, imm, inst })
+    };
+    let rtype = |rd: usize, rs1: usize, rs2: usize, inst: RInstruction| {
+        Ok(Instruction::RType { rd, rs1, rs2, inst })
+    };
+    let sbtype = |rs1: usize, rs2: usize, imm: i32, inst: SBInstruction| {
+        Ok(Instruction::SBType {
+            rs1,
+            rs2,
+            imm,
+            inst,
+        })
+    };
+    let ujtype =
+        |rd: usize, imm: i32, inst: UJInstruction| Ok(Instruction::UJType { rd, imm, inst });
+
+    match (raw & 0b11, bits(15, 13)) {
+        // Quadrant 0
+        (0b00, 0b000) => {
+            // c.addi4spn
+            let nzuimm = bits(12, 11) << 4 | bits(10, 7) << 6 | bits(6, 6) << 2 | bits(5, 5) << 3;
+            if nzuimm == 0 {
+                // Also catches the all-zero illegal instruction
+                return illegal;
+            }
+            itype(rd_p, 2, nzuimm as i32, IInstruction::addi)
+        }
+        (0b00, 0b001) => itype(rd_p, rs1_p, uimm_cld, IInstruction::fld), // c.fld
+        (0b00, 0b010) => itype(rd_p, rs1_p, uimm_clw, IInstruction::lw),  // c.lw
+        (0b00, 0b011) => itype(rd_p, rs1_p, uimm_clw, IInstruction::flw), // c.flw
+        (0b00, 0b101) => sbtype(rs1_p, rd_p, uimm_cld, SBInstruction::fsd), // c.fsd
+        (0b00, 0b110) => sbtype(rs1_p, rd_p, uimm_clw, SBInstruction::sw), // c.sw
+        (0b00, 0b111) => sbtype(rs1_p, rd_p, uimm_clw, SBInstruction::fsw), // c.fsw
+
+        // Quadrant 1
+        (0b01, 0b000) => itype(rd, rd, imm_ci, IInstruction::addi), // c.addi, c.nop
+        (0b01, 0b001) => ujtype(1, imm_cj, UJInstruction::jal),     // c.jal
+        (0b01, 0b010) => itype(rd, 0, imm_ci, IInstruction::addi),  // c.li
+        (0b01, 0b011) if rd == 2 => {
+            // c.addi16sp
+            let nzimm = sext(
+                bits(12, 12) << 9
+                    | bits(6, 6) << 4
+                    | bits(5, 5) << 6
+                    | bits(4, 3) << 7
+                    | bits(2, 2) << 5,
+                10,
+            );
+            if nzimm == 0 {
+                return illegal;
+            }
+            itype(2, 2, nzimm, IInstruction::addi)
+        }
+        (0b01, 0b011) => {
+            // c.lui
+            if imm_ci == 0 {
+                return illegal;
+            }
+            ujtype(rd, imm_ci, UJInstruction::lui)
+        }
+        (0b01, 0b100) => {
+            let shamt = bits(6, 2) as i32;
+            match (bits(12, 12), bits(11, 10), bits(6, 5)) {
+                // shamt[5] must be zero on RV32
+                (0, 0b00, _) => itype(rs1_p, rs1_p, shamt, IInstruction::srli), // c.srli
+                (0, 0b01, _) => itype(rs1_p, rs1_p, 0x400 | shamt, IInstruction::srai), // c.srai
+                (_, 0b10, _) => itype(rs1_p, rs1_p, imm_ci, IInstruction::andi), // c.andi
+                (0, 0b11, 0b00) => rtype(rs1_p, rs1_p, rd_p, RInstruction::sub), // c.sub
+                (0, 0b11, 0b01) => rtype(rs1_p, rs1_p, rd_p, RInstruction::xor), // c.xor
+                (0, 0b11, 0b10) => rtype(rs1_p, rs1_p, rd_p, RInstruction::or), // c.or
+                (0, 0b11, 0b11) => rtype(rs1_p, rs1_p, rd_p, RInstruction::and), // c.and
+                _ => illegal,
+            }
+        }
+        (0b01, 0b101) => ujtype(0, imm_cj, UJInstruction::jal), // c.j
+        (0b01, 0b110) => sbtype(rs1_p, 0, imm_cb, SBInstruction::beq), // c.beqz
+        (0b01, 0b111) => sbtype(rs1_p, 0, imm_cb, SBInstruction::bne), // c.bnez
+
+        // Quadrant 2
+        (0b10, 0b000) if bits(12, 12) == 0 => {
+            itype(rd, rd, bits(6, 2) as i32, IInstruction::slli) // c.slli
+        }
+        (0b10, 0b001) => {
+            // c.fldsp
+            let uimm = bits(12, 12) << 5 | bits(6, 5) << 3 | bits(4, 2) << 6;
+            itype(rd, 2, uimm as i32, IInstruction::fld)
+        }
+        (0b10, 0b010) if rd != 0 => {
+            // c.lwsp
+            let uimm = bits(12, 12) << 5 | bits(6, 4) << 2 | bits(3, 2) << 6;
+            itype(rd, 2, uimm as i32, IInstruction::lw)
+        }
+        (0b10, 0b011) => {
+            // c.flwsp, f0 is a valid destination
+            let uimm = bits(12, 12) << 5 | bits(6, 4) << 2 | bits(3, 2) << 6;
+            itype(rd, 2, uimm as i32, IInstruction::flw)
+        }
+        (0b10, 0b100) => match (bits(12, 12), rd, rs2) {
+            (0, 0, 0) => illegal,
+            (0, _, 0) => itype(0, rd, 0, IInstruction::jalr), // c.jr
+            (0, _, _) => rtype(rd, 0, rs2, RInstruction::add), // c.mv
+            (1, 0, 0) => itype(0, 0, 1, IInstruction::ebreak), // c.ebreak
+            (1, _, 0) => itype(1, rd, 0, IInstruction::jalr), // c.jalr
+            (_, _, _) => rtype(rd, rd, rs2, RInstruction::add), // c.add
+        },
+        (0b10, 0b101) => {
+            // c.fsdsp
+            let uimm = bits(12, 10) << 3 | bits(9, 7) << 6;
+            sbtype(2, rs2, uimm as i32, SBInstruction::fsd)
+        }
+        (0b10, 0b110) => {
+            // c.swsp
+            let uimm = bits(12, 9) << 2 | bits(8, 7) << 6;
+            sbtype(2, rs2, uimm as i32, SBInstruction::sw)
+        }
+        (0b10, 0b111) => {
+            // c.fswsp
+            let uimm = bits(12, 9) << 2 | bits(8, 7) << 6;
+            sbtype(2, rs2, uimm as i32, SBInstruction::fsw)
+        }
+
+        _ => illegal,
+    }
+}
+
 // Decode instruction from raw integer
 pub fn decode(raw: &u32) -> Result<Instruction, RVException> {
+    if is_compressed(*raw) {
+        return decode_compressed(raw);
+    }
+
     // Extract registers
     let rd = |raw: &u32| ((raw >> 7) & 0x1f) as usize;
     let rs1 = |raw: &u32| ((raw >> 15) & 0x1f) as usize;
@@ -13,19 +265,19 @@ pub fn decode(raw: &u32) -> Result<Instruction, RVException> {
 
     // Extract funct codes
     let f3 = |raw: &u32| (raw >> 12) & 0b111;
-    let f7 = |raw: &u32| (raw >> 25);
+    let f7 = |raw: &u32| raw >> 25;
 
     // Extract immediates
-    let imm_i = |raw: &u32| ((*raw as i32) >> 20);
+    let imm_i = |raw: &u32| (*raw as i32) >> 20;
     let imm_b = |raw: &u32| {
         ((*raw as i32) >> 31) << 12
             | (((raw >> 7) & 0b1) << 11) as i32
             | (((raw >> 25) & 0b111111) << 5) as i32
             | (((raw >> 8) & 0b1111) << 1) as i32
     };
-    let imm_s = |raw: &u32| (((*raw as i32) >> 25) << 5) as i32 | ((raw >> 7) & 0x1f) as i32;
+    let imm_s = |raw: &u32| (((*raw as i32) >> 25) << 5) | ((raw >> 7) & 0x1f) as i32;
     let imm_j = |raw: &u32| {
-        (((*raw as i32) >> 31) << 20) as i32
+        (((*raw as i32) >> 31) << 20)
             | (raw & (0xff << 12)) as i32
             | (((raw >> 20) & 1) << 11) as i32
             | (((raw >> 21) & 0x3ff) << 1) as i32
@@ -42,7 +294,7 @@ pub fn decode(raw: &u32) -> Result<Instruction, RVException> {
             Opcode::ARITH_REG | Opcode::ATOMIC => {
                 let funct3 = f3(raw);
                 let funct7 = f7(raw);
-                if let Some(inst) = RInstruction::new(&opcode, &funct3, &funct7) {
+                if let Some(inst) = RInstruction::new(&opcode, &funct3, &funct7, &rs2(raw)) {
                     Ok(Instruction::RType {
                         rd: rd(raw),
                         rs1: rs1(raw),
@@ -54,7 +306,12 @@ pub fn decode(raw: &u32) -> Result<Instruction, RVException> {
                 }
             }
             // I-Type format
-            Opcode::ARITH_IMM | Opcode::LOAD | Opcode::JALR | Opcode::SYSTEM | Opcode::FENCE => {
+            Opcode::ARITH_IMM
+            | Opcode::LOAD
+            | Opcode::LOAD_FP
+            | Opcode::JALR
+            | Opcode::SYSTEM
+            | Opcode::FENCE => {
                 let imm = imm_i(raw);
                 let funct3 = f3(raw);
                 if let Some(inst) = IInstruction::new(&opcode, &funct3, &imm) {
@@ -82,7 +339,7 @@ pub fn decode(raw: &u32) -> Result<Instruction, RVException> {
                     Err(RVException::IllegalInstruction(*raw))
                 }
             }
-            Opcode::STORE => {
+            Opcode::STORE | Opcode::STORE_FP => {
                 let funct3 = f3(raw);
                 if let Some(inst) = SBInstruction::new(&opcode, &funct3) {
                     Ok(Instruction::SBType {
@@ -105,6 +362,23 @@ pub fn decode(raw: &u32) -> Result<Instruction, RVException> {
                 rd: rd(raw),
                 inst: UJInstruction::new(opcode),
             }),
+            // R-Type and R4-Type floating-point formats
+            Opcode::OP_FP | Opcode::MADD | Opcode::MSUB | Opcode::NMSUB | Opcode::NMADD => {
+                let funct3 = f3(raw);
+                let funct7 = f7(raw);
+                if let Some(inst) = FInstruction::new(&opcode, &funct3, &funct7, &rs2(raw)) {
+                    Ok(Instruction::FType {
+                        rd: rd(raw),
+                        rs1: rs1(raw),
+                        rs2: rs2(raw),
+                        rs3: (raw >> 27) as usize,
+                        rm: funct3,
+                        inst,
+                    })
+                } else {
+                    Err(RVException::IllegalInstruction(*raw))
+                }
+            }
         }
     } else {
         Err(RVException::IllegalInstruction(*raw))
@@ -147,6 +421,15 @@ mod tests {
                 rs1: 10,
                 inst: IInstruction::slli
             })
+        );
+        assert_eq!(
+            decode(&0x12b50073),
+            Ok(Instruction::IType {
+                imm: 0x12b,
+                rd: 0,
+                rs1: 10,
+                inst: IInstruction::sfencevma
+            })
         )
     }
     #[test]
@@ -237,4 +520,315 @@ mod tests {
             })
         );
     }
+
+    #[test]
+    fn test_bitmanip() {
+        // rori a0, a1, 4
+        assert_eq!(
+            decode(&0x6045d513),
+            Ok(Instruction::IType {
+                imm: 0x604,
+                rd: 10,
+                rs1: 11,
+                inst: IInstruction::rori
+            })
+        );
+        // rev8 a0, a1
+        assert_eq!(
+            decode(&0x6985d513),
+            Ok(Instruction::IType {
+                imm: 0x698,
+                rd: 10,
+                rs1: 11,
+                inst: IInstruction::rev8
+            })
+        );
+        // zext.h a0, a1
+        assert_eq!(
+            decode(&0x0805c533),
+            Ok(Instruction::RType {
+                rd: 10,
+                rs1: 11,
+                rs2: 0,
+                inst: RInstruction::zexth
+            })
+        );
+        assert_eq!(
+            required_extensions(0x0ac5a533, &decode(&0x0ac5a533).unwrap()),
+            EXT_ZBC
+        );
+    }
+    #[test]
+    fn test_ftype() {
+        // fmadd.s fa0, fa1, fa2, fa3, rne
+        assert_eq!(
+            decode(&0x68c58543),
+            Ok(Instruction::FType {
+                rd: 10,
+                rs1: 11,
+                rs2: 12,
+                rs3: 13,
+                rm: 0,
+                inst: FInstruction::fmadds
+            })
+        );
+        // fcvt.wu.s a0, fa0, rtz
+        assert_eq!(
+            decode(&0xc0151553),
+            Ok(Instruction::FType {
+                rd: 10,
+                rs1: 10,
+                rs2: 1,
+                rs3: 24,
+                rm: 1,
+                inst: FInstruction::fcvtwus
+            })
+        );
+        // flw fa0, -4(sp)
+        assert_eq!(
+            decode(&0xffc12507),
+            Ok(Instruction::IType {
+                imm: -4,
+                rd: 10,
+                rs1: 2,
+                inst: IInstruction::flw
+            })
+        );
+        // fcvt.wu.d a0, fa1, rtz
+        assert_eq!(
+            decode(&0xc2159553),
+            Ok(Instruction::FType {
+                rd: 10,
+                rs1: 11,
+                rs2: 1,
+                rs3: 24,
+                rm: 1,
+                inst: FInstruction::fcvtwud
+            })
+        );
+        // fmadd with fmt=0b10, the Q extension, is not implemented
+        assert_eq!(
+            decode(&0x6cc58543),
+            Err(RVException::IllegalInstruction(0x6cc58543))
+        );
+    }
+
+    #[test]
+    fn test_compressed_quadrant0() {
+        assert_eq!(
+            decode(&0x1fe8),
+            Ok(Instruction::IType {
+                imm: 1020,
+                rd: 10,
+                rs1: 2,
+                inst: IInstruction::addi
+            })
+        );
+        assert_eq!(
+            decode(&0x41d0),
+            Ok(Instruction::IType {
+                imm: 4,
+                rd: 12,
+                rs1: 11,
+                inst: IInstruction::lw
+            })
+        );
+        assert_eq!(
+            decode(&0xdfe8),
+            Ok(Instruction::SBType {
+                imm: 124,
+                rs1: 15,
+                rs2: 10,
+                inst: SBInstruction::sw
+            })
+        );
+        assert_eq!(
+            decode(&0xe1c8),
+            Ok(Instruction::SBType {
+                imm: 4,
+                rs1: 11,
+                rs2: 10,
+                inst: SBInstruction::fsw
+            })
+        );
+        assert_eq!(
+            decode(&0x2588),
+            Ok(Instruction::IType {
+                imm: 8,
+                rd: 10,
+                rs1: 11,
+                inst: IInstruction::fld
+            })
+        );
+    }
+    #[test]
+    fn test_compressed_quadrant1() {
+        assert_eq!(
+            decode(&0x1501),
+            Ok(Instruction::IType {
+                imm: -32,
+                rd: 10,
+                rs1: 10,
+                inst: IInstruction::addi
+            })
+        );
+        assert_eq!(
+            decode(&0x3001),
+            Ok(Instruction::UJType {
+                imm: -2048,
+                rd: 1,
+                inst: UJInstruction::jal
+            })
+        );
+        assert_eq!(
+            decode(&0x7101),
+            Ok(Instruction::IType {
+                imm: -512,
+                rd: 2,
+                rs1: 2,
+                inst: IInstruction::addi
+            })
+        );
+        assert_eq!(
+            decode(&0x7785),
+            Ok(Instruction::UJType {
+                imm: -31,
+                rd: 15,
+                inst: UJInstruction::lui
+            })
+        );
+        assert_eq!(
+            decode(&0x8485),
+            Ok(Instruction::IType {
+                imm: 0x401,
+                rd: 9,
+                rs1: 9,
+                inst: IInstruction::srai
+            })
+        );
+        assert_eq!(
+            decode(&0x8c1d),
+            Ok(Instruction::RType {
+                rd: 8,
+                rs1: 8,
+                rs2: 15,
+                inst: RInstruction::sub
+            })
+        );
+        assert_eq!(
+            decode(&0xaffd),
+            Ok(Instruction::UJType {
+                imm: 2046,
+                rd: 0,
+                inst: UJInstruction::jal
+            })
+        );
+        assert_eq!(
+            decode(&0xd101),
+            Ok(Instruction::SBType {
+                imm: -256,
+                rs1: 10,
+                rs2: 0,
+                inst: SBInstruction::beq
+            })
+        );
+        assert_eq!(
+            decode(&0xecfd),
+            Ok(Instruction::SBType {
+                imm: 254,
+                rs1: 9,
+                rs2: 0,
+                inst: SBInstruction::bne
+            })
+        );
+    }
+    #[test]
+    fn test_compressed_quadrant2() {
+        assert_eq!(
+            decode(&0x50fe),
+            Ok(Instruction::IType {
+                imm: 252,
+                rd: 1,
+                rs1: 2,
+                inst: IInstruction::lw
+            })
+        );
+        assert_eq!(
+            decode(&0x8082),
+            Ok(Instruction::IType {
+                imm: 0,
+                rd: 0,
+                rs1: 1,
+                inst: IInstruction::jalr
+            })
+        );
+        assert_eq!(
+            decode(&0x857e),
+            Ok(Instruction::RType {
+                rd: 10,
+                rs1: 0,
+                rs2: 31,
+                inst: RInstruction::add
+            })
+        );
+        assert_eq!(
+            decode(&0x9002),
+            Ok(Instruction::IType {
+                imm: 1,
+                rd: 0,
+                rs1: 0,
+                inst: IInstruction::ebreak
+            })
+        );
+        assert_eq!(
+            decode(&0x9282),
+            Ok(Instruction::IType {
+                imm: 0,
+                rd: 1,
+                rs1: 5,
+                inst: IInstruction::jalr
+            })
+        );
+        assert_eq!(
+            decode(&0xdffe),
+            Ok(Instruction::SBType {
+                imm: 252,
+                rs1: 2,
+                rs2: 31,
+                inst: SBInstruction::sw
+            })
+        );
+        assert_eq!(
+            decode(&0x6532),
+            Ok(Instruction::IType {
+                imm: 12,
+                rd: 10,
+                rs1: 2,
+                inst: IInstruction::flw
+            })
+        );
+        assert_eq!(
+            decode(&0xa626),
+            Ok(Instruction::SBType {
+                imm: 264,
+                rs1: 2,
+                rs2: 9,
+                inst: SBInstruction::fsd
+            })
+        );
+    }
+    #[test]
+    fn test_compressed_illegal() {
+        assert_eq!(decode(&0x0000), Err(RVException::IllegalInstruction(0)));
+        // c.lwsp with rd=x0
+        assert_eq!(
+            decode(&0x4002),
+            Err(RVException::IllegalInstruction(0x4002))
+        );
+        // c.slli with shamt[5] set
+        assert_eq!(
+            decode(&0x1006),
+            Err(RVException::IllegalInstruction(0x1006))
+        );
+    }
 }
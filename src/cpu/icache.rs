@@ -0,0 +1,92 @@
+use std::collections::HashMap;
+
+use super::instructions::Instruction;
+use super::mmu::{PAGE_SHIFT, PAGE_SIZE};
+
+// One slot per halfword, since compressed instructions can start at any of them
+const SLOTS: usize = PAGE_SIZE / 2;
+
+// Translated code by physical address, so that hot code is only fetched
+// through the MMU but not decoded again. Pages are dropped as a whole when
+// they are written to.
+pub struct PageCache<T> {
+    pages: HashMap<usize, Vec<Option<T>>>,
+}
+
+// Raw and decoded instructions for the interpreter
+pub type DecodeCache = PageCache<(u32, Instruction)>;
+
+impl<T: Clone> PageCache<T> {
+    pub fn new() -> Self {
+        Self {
+            pages: HashMap::new(),
+        }
+    }
+
+    pub fn get(&self, paddr: usize) -> Option<&T> {
+        self.pages
+            .get(&(paddr >> PAGE_SHIFT))
+            .and_then(|page| page[Self::slot(paddr)].as_ref())
+    }
+
+    pub fn insert(&mut self, paddr: usize, entry: T) {
+        let page = self
+            .pages
+            .entry(paddr >> PAGE_SHIFT)
+            .or_insert_with(|| vec![None; SLOTS]);
+        page[Self::slot(paddr)] = Some(entry);
+    }
+
+    // Stores never cross a page boundary, as they have to be aligned.
+    // Returns whether anything was cached for the page.
+    pub fn invalidate(&mut self, paddr: usize) -> bool {
+        self.pages.remove(&(paddr >> PAGE_SHIFT)).is_some()
+    }
+
+    // fence.i
+    pub fn flush(&mut self) {
+        self.pages.clear();
+    }
+
+    fn slot(paddr: usize) -> usize {
+        (paddr & (PAGE_SIZE - 1)) >> 1
+    }
+}
+
+impl<T: Clone> Default for PageCache<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::instructions::IInstruction;
+
+    fn nop() -> Instruction {
+        Instruction::IType {
+            imm: 0,
+            rd: 0,
+            rs1: 0,
+            inst: IInstruction::addi,
+        }
+    }
+
+    #[test]
+    fn test_invalidate_page() {
+        let mut dut = DecodeCache::new();
+        dut.insert(0x8000_0ffc, (0x13, nop()));
+        dut.insert(0x8000_1000, (0x13, nop()));
+        assert_eq!(dut.get(0x8000_0ffc), Some(&(0x13, nop())));
+        assert_eq!(dut.get(0x8000_0ffe), None);
+
+        // A store anywhere in the page drops all of it
+        assert!(dut.invalidate(0x8000_0004));
+        assert!(!dut.invalidate(0x8000_0008));
+        assert_eq!(dut.get(0x8000_0ffc), None);
+        assert!(dut.get(0x8000_1000).is_some());
+        dut.flush();
+        assert_eq!(dut.get(0x8000_1000), None);
+    }
+}
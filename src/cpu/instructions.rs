@@ -16,6 +16,13 @@ pub enum Opcode {
     FENCE = 0b_000_1111,
     SYSTEM = 0b_111_0011,
     ATOMIC = 0b010_1111,
+    LOAD_FP = 0b000_0111,
+    STORE_FP = 0b010_0111,
+    MADD = 0b100_0011,
+    MSUB = 0b100_0111,
+    NMSUB = 0b100_1011,
+    NMADD = 0b100_1111,
+    OP_FP = 0b101_0011,
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -53,10 +60,34 @@ pub enum RInstruction {
     amoMaxW,
     amoMinUW,
     amoMaxUW,
+
+    sh1add,
+    sh2add,
+    sh3add,
+
+    andn,
+    orn,
+    xnor,
+    max,
+    maxu,
+    min,
+    minu,
+    rol,
+    ror,
+    zexth,
+
+    clmul,
+    clmulh,
+    clmulr,
+
+    bclr,
+    bext,
+    binv,
+    bset,
 }
 
 impl RInstruction {
-    pub fn new(opcode: &Opcode, funct3: &u32, funct7: &u32) -> Option<Self> {
+    pub fn new(opcode: &Opcode, funct3: &u32, funct7: &u32, rs2: &usize) -> Option<Self> {
         if *opcode == Opcode::ATOMIC && *funct3 == 0b010 {
             let funct = funct7 >> 2;
             return match funct {
@@ -102,6 +133,31 @@ impl RInstruction {
             (0b110, 1) => Some(RInstruction::rem),
             (0b111, 1) => Some(RInstruction::remu),
 
+            (0b010, 0x10) => Some(RInstruction::sh1add),
+            (0b100, 0x10) => Some(RInstruction::sh2add),
+            (0b110, 0x10) => Some(RInstruction::sh3add),
+
+            (0b111, 0x20) => Some(RInstruction::andn),
+            (0b110, 0x20) => Some(RInstruction::orn),
+            (0b100, 0x20) => Some(RInstruction::xnor),
+            (0b110, 0x05) => Some(RInstruction::max),
+            (0b111, 0x05) => Some(RInstruction::maxu),
+            (0b100, 0x05) => Some(RInstruction::min),
+            (0b101, 0x05) => Some(RInstruction::minu),
+            (0b001, 0x30) => Some(RInstruction::rol),
+            (0b101, 0x30) => Some(RInstruction::ror),
+            // rs2 is part of the encoding
+            (0b100, 0x04) if *rs2 == 0 => Some(RInstruction::zexth),
+
+            (0b001, 0x05) => Some(RInstruction::clmul),
+            (0b011, 0x05) => Some(RInstruction::clmulh),
+            (0b010, 0x05) => Some(RInstruction::clmulr),
+
+            (0b001, 0x24) => Some(RInstruction::bclr),
+            (0b101, 0x24) => Some(RInstruction::bext),
+            (0b001, 0x34) => Some(RInstruction::binv),
+            (0b001, 0x14) => Some(RInstruction::bset),
+
             _ => None,
         }
     }
@@ -121,11 +177,27 @@ pub enum IInstruction {
     slti,
     sltiu,
 
+    clz,
+    ctz,
+    cpop,
+    sextb,
+    sexth,
+    orcb,
+    rev8,
+    rori,
+
+    bclri,
+    bexti,
+    binvi,
+    bseti,
+
     lb,
     lh,
     lw,
     lbu,
     lhu,
+    flw,
+    fld,
 
     jalr,
 
@@ -133,6 +205,7 @@ pub enum IInstruction {
     ebreak,
     fence,
     fencei,
+    sfencevma,
 
     csrrw,
     csrrs,
@@ -162,6 +235,21 @@ impl IInstruction {
             (Opcode::ARITH_IMM, 0b101, _) if (*imm & !0x1f) == 0x000 => Some(IInstruction::srli),
             (Opcode::ARITH_IMM, 0b101, _) if (*imm & !0x1f) == 0x400 => Some(IInstruction::srai),
 
+            // Unary bit-manipulation ops keep their funct7 and rs2 in the immediate
+            (Opcode::ARITH_IMM, 0b001, 0x600) => Some(IInstruction::clz),
+            (Opcode::ARITH_IMM, 0b001, 0x601) => Some(IInstruction::ctz),
+            (Opcode::ARITH_IMM, 0b001, 0x602) => Some(IInstruction::cpop),
+            (Opcode::ARITH_IMM, 0b001, 0x604) => Some(IInstruction::sextb),
+            (Opcode::ARITH_IMM, 0b001, 0x605) => Some(IInstruction::sexth),
+            (Opcode::ARITH_IMM, 0b101, 0x287) => Some(IInstruction::orcb),
+            (Opcode::ARITH_IMM, 0b101, 0x698) => Some(IInstruction::rev8),
+            (Opcode::ARITH_IMM, 0b101, _) if (*imm & !0x1f) == 0x600 => Some(IInstruction::rori),
+
+            (Opcode::ARITH_IMM, 0b001, _) if (*imm & !0x1f) == 0x480 => Some(IInstruction::bclri),
+            (Opcode::ARITH_IMM, 0b101, _) if (*imm & !0x1f) == 0x480 => Some(IInstruction::bexti),
+            (Opcode::ARITH_IMM, 0b001, _) if (*imm & !0x1f) == 0x680 => Some(IInstruction::binvi),
+            (Opcode::ARITH_IMM, 0b001, _) if (*imm & !0x1f) == 0x280 => Some(IInstruction::bseti),
+
             (Opcode::ARITH_IMM, 0x0, _) => Some(IInstruction::addi),
             (Opcode::ARITH_IMM, 0x4, _) => Some(IInstruction::xori),
             (Opcode::ARITH_IMM, 0x6, _) => Some(IInstruction::ori),
@@ -175,6 +263,8 @@ impl IInstruction {
             (Opcode::LOAD, 0x2, _) => Some(IInstruction::lw),
             (Opcode::LOAD, 0x4, _) => Some(IInstruction::lbu),
             (Opcode::LOAD, 0x5, _) => Some(IInstruction::lhu),
+            (Opcode::LOAD_FP, 0x2, _) => Some(IInstruction::flw),
+            (Opcode::LOAD_FP, 0x3, _) => Some(IInstruction::fld),
 
             (Opcode::JALR, 0x0, _) => Some(IInstruction::jalr),
 
@@ -191,6 +281,8 @@ impl IInstruction {
             (Opcode::SYSTEM, 0x0, 0x102) => Some(IInstruction::sret),
             (Opcode::SYSTEM, 0x0, 0x302) => Some(IInstruction::mret),
             (Opcode::SYSTEM, 0x0, 0x105) => Some(IInstruction::wfi),
+            // sfence.vma is R-type, so the immediate holds funct7 and rs2
+            (Opcode::SYSTEM, 0x0, _) if (*imm >> 5) == 0b000_1001 => Some(IInstruction::sfencevma),
 
             _ => None,
         }
@@ -222,6 +314,8 @@ pub enum SBInstruction {
     sb,
     sh,
     sw,
+    fsw,
+    fsd,
     beq,
     bne,
     blt,
@@ -236,6 +330,8 @@ impl SBInstruction {
             (Opcode::STORE, 0x0) => Some(SBInstruction::sb),
             (Opcode::STORE, 0x1) => Some(SBInstruction::sh),
             (Opcode::STORE, 0x2) => Some(SBInstruction::sw),
+            (Opcode::STORE_FP, 0x2) => Some(SBInstruction::fsw),
+            (Opcode::STORE_FP, 0x3) => Some(SBInstruction::fsd),
 
             (Opcode::BRANCH, 0x0) => Some(SBInstruction::beq),
             (Opcode::BRANCH, 0x1) => Some(SBInstruction::bne),
@@ -250,6 +346,171 @@ impl SBInstruction {
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[allow(non_camel_case_types)]
+pub enum FInstruction {
+    fmadds,
+    fmsubs,
+    fnmsubs,
+    fnmadds,
+
+    fadds,
+    fsubs,
+    fmuls,
+    fdivs,
+    fsqrts,
+
+    fsgnjs,
+    fsgnjns,
+    fsgnjxs,
+    fmins,
+    fmaxs,
+
+    fcvtws,
+    fcvtwus,
+    fcvtsw,
+    fcvtswu,
+    fmvxw,
+    fmvwx,
+
+    feqs,
+    flts,
+    fles,
+    fclasss,
+
+    fmaddd,
+    fmsubd,
+    fnmsubd,
+    fnmaddd,
+
+    faddd,
+    fsubd,
+    fmuld,
+    fdivd,
+    fsqrtd,
+
+    fsgnjd,
+    fsgnjnd,
+    fsgnjxd,
+    fmind,
+    fmaxd,
+
+    fcvtsd,
+    fcvtds,
+    fcvtwd,
+    fcvtwud,
+    fcvtdw,
+    fcvtdwu,
+
+    feqd,
+    fltd,
+    fled,
+    fclassd,
+}
+
+impl FInstruction {
+    pub fn new(opcode: &Opcode, funct3: &u32, funct7: &u32, rs2: &usize) -> Option<Self> {
+        // The lowest two bits of funct7 select the format, 0 is single and 1
+        // double precision
+        if *opcode != Opcode::OP_FP {
+            return match (opcode, funct7 & 0b11) {
+                (Opcode::MADD, 0b00) => Some(FInstruction::fmadds),
+                (Opcode::MSUB, 0b00) => Some(FInstruction::fmsubs),
+                (Opcode::NMSUB, 0b00) => Some(FInstruction::fnmsubs),
+                (Opcode::NMADD, 0b00) => Some(FInstruction::fnmadds),
+                (Opcode::MADD, 0b01) => Some(FInstruction::fmaddd),
+                (Opcode::MSUB, 0b01) => Some(FInstruction::fmsubd),
+                (Opcode::NMSUB, 0b01) => Some(FInstruction::fnmsubd),
+                (Opcode::NMADD, 0b01) => Some(FInstruction::fnmaddd),
+                _ => None,
+            };
+        }
+        match (funct7, funct3, rs2) {
+            (0x00, _, _) => Some(FInstruction::fadds),
+            (0x04, _, _) => Some(FInstruction::fsubs),
+            (0x08, _, _) => Some(FInstruction::fmuls),
+            (0x0c, _, _) => Some(FInstruction::fdivs),
+            (0x2c, _, 0) => Some(FInstruction::fsqrts),
+
+            (0x10, 0b000, _) => Some(FInstruction::fsgnjs),
+            (0x10, 0b001, _) => Some(FInstruction::fsgnjns),
+            (0x10, 0b010, _) => Some(FInstruction::fsgnjxs),
+            (0x14, 0b000, _) => Some(FInstruction::fmins),
+            (0x14, 0b001, _) => Some(FInstruction::fmaxs),
+
+            (0x60, _, 0) => Some(FInstruction::fcvtws),
+            (0x60, _, 1) => Some(FInstruction::fcvtwus),
+            (0x68, _, 0) => Some(FInstruction::fcvtsw),
+            (0x68, _, 1) => Some(FInstruction::fcvtswu),
+            (0x70, 0b000, 0) => Some(FInstruction::fmvxw),
+            (0x78, 0b000, 0) => Some(FInstruction::fmvwx),
+
+            (0x50, 0b010, _) => Some(FInstruction::feqs),
+            (0x50, 0b001, _) => Some(FInstruction::flts),
+            (0x50, 0b000, _) => Some(FInstruction::fles),
+            (0x70, 0b001, 0) => Some(FInstruction::fclasss),
+
+            (0x01, _, _) => Some(FInstruction::faddd),
+            (0x05, _, _) => Some(FInstruction::fsubd),
+            (0x09, _, _) => Some(FInstruction::fmuld),
+            (0x0d, _, _) => Some(FInstruction::fdivd),
+            (0x2d, _, 0) => Some(FInstruction::fsqrtd),
+
+            (0x11, 0b000, _) => Some(FInstruction::fsgnjd),
+            (0x11, 0b001, _) => Some(FInstruction::fsgnjnd),
+            (0x11, 0b010, _) => Some(FInstruction::fsgnjxd),
+            (0x15, 0b000, _) => Some(FInstruction::fmind),
+            (0x15, 0b001, _) => Some(FInstruction::fmaxd),
+
+            (0x20, _, 1) => Some(FInstruction::fcvtsd),
+            (0x21, _, 0) => Some(FInstruction::fcvtds),
+            (0x61, _, 0) => Some(FInstruction::fcvtwd),
+            (0x61, _, 1) => Some(FInstruction::fcvtwud),
+            (0x69, _, 0) => Some(FInstruction::fcvtdw),
+            (0x69, _, 1) => Some(FInstruction::fcvtdwu),
+
+            (0x51, 0b010, _) => Some(FInstruction::feqd),
+            (0x51, 0b001, _) => Some(FInstruction::fltd),
+            (0x51, 0b000, _) => Some(FInstruction::fled),
+            (0x71, 0b001, 0) => Some(FInstruction::fclassd),
+
+            _ => None,
+        }
+    }
+
+    // Instructions of the D extension
+    pub fn is_double(&self) -> bool {
+        matches!(
+            self,
+            FInstruction::fmaddd
+                | FInstruction::fmsubd
+                | FInstruction::fnmsubd
+                | FInstruction::fnmaddd
+                | FInstruction::faddd
+                | FInstruction::fsubd
+                | FInstruction::fmuld
+                | FInstruction::fdivd
+                | FInstruction::fsqrtd
+                | FInstruction::fsgnjd
+                | FInstruction::fsgnjnd
+                | FInstruction::fsgnjxd
+                | FInstruction::fmind
+                | FInstruction::fmaxd
+                | FInstruction::fcvtsd
+                | FInstruction::fcvtds
+                | FInstruction::fcvtwd
+                | FInstruction::fcvtwud
+                | FInstruction::fcvtdw
+                | FInstruction::fcvtdwu
+                | FInstruction::feqd
+                | FInstruction::fltd
+                | FInstruction::fled
+                | FInstruction::fclassd
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[allow(clippy::enum_variant_names)]
 pub enum Instruction {
     RType {
         rd: usize,
@@ -274,6 +535,16 @@ pub enum Instruction {
         rd: usize,
         inst: UJInstruction,
     },
+    // Register numbers are floating-point or integer ones depending on the
+    // instruction, rm is funct3 and only used by instructions that round
+    FType {
+        rd: usize,
+        rs1: usize,
+        rs2: usize,
+        rs3: usize,
+        rm: u32,
+        inst: FInstruction,
+    },
 }
 
 pub fn pretty_register(num: &usize) -> &str {
@@ -326,6 +597,20 @@ impl fmt::Display for Instruction {
             Self::UJType { imm, rd, inst } => {
                 write!(f, "{:?} - {}, {:#x} (U/J)", inst, pretty_register(rd), imm)
             }
+            Self::FType {
+                rd,
+                rs1,
+                rs2,
+                rs3,
+                rm,
+                inst,
+            } => {
+                write!(
+                    f,
+                    "{:?} - {}, {}, {}, {}, rm {} (F)",
+                    inst, rd, rs1, rs2, rs3, rm
+                )
+            }
         }
     }
 }
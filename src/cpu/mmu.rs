@@ -0,0 +1,399 @@
+use tracing::debug;
+
+use super::csr::{ArchCSRs, CSRFile};
+use super::ExecMode;
+use crate::bus::{Bus, BusDevice, BusError};
+use crate::trap::RVException;
+
+pub const PAGE_SHIFT: usize = 12;
+pub const PAGE_SIZE: usize = 1 << PAGE_SHIFT;
+const PTE_SIZE: usize = 4;
+const TLB_SIZE: usize = 64;
+
+// Page table entry flags
+const PTE_V: u32 = 1 << 0;
+const PTE_R: u32 = 1 << 1;
+const PTE_W: u32 = 1 << 2;
+const PTE_X: u32 = 1 << 3;
+const PTE_U: u32 = 1 << 4;
+const PTE_A: u32 = 1 << 6;
+const PTE_D: u32 = 1 << 7;
+
+// mstatus fields that influence translation
+const MSTATUS_SUM: u32 = 1 << 18;
+const MSTATUS_MXR: u32 = 1 << 19;
+
+const SATP_MODE_SV32: u32 = 1 << 31;
+const SATP_PPN: u32 = 0x003f_ffff;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AccessType {
+    Fetch,
+    Load,
+    Store,
+}
+
+impl AccessType {
+    pub fn page_fault(&self, addr: usize) -> RVException {
+        match self {
+            Self::Fetch => RVException::InstructionPageFault(addr),
+            Self::Load => RVException::LoadPageFault(addr),
+            Self::Store => RVException::StorePageFault(addr),
+        }
+    }
+
+    pub fn access_fault(&self, addr: usize) -> RVException {
+        match self {
+            Self::Fetch => RVException::InstructionAccessFault(addr),
+            Self::Load => RVException::LoadAccessFault(addr),
+            Self::Store => RVException::StoreAccessFault(addr),
+        }
+    }
+
+    pub fn misaligned(&self, addr: usize) -> RVException {
+        match self {
+            Self::Fetch => RVException::InstructionAddressMisaligned(addr),
+            Self::Load => RVException::LoadAddressMisaligned(addr),
+            Self::Store => RVException::StoreAddressMisaligned(addr),
+        }
+    }
+
+    // Turn a physical bus error into an exception for the virtual address
+    pub fn bus_error(&self, e: BusError, vaddr: usize) -> RVException {
+        match e {
+            BusError::AddressMisaligned(_) => self.misaligned(vaddr),
+            BusError::AddressUnmapped(_) => self.access_fault(vaddr),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct TlbEntry {
+    vpn: usize,
+    // Physical page number of the 4 KiB page, also for megapages
+    ppn: usize,
+    pte: u32,
+}
+
+pub struct Mmu {
+    tlb: [Option<TlbEntry>; TLB_SIZE],
+}
+
+impl Mmu {
+    pub fn new() -> Self {
+        Self {
+            tlb: [None; TLB_SIZE],
+        }
+    }
+
+    // sfence.vma: drop a single page or the whole TLB
+    pub fn flush(&mut self, vaddr: Option<usize>) {
+        match vaddr {
+            Some(vaddr) => {
+                let vpn = vaddr >> PAGE_SHIFT;
+                let slot = &mut self.tlb[vpn % TLB_SIZE];
+                if matches!(slot, Some(entry) if entry.vpn == vpn) {
+                    *slot = None;
+                }
+            }
+            None => self.tlb = [None; TLB_SIZE],
+        }
+    }
+
+    pub fn translate(
+        &mut self,
+        bus: &mut Bus,
+        csrfile: &CSRFile,
+        mode: &ExecMode,
+        vaddr: usize,
+        access: AccessType,
+    ) -> Result<usize, RVException> {
+        let mstatus = csrfile.read(ArchCSRs::mstatus as i32) as u32;
+        let satp = csrfile.read(ArchCSRs::satp as i32) as u32;
+
+        if *mode == ExecMode::MACHINE || satp & SATP_MODE_SV32 == 0 {
+            return Ok(vaddr);
+        }
+
+        let vpn = vaddr >> PAGE_SHIFT;
+        let offset = vaddr & (PAGE_SIZE - 1);
+
+        // Stores to pages that are not yet dirty go through the walker to set D
+        if let Some(entry) = self.tlb[vpn % TLB_SIZE] {
+            if entry.vpn == vpn && (access != AccessType::Store || entry.pte & PTE_D != 0) {
+                if !has_permission(entry.pte, mode, mstatus, access) {
+                    return Err(access.page_fault(vaddr));
+                }
+                return Ok(entry.ppn << PAGE_SHIFT | offset);
+            }
+        }
+
+        let (mut entry, pte_addr) = walk(bus, csrfile, satp, vaddr, access)?;
+        if !has_permission(entry.pte, mode, mstatus, access) {
+            return Err(access.page_fault(vaddr));
+        }
+
+        // Only permitted accesses mark the page as accessed or dirty
+        let mut updated = entry.pte | PTE_A;
+        if access == AccessType::Store {
+            updated |= PTE_D;
+        }
+        if updated != entry.pte {
+            if !csrfile
+                .pmp
+                .check(pte_addr, PTE_SIZE, AccessType::Store, &ExecMode::SUPERVISOR)
+            {
+                return Err(access.access_fault(vaddr));
+            }
+            bus.store::<u32>(pte_addr, updated)
+                .map_err(|_| access.access_fault(vaddr))?;
+            entry.pte = updated;
+        }
+        self.tlb[vpn % TLB_SIZE] = Some(entry);
+
+        Ok(entry.ppn << PAGE_SHIFT | offset)
+    }
+}
+
+impl Default for Mmu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Walk the two-level Sv32 page table and return the leaf with its address
+fn walk(
+    bus: &mut Bus,
+    csrfile: &CSRFile,
+    satp: u32,
+    vaddr: usize,
+    access: AccessType,
+) -> Result<(TlbEntry, usize), RVException> {
+    let vpn = [(vaddr >> 12) & 0x3ff, (vaddr >> 22) & 0x3ff];
+
+    let mut table = ((satp & SATP_PPN) as usize) << PAGE_SHIFT;
+    let mut level = 1;
+    let (pte, pte_addr) = loop {
+        let pte_addr = table + vpn[level] * PTE_SIZE;
+        // Page table accesses are checked by the PMP as if they came from S-mode
+        if !csrfile
+            .pmp
+            .check(pte_addr, PTE_SIZE, AccessType::Load, &ExecMode::SUPERVISOR)
+        {
+            return Err(access.access_fault(vaddr));
+        }
+        let pte = bus
+            .load::<u32>(pte_addr)
+            .map_err(|_| access.access_fault(vaddr))?;
+
+        // Invalid entry or reserved R/W combination
+        if pte & PTE_V == 0 || (pte & PTE_R == 0 && pte & PTE_W != 0) {
+            return Err(access.page_fault(vaddr));
+        }
+        // Leaf entry
+        if pte & (PTE_R | PTE_X) != 0 {
+            break (pte, pte_addr);
+        }
+        if level == 0 {
+            return Err(access.page_fault(vaddr));
+        }
+        level -= 1;
+        table = ((pte >> 10) as usize) << PAGE_SHIFT;
+    };
+
+    let ppn = (pte >> 10) as usize;
+    let ppn = if level == 1 {
+        // Megapages must be aligned to 4 MiB
+        if ppn & 0x3ff != 0 {
+            return Err(access.page_fault(vaddr));
+        }
+        ppn | vpn[0]
+    } else {
+        ppn
+    };
+    debug!(
+        "Translated {:#010x} to page {:#010x} (PTE {:#010x})",
+        vaddr,
+        ppn << PAGE_SHIFT,
+        pte
+    );
+
+    Ok((
+        TlbEntry {
+            vpn: vaddr >> PAGE_SHIFT,
+            ppn,
+            pte,
+        },
+        pte_addr,
+    ))
+}
+
+fn has_permission(pte: u32, mode: &ExecMode, mstatus: u32, access: AccessType) -> bool {
+    let allowed = match access {
+        AccessType::Fetch => pte & PTE_X != 0,
+        // MXR makes executable pages readable
+        AccessType::Load => pte & PTE_R != 0 || (mstatus & MSTATUS_MXR != 0 && pte & PTE_X != 0),
+        AccessType::Store => pte & PTE_W != 0,
+    };
+    let privileged = match mode {
+        ExecMode::USER => pte & PTE_U != 0,
+        // S-mode may only touch user pages with SUM set and never execute them
+        ExecMode::SUPERVISOR => {
+            pte & PTE_U == 0 || (access != AccessType::Fetch && mstatus & MSTATUS_SUM != 0)
+        }
+        ExecMode::MACHINE => true,
+    };
+    allowed && privileged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RAM_START: usize = 0x8000_0000;
+    const ROOT: usize = RAM_START + 0x1000;
+    const LEAF: usize = RAM_START + 0x2000;
+
+    // Root table with a user megapage at 0x4000_0000 -> 0x8000_0000
+    // and a pointer to a second-level table for 0x0000_0000
+    fn setup() -> (Mmu, Bus, CSRFile) {
+        let mut bus = Bus::new(vec![0u8; 0x4000], RAM_START).unwrap();
+        let mut csrfile = CSRFile::new();
+        csrfile.write(
+            ArchCSRs::satp as i32,
+            (SATP_MODE_SV32 | (ROOT >> PAGE_SHIFT) as u32) as i32,
+        );
+        // Let the page table walker through the PMP
+        csrfile.write(ArchCSRs::pmpaddr0 as i32, -1);
+        csrfile.write(ArchCSRs::pmpcfg0 as i32, 0x1f);
+
+        let mega = ((RAM_START >> PAGE_SHIFT) << 10) as u32;
+        bus.store::<u32>(ROOT + 0x100 * 4, mega | PTE_U | PTE_R | PTE_W | PTE_V)
+            .unwrap();
+        bus.store::<u32>(ROOT, ((LEAF >> PAGE_SHIFT) << 10) as u32 | PTE_V)
+            .unwrap();
+        // 0x0000_3000 -> 0x8000_3000, supervisor read-only
+        let page = (((RAM_START + 0x3000) >> PAGE_SHIFT) << 10) as u32;
+        bus.store::<u32>(LEAF + 3 * 4, page | PTE_R | PTE_X | PTE_V)
+            .unwrap();
+        (Mmu::new(), bus, csrfile)
+    }
+
+    #[test]
+    fn test_translate() {
+        let (mut dut, mut bus, csrfile) = setup();
+        let mode = ExecMode::SUPERVISOR;
+
+        assert_eq!(
+            dut.translate(&mut bus, &csrfile, &mode, 0x0000_3abc, AccessType::Load),
+            Ok(0x8000_3abc)
+        );
+        assert_eq!(
+            dut.translate(&mut bus, &csrfile, &mode, 0x0000_3abc, AccessType::Fetch),
+            Ok(0x8000_3abc)
+        );
+        assert_eq!(
+            dut.translate(&mut bus, &csrfile, &mode, 0x0000_3abc, AccessType::Store),
+            Err(RVException::StorePageFault(0x0000_3abc))
+        );
+        assert_eq!(
+            dut.translate(&mut bus, &csrfile, &mode, 0x0000_4000, AccessType::Load),
+            Err(RVException::LoadPageFault(0x0000_4000))
+        );
+        // M-mode is never translated
+        assert_eq!(
+            dut.translate(
+                &mut bus,
+                &csrfile,
+                &ExecMode::MACHINE,
+                0x1234,
+                AccessType::Load
+            ),
+            Ok(0x1234)
+        );
+    }
+    #[test]
+    fn test_user_pages() {
+        let (mut dut, mut bus, mut csrfile) = setup();
+
+        assert_eq!(
+            dut.translate(
+                &mut bus,
+                &csrfile,
+                &ExecMode::USER,
+                0x4000_1004,
+                AccessType::Load
+            ),
+            Ok(0x8000_1004)
+        );
+        assert_eq!(
+            dut.translate(
+                &mut bus,
+                &csrfile,
+                &ExecMode::USER,
+                0x0000_3000,
+                AccessType::Load
+            ),
+            Err(RVException::LoadPageFault(0x0000_3000))
+        );
+        // S-mode needs SUM to access user pages
+        assert_eq!(
+            dut.translate(
+                &mut bus,
+                &csrfile,
+                &ExecMode::SUPERVISOR,
+                0x4000_1004,
+                AccessType::Load
+            ),
+            Err(RVException::LoadPageFault(0x4000_1004))
+        );
+        csrfile.write(ArchCSRs::mstatus as i32, MSTATUS_SUM as i32);
+        assert_eq!(
+            dut.translate(
+                &mut bus,
+                &csrfile,
+                &ExecMode::SUPERVISOR,
+                0x4000_1004,
+                AccessType::Load
+            ),
+            Ok(0x8000_1004)
+        );
+    }
+    #[test]
+    fn test_accessed_dirty() {
+        let (mut dut, mut bus, csrfile) = setup();
+        let mode = ExecMode::USER;
+        let pte_addr = ROOT + 0x100 * 4;
+
+        dut.translate(&mut bus, &csrfile, &mode, 0x4000_0000, AccessType::Load)
+            .unwrap();
+        assert_eq!(bus.load::<u32>(pte_addr).unwrap() & (PTE_A | PTE_D), PTE_A);
+
+        // The cached entry is not dirty yet, so the store has to walk again
+        dut.translate(&mut bus, &csrfile, &mode, 0x4000_0000, AccessType::Store)
+            .unwrap();
+        assert_eq!(
+            bus.load::<u32>(pte_addr).unwrap() & (PTE_A | PTE_D),
+            PTE_A | PTE_D
+        );
+    }
+    #[test]
+    fn test_flush() {
+        let (mut dut, mut bus, csrfile) = setup();
+        let mode = ExecMode::SUPERVISOR;
+
+        dut.translate(&mut bus, &csrfile, &mode, 0x0000_3000, AccessType::Load)
+            .unwrap();
+        // Remap the page behind the TLB's back
+        bus.store::<u32>(LEAF + 3 * 4, 0).unwrap();
+        assert_eq!(
+            dut.translate(&mut bus, &csrfile, &mode, 0x0000_3000, AccessType::Load),
+            Ok(0x8000_3000)
+        );
+        dut.flush(Some(0x0000_3000));
+        assert_eq!(
+            dut.translate(&mut bus, &csrfile, &mode, 0x0000_3000, AccessType::Load),
+            Err(RVException::LoadPageFault(0x0000_3000))
+        );
+    }
+}
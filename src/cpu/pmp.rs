@@ -0,0 +1,219 @@
+use super::mmu::AccessType;
+use super::ExecMode;
+
+pub const PMP_ENTRIES: usize = 16;
+
+// pmpcfg fields
+const PMP_R: u8 = 1 << 0;
+const PMP_W: u8 = 1 << 1;
+const PMP_X: u8 = 1 << 2;
+const PMP_A: u8 = 0b11 << 3;
+const PMP_L: u8 = 1 << 7;
+
+// Address matching modes in the A field
+const PMP_A_OFF: u8 = 0b00;
+const PMP_A_TOR: u8 = 0b01;
+const PMP_A_NA4: u8 = 0b10;
+const PMP_A_NAPOT: u8 = 0b11;
+
+pub struct Pmp {
+    cfg: [u8; PMP_ENTRIES],
+    addr: [u32; PMP_ENTRIES],
+}
+
+impl Pmp {
+    pub fn new() -> Self {
+        Self {
+            cfg: [0; PMP_ENTRIES],
+            addr: [0; PMP_ENTRIES],
+        }
+    }
+
+    fn is_locked(&self, entry: usize) -> bool {
+        self.cfg[entry] & PMP_L != 0
+    }
+
+    fn mode(&self, entry: usize) -> u8 {
+        (self.cfg[entry] & PMP_A) >> 3
+    }
+
+    // Each pmpcfg register packs the configuration of four entries
+    pub fn read_cfg(&self, reg: usize) -> u32 {
+        self.cfg[reg * 4..reg * 4 + 4]
+            .iter()
+            .rev()
+            .fold(0, |word, &cfg| word << 8 | cfg as u32)
+    }
+
+    pub fn write_cfg(&mut self, reg: usize, value: u32) {
+        for (i, byte) in value.to_le_bytes().iter().enumerate() {
+            let entry = reg * 4 + i;
+            if self.is_locked(entry) {
+                continue;
+            }
+            // Bits 5 and 6 are reserved and W without R is a reserved combination
+            let mut cfg = byte & (PMP_L | PMP_A | PMP_X | PMP_W | PMP_R);
+            if cfg & PMP_R == 0 {
+                cfg &= !PMP_W;
+            }
+            self.cfg[entry] = cfg;
+        }
+    }
+
+    pub fn read_addr(&self, entry: usize) -> u32 {
+        self.addr[entry]
+    }
+
+    pub fn write_addr(&mut self, entry: usize, value: u32) {
+        // A locked TOR entry also locks the address register below it
+        let next_locks_tor = entry + 1 < PMP_ENTRIES
+            && self.is_locked(entry + 1)
+            && self.mode(entry + 1) == PMP_A_TOR;
+        if self.is_locked(entry) || next_locks_tor {
+            return;
+        }
+        self.addr[entry] = value;
+    }
+
+    // Physical address range [start, end) covered by an entry
+    fn range(&self, entry: usize) -> Option<(u64, u64)> {
+        // pmpaddr holds bits 33:2 of the address
+        let addr = (self.addr[entry] as u64) << 2;
+        match self.mode(entry) {
+            PMP_A_OFF => None,
+            PMP_A_TOR => {
+                let start = if entry == 0 {
+                    0
+                } else {
+                    (self.addr[entry - 1] as u64) << 2
+                };
+                Some((start, addr))
+            }
+            PMP_A_NA4 => Some((addr, addr + 4)),
+            PMP_A_NAPOT => {
+                // The number of trailing ones encodes the size of the region
+                let size = 1u64 << (self.addr[entry].trailing_ones() + 3);
+                let start = addr & !(size - 1);
+                Some((start, start + size))
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn check(&self, addr: usize, size: usize, access: AccessType, mode: &ExecMode) -> bool {
+        let start = addr as u64;
+        let end = start + size as u64;
+
+        // The lowest-numbered entry that matches any byte of the access decides
+        for entry in 0..PMP_ENTRIES {
+            let (lower, upper) = match self.range(entry) {
+                Some(range) => range,
+                None => continue,
+            };
+            if end <= lower || start >= upper {
+                continue;
+            }
+            // Accesses that only partially match an entry always fail
+            if start < lower || end > upper {
+                return false;
+            }
+            // Unlocked entries do not apply to M-mode
+            if *mode == ExecMode::MACHINE && !self.is_locked(entry) {
+                return true;
+            }
+            let permission = match access {
+                AccessType::Fetch => PMP_X,
+                AccessType::Load => PMP_R,
+                AccessType::Store => PMP_W,
+            };
+            return self.cfg[entry] & permission != 0;
+        }
+
+        // Without a match only M-mode has access
+        *mode == ExecMode::MACHINE
+    }
+}
+
+impl Default for Pmp {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tor() {
+        let mut dut = Pmp::new();
+        dut.write_addr(0, 0x8000_0000 >> 2);
+        dut.write_addr(1, 0x8000_1000 >> 2);
+        // Entry 1: TOR, R+X
+        dut.write_cfg(0, ((PMP_A_TOR << 3 | PMP_R | PMP_X) as u32) << 8);
+
+        let user = ExecMode::USER;
+        assert!(dut.check(0x8000_0000, 4, AccessType::Load, &user));
+        assert!(dut.check(0x8000_0ffc, 4, AccessType::Fetch, &user));
+        assert!(!dut.check(0x8000_0000, 4, AccessType::Store, &user));
+        assert!(!dut.check(0x8000_1000, 4, AccessType::Load, &user));
+        assert!(!dut.check(0x7fff_fffc, 4, AccessType::Load, &user));
+        // Unlocked entries and unmatched addresses are fine for M-mode
+        assert!(dut.check(0x8000_0000, 4, AccessType::Store, &ExecMode::MACHINE));
+        assert!(dut.check(0x1000_0000, 4, AccessType::Store, &ExecMode::MACHINE));
+    }
+    #[test]
+    fn test_na4_napot() {
+        let mut dut = Pmp::new();
+        // Entry 0: NA4 at 0x1000_0000, no permissions
+        dut.write_addr(0, 0x1000_0000 >> 2);
+        // Entry 1: 4 KiB NAPOT at 0x8000_0000, R+W
+        dut.write_addr(1, (0x8000_0000 >> 2) | 0x1ff);
+        dut.write_cfg(
+            0,
+            (PMP_A_NA4 << 3) as u32 | ((PMP_A_NAPOT << 3 | PMP_R | PMP_W) as u32) << 8,
+        );
+
+        let user = ExecMode::SUPERVISOR;
+        assert!(!dut.check(0x1000_0000, 1, AccessType::Load, &user));
+        assert!(!dut.check(0x1000_0004, 1, AccessType::Load, &user));
+        assert!(dut.check(0x8000_0000, 4, AccessType::Store, &user));
+        assert!(dut.check(0x8000_0ffc, 4, AccessType::Load, &user));
+        assert!(!dut.check(0x8000_0ffc, 4, AccessType::Fetch, &user));
+        assert!(!dut.check(0x8000_1000, 4, AccessType::Load, &user));
+    }
+    #[test]
+    fn test_lock() {
+        let mut dut = Pmp::new();
+        dut.write_addr(0, 0x8000_0000 >> 2);
+        dut.write_addr(1, 0x8000_1000 >> 2);
+        // Entry 1: locked TOR, read-only
+        dut.write_cfg(0, ((PMP_L | PMP_A_TOR << 3 | PMP_R) as u32) << 8);
+
+        // Locked entries are enforced in M-mode as well
+        let machine = ExecMode::MACHINE;
+        assert!(dut.check(0x8000_0000, 4, AccessType::Load, &machine));
+        assert!(!dut.check(0x8000_0000, 4, AccessType::Store, &machine));
+
+        // Neither the entry nor the TOR base below it can be changed
+        dut.write_cfg(0, 0);
+        dut.write_addr(0, 0);
+        dut.write_addr(1, 0);
+        assert_eq!(
+            dut.read_cfg(0),
+            ((PMP_L | PMP_A_TOR << 3 | PMP_R) as u32) << 8
+        );
+        assert_eq!(dut.read_addr(0), 0x8000_0000 >> 2);
+        assert_eq!(dut.read_addr(1), 0x8000_1000 >> 2);
+    }
+    #[test]
+    fn test_partial_match() {
+        let mut dut = Pmp::new();
+        dut.write_addr(0, 0x8000_0000 >> 2);
+        dut.write_cfg(0, (PMP_A_NA4 << 3 | PMP_R | PMP_W) as u32);
+
+        let user = ExecMode::USER;
+        assert!(dut.check(0x8000_0000, 4, AccessType::Load, &user));
+        assert!(!dut.check(0x7fff_fffe, 4, AccessType::Load, &user));
+    }
+}
@@ -26,3 +26,52 @@ impl RegFile {
     //     self.write(num, value as i32)
     // }
 }
+
+impl Default for RegFile {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Floating-point registers are as wide as a double, single-precision values
+// are NaN-boxed in the upper half
+#[derive(Debug)]
+pub struct FRegFile {
+    registers: [u64; 32],
+}
+
+const NAN_BOX: u64 = 0xffff_ffff_0000_0000;
+// Single-precision canonical NaN
+const CANONICAL_NAN: u32 = 0x7fc0_0000;
+
+impl FRegFile {
+    pub fn new() -> Self {
+        Self { registers: [0; 32] }
+    }
+
+    pub fn read(&self, num: usize) -> u64 {
+        self.registers[num]
+    }
+
+    pub fn write(&mut self, num: usize, value: u64) {
+        self.registers[num] = value;
+    }
+
+    // Values that aren't properly NaN-boxed read as the canonical NaN
+    pub fn read_single(&self, num: usize) -> u32 {
+        match self.registers[num] & NAN_BOX {
+            NAN_BOX => self.registers[num] as u32,
+            _ => CANONICAL_NAN,
+        }
+    }
+
+    pub fn write_single(&mut self, num: usize, value: u32) {
+        self.registers[num] = NAN_BOX | value as u64;
+    }
+}
+
+impl Default for FRegFile {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,716 @@
+use enum_primitive_derive::Primitive;
+
+// IEEE 754 binary floating point in software, so that all RISC-V rounding
+// modes and exception flags behave the same on every host. Values are passed
+// around as raw bits in the low bits of a u64.
+
+// Exception flags, in the same order as in fflags
+pub const FLAG_NX: u32 = 1 << 0;
+pub const FLAG_UF: u32 = 1 << 1;
+pub const FLAG_OF: u32 = 1 << 2;
+pub const FLAG_DZ: u32 = 1 << 3;
+pub const FLAG_NV: u32 = 1 << 4;
+
+// Encoded like the rm field of an instruction and frm
+#[derive(Debug, Clone, Copy, PartialEq, Primitive)]
+pub enum RoundingMode {
+    NearestEven = 0,
+    TowardZero = 1,
+    Down = 2,
+    Up = 3,
+    NearestMaxMagnitude = 4,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Format {
+    exp_bits: u32,
+    frac_bits: u32,
+}
+
+pub const SINGLE: Format = Format {
+    exp_bits: 8,
+    frac_bits: 23,
+};
+
+pub const DOUBLE: Format = Format {
+    exp_bits: 11,
+    frac_bits: 52,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Value {
+    Zero,
+    // sig * 2^exp
+    Finite(i32, u128),
+    Inf,
+    QuietNaN,
+    SignalingNaN,
+}
+
+impl Value {
+    fn is_nan(self) -> bool {
+        matches!(self, Value::QuietNaN | Value::SignalingNaN)
+    }
+}
+
+// Drop the bits of sig * 2^exp below 2^quantum, rounding the rest. Returns the
+// kept bits, which can carry into one more bit, and whether it was inexact.
+fn round_at(sign: bool, exp: i32, sig: u128, quantum: i32, rm: RoundingMode) -> (u128, bool) {
+    let shift = quantum - exp;
+    if shift <= 0 {
+        return (sig << -shift, false);
+    }
+    let (kept, round, sticky) = match shift {
+        1..=127 => (
+            sig >> shift,
+            (sig >> (shift - 1)) & 1 != 0,
+            sig & ((1 << (shift - 1)) - 1) != 0,
+        ),
+        128 => (0, sig >> 127 != 0, sig & (u128::MAX >> 1) != 0),
+        _ => (0, false, sig != 0),
+    };
+    let increment = match rm {
+        RoundingMode::NearestEven => round && (sticky || kept & 1 != 0),
+        RoundingMode::TowardZero => false,
+        RoundingMode::Down => sign && (round || sticky),
+        RoundingMode::Up => !sign && (round || sticky),
+        RoundingMode::NearestMaxMagnitude => round,
+    };
+    (kept + increment as u128, round || sticky)
+}
+
+// Integer square root and remainder, one result bit at a time
+fn isqrt(n: u128) -> (u128, u128) {
+    let mut remainder = n;
+    let mut root = 0;
+    let mut bit = 1 << 126;
+    while bit > n {
+        bit >>= 2;
+    }
+    while bit != 0 {
+        if remainder >= root + bit {
+            remainder -= root + bit;
+            root = (root >> 1) + bit;
+        } else {
+            root >>= 1;
+        }
+        bit >>= 2;
+    }
+    (root, remainder)
+}
+
+impl Format {
+    fn bias(self) -> i32 {
+        (1 << (self.exp_bits - 1)) - 1
+    }
+
+    fn max_exp(self) -> u64 {
+        (1 << self.exp_bits) - 1
+    }
+
+    pub fn sign_bit(self) -> u64 {
+        1 << (self.exp_bits + self.frac_bits)
+    }
+
+    fn frac_mask(self) -> u64 {
+        (1 << self.frac_bits) - 1
+    }
+
+    // The NaN that RISC-V returns for every operation that produces a NaN
+    pub fn canonical_nan(self) -> u64 {
+        self.max_exp() << self.frac_bits | 1 << (self.frac_bits - 1)
+    }
+
+    fn zero(self, sign: bool) -> u64 {
+        if sign {
+            self.sign_bit()
+        } else {
+            0
+        }
+    }
+
+    fn inf(self, sign: bool) -> u64 {
+        self.zero(sign) | self.max_exp() << self.frac_bits
+    }
+
+    // Largest finite value
+    fn max_finite(self, sign: bool) -> u64 {
+        self.inf(sign) - 1
+    }
+
+    fn unpack(self, bits: u64) -> (bool, Value) {
+        let sign = bits & self.sign_bit() != 0;
+        let exp = (bits >> self.frac_bits) & self.max_exp();
+        let frac = bits & self.frac_mask();
+        let min_exp = 1 - self.bias() - self.frac_bits as i32;
+        let value = match (exp, frac) {
+            (0, 0) => Value::Zero,
+            (0, _) => Value::Finite(min_exp, frac as u128),
+            (exp, 0) if exp == self.max_exp() => Value::Inf,
+            (exp, _) if exp == self.max_exp() => match frac >> (self.frac_bits - 1) {
+                0 => Value::SignalingNaN,
+                _ => Value::QuietNaN,
+            },
+            (exp, _) => Value::Finite(
+                min_exp + exp as i32 - 1,
+                (frac | 1 << self.frac_bits) as u128,
+            ),
+        };
+        (sign, value)
+    }
+
+    // Round sig * 2^exp to this format. sig has to be non-zero and, if some of
+    // its bits were shifted out, have the lowest bit set (jammed) with at least
+    // two more bits than the format's precision.
+    fn round_pack(self, sign: bool, exp: i32, sig: u128, rm: RoundingMode) -> (u64, u32) {
+        let precision = self.frac_bits as i32 + 1;
+        let min_exp = 1 - self.bias();
+        // The value is in [2^top, 2^(top + 1))
+        let top = exp + 127 - sig.leading_zeros() as i32;
+        let mut quantum = top.max(min_exp) - (precision - 1);
+        let (mut kept, inexact) = round_at(sign, exp, sig, quantum, rm);
+        if kept >> precision != 0 {
+            kept >>= 1;
+            quantum += 1;
+        }
+
+        let mut flags = if inexact { FLAG_NX } else { 0 };
+        // Tininess is detected after rounding: the result is tiny if rounding
+        // to full precision with an unbounded exponent stays below 2^min_exp
+        if inexact && top < min_exp {
+            let tiny = top < min_exp - 1 || {
+                let (unbounded, _) = round_at(sign, exp, sig, top - (precision - 1), rm);
+                unbounded >> precision == 0
+            };
+            if tiny {
+                flags |= FLAG_UF;
+            }
+        }
+
+        let biased = match kept >> (precision - 1) {
+            0 => 0,
+            _ => (quantum + precision - 1 + self.bias()) as u64,
+        };
+        if biased >= self.max_exp() {
+            let inf = match rm {
+                RoundingMode::NearestEven | RoundingMode::NearestMaxMagnitude => true,
+                RoundingMode::TowardZero => false,
+                RoundingMode::Down => sign,
+                RoundingMode::Up => !sign,
+            };
+            let result = match inf {
+                true => self.inf(sign),
+                false => self.max_finite(sign),
+            };
+            return (result, FLAG_OF | FLAG_NX);
+        }
+        let bits = self.zero(sign) | biased << self.frac_bits | kept as u64 & self.frac_mask();
+        (bits, flags)
+    }
+
+    // Canonical NaN, invalid if any of the operands is a signaling NaN
+    fn propagate_nan(self, values: &[Value]) -> (u64, u32) {
+        let invalid = values.contains(&Value::SignalingNaN);
+        (self.canonical_nan(), if invalid { FLAG_NV } else { 0 })
+    }
+
+    fn invalid(self) -> (u64, u32) {
+        (self.canonical_nan(), FLAG_NV)
+    }
+
+    // Exact sum of two non-zero finite values, rounded once
+    fn add_finite(
+        &self,
+        (sign_a, exp_a, sig_a): (bool, i32, u128),
+        (sign_b, exp_b, sig_b): (bool, i32, u128),
+        rm: RoundingMode,
+    ) -> (u64, u32) {
+        // Line both up at bit 125, which leaves room for the carry
+        let normalize = |exp: i32, sig: u128| {
+            let shift = sig.leading_zeros() as i32 - 2;
+            (exp - shift, sig << shift)
+        };
+        let (exp_a, sig_a) = normalize(exp_a, sig_a);
+        let (exp_b, sig_b) = normalize(exp_b, sig_b);
+        let ((sign_a, exp_a, sig_a), (sign_b, exp_b, sig_b)) = if exp_a >= exp_b {
+            ((sign_a, exp_a, sig_a), (sign_b, exp_b, sig_b))
+        } else {
+            ((sign_b, exp_b, sig_b), (sign_a, exp_a, sig_a))
+        };
+        let shift = exp_a - exp_b;
+        let sig_b = match shift {
+            0..=127 => sig_b >> shift | (sig_b & ((1 << shift) - 1) != 0) as u128,
+            _ => 1,
+        };
+
+        if sign_a == sign_b {
+            self.round_pack(sign_a, exp_a, sig_a + sig_b, rm)
+        } else if sig_a > sig_b {
+            self.round_pack(sign_a, exp_a, sig_a - sig_b, rm)
+        } else if sig_b > sig_a {
+            self.round_pack(sign_b, exp_a, sig_b - sig_a, rm)
+        } else {
+            (self.zero(rm == RoundingMode::Down), 0)
+        }
+    }
+
+    pub fn add(self, a: u64, b: u64, rm: RoundingMode) -> (u64, u32) {
+        let (sign_a, value_a) = self.unpack(a);
+        let (sign_b, value_b) = self.unpack(b);
+        match (value_a, value_b) {
+            (a, b) if a.is_nan() || b.is_nan() => self.propagate_nan(&[a, b]),
+            (Value::Inf, Value::Inf) if sign_a != sign_b => self.invalid(),
+            (Value::Inf, _) => (self.inf(sign_a), 0),
+            (_, Value::Inf) => (self.inf(sign_b), 0),
+            (Value::Zero, Value::Zero) if sign_a == sign_b => (self.zero(sign_a), 0),
+            (Value::Zero, Value::Zero) => (self.zero(rm == RoundingMode::Down), 0),
+            (Value::Zero, _) => (b, 0),
+            (_, Value::Zero) => (a, 0),
+            (Value::Finite(exp_a, sig_a), Value::Finite(exp_b, sig_b)) => {
+                self.add_finite((sign_a, exp_a, sig_a), (sign_b, exp_b, sig_b), rm)
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn sub(self, a: u64, b: u64, rm: RoundingMode) -> (u64, u32) {
+        self.add(a, b ^ self.sign_bit(), rm)
+    }
+
+    pub fn mul(self, a: u64, b: u64, rm: RoundingMode) -> (u64, u32) {
+        let (sign_a, value_a) = self.unpack(a);
+        let (sign_b, value_b) = self.unpack(b);
+        let sign = sign_a != sign_b;
+        match (value_a, value_b) {
+            (a, b) if a.is_nan() || b.is_nan() => self.propagate_nan(&[a, b]),
+            (Value::Inf, Value::Zero) | (Value::Zero, Value::Inf) => self.invalid(),
+            (Value::Inf, _) | (_, Value::Inf) => (self.inf(sign), 0),
+            (Value::Zero, _) | (_, Value::Zero) => (self.zero(sign), 0),
+            (Value::Finite(exp_a, sig_a), Value::Finite(exp_b, sig_b)) => {
+                self.round_pack(sign, exp_a + exp_b, sig_a * sig_b, rm)
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn div(self, a: u64, b: u64, rm: RoundingMode) -> (u64, u32) {
+        let (sign_a, value_a) = self.unpack(a);
+        let (sign_b, value_b) = self.unpack(b);
+        let sign = sign_a != sign_b;
+        match (value_a, value_b) {
+            (a, b) if a.is_nan() || b.is_nan() => self.propagate_nan(&[a, b]),
+            (Value::Inf, Value::Inf) | (Value::Zero, Value::Zero) => self.invalid(),
+            (Value::Inf, _) => (self.inf(sign), 0),
+            (_, Value::Inf) | (Value::Zero, _) => (self.zero(sign), 0),
+            (_, Value::Zero) => (self.inf(sign), FLAG_DZ),
+            (Value::Finite(exp_a, sig_a), Value::Finite(exp_b, sig_b)) => {
+                // Enough quotient bits for rounding, the remainder is jammed
+                let shift = sig_a.leading_zeros() as i32 - 2;
+                let dividend = sig_a << shift;
+                let quotient = (dividend / sig_b) | !dividend.is_multiple_of(sig_b) as u128;
+                self.round_pack(sign, exp_a - shift - exp_b, quotient, rm)
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn sqrt(self, a: u64, rm: RoundingMode) -> (u64, u32) {
+        match self.unpack(a) {
+            (_, value) if value.is_nan() => self.propagate_nan(&[value]),
+            // sqrt(-0) is -0
+            (_, Value::Zero) => (a, 0),
+            (true, _) => self.invalid(),
+            (false, Value::Inf) => (a, 0),
+            (false, Value::Finite(exp, sig)) => {
+                // Shift by an amount that leaves an even exponent
+                let mut shift = sig.leading_zeros() as i32 - 2;
+                if (exp - shift) % 2 != 0 {
+                    shift -= 1;
+                }
+                let (root, remainder) = isqrt(sig << shift);
+                let root = root | (remainder != 0) as u128;
+                self.round_pack(false, (exp - shift) / 2, root, rm)
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    // a * b + c with a single rounding
+    pub fn fused_mul_add(self, a: u64, b: u64, c: u64, rm: RoundingMode) -> (u64, u32) {
+        let (sign_a, value_a) = self.unpack(a);
+        let (sign_b, value_b) = self.unpack(b);
+        let (sign_c, value_c) = self.unpack(c);
+        let sign = sign_a != sign_b;
+        let inf_times_zero = matches!(
+            (value_a, value_b),
+            (Value::Inf, Value::Zero) | (Value::Zero, Value::Inf)
+        );
+        // Invalid even if the addend is a quiet NaN
+        if inf_times_zero {
+            return self.invalid();
+        }
+        if value_a.is_nan() || value_b.is_nan() || value_c.is_nan() {
+            return self.propagate_nan(&[value_a, value_b, value_c]);
+        }
+        match (value_a, value_b, value_c) {
+            (Value::Inf, _, Value::Inf) | (_, Value::Inf, Value::Inf) if sign != sign_c => {
+                self.invalid()
+            }
+            (Value::Inf, _, _) | (_, Value::Inf, _) => (self.inf(sign), 0),
+            (_, _, Value::Inf) => (self.inf(sign_c), 0),
+            (Value::Zero, _, Value::Zero) | (_, Value::Zero, Value::Zero) => match sign == sign_c {
+                true => (self.zero(sign), 0),
+                false => (self.zero(rm == RoundingMode::Down), 0),
+            },
+            (Value::Zero, _, _) | (_, Value::Zero, _) => (c, 0),
+            (Value::Finite(exp_a, sig_a), Value::Finite(exp_b, sig_b), value_c) => {
+                let product = (sign, exp_a + exp_b, sig_a * sig_b);
+                match value_c {
+                    Value::Finite(exp_c, sig_c) => {
+                        self.add_finite(product, (sign_c, exp_c, sig_c), rm)
+                    }
+                    _ => self.round_pack(product.0, product.1, product.2, rm),
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    // Orders all non-NaN values, with both zeros being equal
+    fn order_key(self, bits: u64) -> i128 {
+        let magnitude = (bits & !self.sign_bit()) as i128;
+        if bits & self.sign_bit() != 0 {
+            -magnitude
+        } else {
+            magnitude
+        }
+    }
+
+    // Quiet comparison, only signaling NaNs are invalid
+    pub fn eq(self, a: u64, b: u64) -> (bool, u32) {
+        let (_, value_a) = self.unpack(a);
+        let (_, value_b) = self.unpack(b);
+        if value_a.is_nan() || value_b.is_nan() {
+            return (false, self.propagate_nan(&[value_a, value_b]).1);
+        }
+        (self.order_key(a) == self.order_key(b), 0)
+    }
+
+    // Signaling comparisons, any NaN is invalid
+    pub fn lt(self, a: u64, b: u64) -> (bool, u32) {
+        if self.unpack(a).1.is_nan() || self.unpack(b).1.is_nan() {
+            return (false, FLAG_NV);
+        }
+        (self.order_key(a) < self.order_key(b), 0)
+    }
+
+    pub fn le(self, a: u64, b: u64) -> (bool, u32) {
+        if self.unpack(a).1.is_nan() || self.unpack(b).1.is_nan() {
+            return (false, FLAG_NV);
+        }
+        (self.order_key(a) <= self.order_key(b), 0)
+    }
+
+    // minimumNumber and maximumNumber: a NaN only wins if both are NaNs, and
+    // -0 is smaller than +0
+    fn min_max(self, a: u64, b: u64, max: bool) -> (u64, u32) {
+        let (_, value_a) = self.unpack(a);
+        let (_, value_b) = self.unpack(b);
+        let flags = self.propagate_nan(&[value_a, value_b]).1;
+        match (value_a.is_nan(), value_b.is_nan()) {
+            (true, true) => (self.canonical_nan(), flags),
+            (true, false) => (b, flags),
+            (false, true) => (a, flags),
+            (false, false) => {
+                let (key_a, key_b) = (self.order_key(a), self.order_key(b));
+                let a_first = match key_a == key_b {
+                    true => (a & self.sign_bit() != 0) != max,
+                    false => (key_a < key_b) != max,
+                };
+                (if a_first { a } else { b }, 0)
+            }
+        }
+    }
+
+    pub fn min(self, a: u64, b: u64) -> (u64, u32) {
+        self.min_max(a, b, false)
+    }
+
+    pub fn max(self, a: u64, b: u64) -> (u64, u32) {
+        self.min_max(a, b, true)
+    }
+
+    // One-hot class mask as returned by fclass
+    pub fn classify(self, a: u64) -> u32 {
+        let (sign, value) = self.unpack(a);
+        let subnormal = (a >> self.frac_bits) & self.max_exp() == 0;
+        let class = match (value, sign) {
+            (Value::Inf, true) => 0,
+            (Value::Finite(..), true) if !subnormal => 1,
+            (Value::Finite(..), true) => 2,
+            (Value::Zero, true) => 3,
+            (Value::Zero, false) => 4,
+            (Value::Finite(..), false) if subnormal => 5,
+            (Value::Finite(..), false) => 6,
+            (Value::Inf, false) => 7,
+            (Value::SignalingNaN, _) => 8,
+            (Value::QuietNaN, _) => 9,
+        };
+        1 << class
+    }
+
+    // Round to an integer in [min, max]. NaNs and values that are out of range
+    // are invalid and saturate, NaNs to the maximum.
+    fn to_int(self, a: u64, rm: RoundingMode, min: i64, max: i64) -> (i64, u32) {
+        let (sign, value) = self.unpack(a);
+        let saturated = if sign { min } else { max };
+        let (magnitude, inexact) = match value {
+            Value::QuietNaN | Value::SignalingNaN => return (max, FLAG_NV),
+            Value::Inf => return (saturated, FLAG_NV),
+            Value::Zero => return (0, 0),
+            Value::Finite(exp, _) if exp > 64 => return (saturated, FLAG_NV),
+            Value::Finite(exp, sig) => round_at(sign, exp, sig, 0, rm),
+        };
+        let result = if sign {
+            -(magnitude as i128)
+        } else {
+            magnitude as i128
+        };
+        if result < min as i128 || result > max as i128 {
+            return (saturated, FLAG_NV);
+        }
+        (result as i64, if inexact { FLAG_NX } else { 0 })
+    }
+
+    pub fn to_i32(self, a: u64, rm: RoundingMode) -> (i32, u32) {
+        let (result, flags) = self.to_int(a, rm, i32::MIN as i64, i32::MAX as i64);
+        (result as i32, flags)
+    }
+
+    pub fn to_u32(self, a: u64, rm: RoundingMode) -> (u32, u32) {
+        let (result, flags) = self.to_int(a, rm, 0, u32::MAX as i64);
+        (result as u32, flags)
+    }
+
+    pub fn from_int(self, value: i64, rm: RoundingMode) -> (u64, u32) {
+        match value {
+            0 => (0, 0),
+            _ => self.round_pack(value < 0, 0, value.unsigned_abs() as u128, rm),
+        }
+    }
+
+    // Convert a value in another format to this one
+    pub fn convert(self, from: Format, a: u64, rm: RoundingMode) -> (u64, u32) {
+        match from.unpack(a) {
+            (_, value) if value.is_nan() => self.propagate_nan(&[value]),
+            (sign, Value::Zero) => (self.zero(sign), 0),
+            (sign, Value::Inf) => (self.inf(sign), 0),
+            (sign, Value::Finite(exp, sig)) => self.round_pack(sign, exp, sig, rm),
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RNE: RoundingMode = RoundingMode::NearestEven;
+
+    fn single(value: f32) -> u64 {
+        value.to_bits() as u64
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        assert_eq!(
+            SINGLE.add(single(1.5), single(2.25), RNE),
+            (single(3.75), 0)
+        );
+        assert_eq!(
+            SINGLE.sub(single(1.0), single(1.0), RoundingMode::Down),
+            (single(-0.0), 0)
+        );
+        assert_eq!(
+            SINGLE.div(single(1.0), single(3.0), RNE),
+            (single(1.0 / 3.0), FLAG_NX)
+        );
+        assert_eq!(
+            SINGLE.div(single(1.0), 0, RNE),
+            (single(f32::INFINITY), FLAG_DZ)
+        );
+        assert_eq!(
+            SINGLE.sqrt(single(2.0), RNE),
+            (single(2f32.sqrt()), FLAG_NX)
+        );
+        assert_eq!(SINGLE.sqrt(single(-1.0), RNE), (0x7fc0_0000, FLAG_NV));
+        assert_eq!(
+            SINGLE.mul(single(f32::INFINITY), 0, RNE),
+            (0x7fc0_0000, FLAG_NV)
+        );
+        // Signaling NaNs are invalid and never propagated
+        assert_eq!(
+            SINGLE.add(0x7f80_0001, single(1.0), RNE),
+            (0x7fc0_0000, FLAG_NV)
+        );
+
+        // Compare with the host, which rounds to nearest even
+        let mut state = 0x1234_5678u32;
+        for _ in 0..10000 {
+            let mut random = || {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                f32::from_bits(state)
+            };
+            let (a, b, c) = (random(), random(), random());
+            let expect = |result: f32| match result.is_nan() {
+                true => 0x7fc0_0000,
+                false => single(result),
+            };
+            assert_eq!(SINGLE.add(single(a), single(b), RNE).0, expect(a + b));
+            assert_eq!(SINGLE.mul(single(a), single(b), RNE).0, expect(a * b));
+            assert_eq!(SINGLE.div(single(a), single(b), RNE).0, expect(a / b));
+            assert_eq!(SINGLE.sqrt(single(a), RNE).0, expect(a.sqrt()));
+            assert_eq!(
+                SINGLE.fused_mul_add(single(a), single(b), single(c), RNE).0,
+                expect(a.mul_add(b, c))
+            );
+            assert_eq!(
+                DOUBLE.convert(SINGLE, single(a), RNE).0,
+                match a.is_nan() {
+                    true => DOUBLE.canonical_nan(),
+                    false => (a as f64).to_bits(),
+                }
+            );
+        }
+        let mut state = 0x1234_5678_9abc_def0u64;
+        for _ in 0..10000 {
+            let mut random = || {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                f64::from_bits(state)
+            };
+            let (a, b, c) = (random(), random(), random());
+            let expect = |result: f64| match result.is_nan() {
+                true => DOUBLE.canonical_nan(),
+                false => result.to_bits(),
+            };
+            let (a_bits, b_bits, c_bits) = (a.to_bits(), b.to_bits(), c.to_bits());
+            assert_eq!(DOUBLE.add(a_bits, b_bits, RNE).0, expect(a + b));
+            assert_eq!(DOUBLE.mul(a_bits, b_bits, RNE).0, expect(a * b));
+            assert_eq!(DOUBLE.div(a_bits, b_bits, RNE).0, expect(a / b));
+            assert_eq!(DOUBLE.sqrt(a_bits, RNE).0, expect(a.sqrt()));
+            assert_eq!(
+                DOUBLE.fused_mul_add(a_bits, b_bits, c_bits, RNE).0,
+                expect(a.mul_add(b, c))
+            );
+            assert_eq!(
+                SINGLE.convert(DOUBLE, a_bits, RNE).0,
+                match a.is_nan() {
+                    true => 0x7fc0_0000,
+                    false => single(a as f32),
+                }
+            );
+        }
+    }
+    #[test]
+    fn test_rounding() {
+        // 1 + 2^-24 is halfway between 1 and the next single
+        let halfway = 0x3f80_0000u64;
+        let ulp = single(2f32.powi(-24));
+        assert_eq!(SINGLE.add(halfway, ulp, RNE), (halfway, FLAG_NX));
+        assert_eq!(
+            SINGLE.add(halfway, ulp, RoundingMode::NearestMaxMagnitude),
+            (halfway + 1, FLAG_NX)
+        );
+        assert_eq!(
+            SINGLE.add(halfway, ulp, RoundingMode::Up),
+            (halfway + 1, FLAG_NX)
+        );
+        assert_eq!(
+            SINGLE.sub(single(-1.0), ulp, RoundingMode::Down),
+            (single(-1.0) + 1, FLAG_NX)
+        );
+
+        // Overflow rounds to infinity or to the largest finite value
+        let max = single(f32::MAX);
+        assert_eq!(
+            SINGLE.mul(max, single(2.0), RNE),
+            (single(f32::INFINITY), FLAG_OF | FLAG_NX)
+        );
+        assert_eq!(
+            SINGLE.mul(max, single(2.0), RoundingMode::TowardZero),
+            (max, FLAG_OF | FLAG_NX)
+        );
+
+        // Underflow is only signaled for inexact tiny results
+        let min_normal = single(f32::MIN_POSITIVE);
+        assert_eq!(SINGLE.mul(min_normal, single(0.5), RNE), (0x0040_0000, 0));
+        assert_eq!(
+            SINGLE.mul(min_normal, single(2f32.powi(-24)), RNE),
+            (0, FLAG_UF | FLAG_NX)
+        );
+        // Tiny before rounding, but not after: 2^-126 * (1 - 2^-46)
+        let below = single(1.0 - 2f32.powi(-23));
+        assert_eq!(
+            SINGLE.mul(min_normal + 1, below, RNE),
+            (min_normal, FLAG_NX)
+        );
+        assert_eq!(
+            SINGLE.mul(min_normal + 1, below, RoundingMode::TowardZero),
+            (min_normal - 1, FLAG_UF | FLAG_NX)
+        );
+
+        // The product is not rounded before the addition
+        let a = single(1.0 + 2f32.powi(-12));
+        assert_eq!(
+            SINGLE.fused_mul_add(a, a, single(-1.0), RNE),
+            (single(2f32.powi(-11) + 2f32.powi(-24)), 0)
+        );
+    }
+    #[test]
+    fn test_conversion() {
+        assert_eq!(SINGLE.to_i32(single(-2.5), RNE), (-2, FLAG_NX));
+        assert_eq!(
+            SINGLE.to_i32(single(-2.5), RoundingMode::NearestMaxMagnitude),
+            (-3, FLAG_NX)
+        );
+        assert_eq!(SINGLE.to_i32(single(3e9), RNE), (i32::MAX, FLAG_NV));
+        assert_eq!(SINGLE.to_i32(0x7fc0_0000, RNE), (i32::MAX, FLAG_NV));
+        assert_eq!(SINGLE.to_u32(single(3e9), RNE), (3_000_000_000, 0));
+        assert_eq!(
+            SINGLE.to_u32(single(-0.4), RoundingMode::TowardZero),
+            (0, FLAG_NX)
+        );
+        assert_eq!(SINGLE.to_u32(single(-1.0), RNE), (0, FLAG_NV));
+        assert_eq!(
+            SINGLE.from_int(i32::MAX as i64, RNE),
+            (single(2147483648.0), FLAG_NX)
+        );
+        assert_eq!(SINGLE.from_int(-7, RNE), (single(-7.0), 0));
+        assert_eq!(
+            SINGLE.convert(DOUBLE, 1e300f64.to_bits(), RNE),
+            (single(f32::INFINITY), FLAG_OF | FLAG_NX)
+        );
+    }
+    #[test]
+    fn test_compare() {
+        let nan = 0x7fc0_0000;
+        assert_eq!(SINGLE.eq(0, single(-0.0)), (true, 0));
+        assert_eq!(SINGLE.eq(nan, nan), (false, 0));
+        assert_eq!(SINGLE.eq(0x7f80_0001, nan), (false, FLAG_NV));
+        assert_eq!(SINGLE.lt(nan, single(1.0)), (false, FLAG_NV));
+        assert_eq!(SINGLE.lt(single(-2.0), single(-1.0)), (true, 0));
+        assert_eq!(SINGLE.le(single(-0.0), 0), (true, 0));
+        assert_eq!(SINGLE.min(single(-0.0), 0), (single(-0.0), 0));
+        assert_eq!(SINGLE.max(single(-0.0), 0), (0, 0));
+        assert_eq!(SINGLE.max(nan, single(1.0)), (single(1.0), 0));
+        assert_eq!(SINGLE.min(0x7f80_0001, nan), (nan, FLAG_NV));
+        assert_eq!(SINGLE.classify(single(-0.0)), 1 << 3);
+        assert_eq!(SINGLE.classify(0x0000_0001), 1 << 5);
+        assert_eq!(SINGLE.classify(single(f32::NEG_INFINITY)), 1 << 0);
+        assert_eq!(SINGLE.classify(0x7f80_0001), 1 << 8);
+    }
+}
@@ -0,0 +1,370 @@
+use std::cell::RefCell;
+use std::rc::{Rc, Weak};
+
+use super::alu::exec;
+use super::decoder::is_compressed;
+use super::instructions::{IInstruction, Instruction, RInstruction, SBInstruction, UJInstruction};
+use super::mmu::{AccessType, PAGE_SIZE};
+use super::{Cpu, HaltReason};
+use crate::bus::{BusDevice, BusWidth};
+use crate::trap::RVException;
+
+// Longest run of instructions that is translated into one block
+const MAX_BLOCK_LEN: usize = 64;
+
+type Op = Box<dyn Fn(&mut Cpu) -> Result<(), RVException>>;
+
+struct CompiledInstruction {
+    len: usize,
+    op: Op,
+}
+
+// A straight run of instructions within one physical page, ending with a
+// branch or jump, before an instruction that needs the interpreter, or at the
+// end of the page. CSR and system instructions are never part of a block.
+pub struct Block {
+    paddr: usize,
+    // Bytes of code, which all have to be executable
+    size: usize,
+    instructions: Vec<CompiledInstruction>,
+    // The block that followed last time, so that loops don't go through the cache
+    successor: RefCell<Weak<Block>>,
+}
+
+impl Block {
+    fn len(&self) -> u64 {
+        self.instructions.len() as u64
+    }
+}
+
+// Instructions that change privilege, CSRs, translation or wait for interrupts
+fn needs_interpreter(instruction: &Instruction) -> bool {
+    match instruction {
+        Instruction::IType { inst, .. } => !matches!(
+            inst,
+            IInstruction::addi
+                | IInstruction::xori
+                | IInstruction::ori
+                | IInstruction::andi
+                | IInstruction::slli
+                | IInstruction::srli
+                | IInstruction::srai
+                | IInstruction::slti
+                | IInstruction::sltiu
+                | IInstruction::clz
+                | IInstruction::ctz
+                | IInstruction::cpop
+                | IInstruction::sextb
+                | IInstruction::sexth
+                | IInstruction::orcb
+                | IInstruction::rev8
+                | IInstruction::rori
+                | IInstruction::bclri
+                | IInstruction::bexti
+                | IInstruction::binvi
+                | IInstruction::bseti
+                | IInstruction::lb
+                | IInstruction::lh
+                | IInstruction::lw
+                | IInstruction::lbu
+                | IInstruction::lhu
+                | IInstruction::flw
+                | IInstruction::fld
+                | IInstruction::jalr
+        ),
+        _ => false,
+    }
+}
+
+fn ends_block(instruction: &Instruction) -> bool {
+    match instruction {
+        Instruction::IType { inst, .. } => *inst == IInstruction::jalr,
+        Instruction::SBType { inst, .. } => !matches!(
+            inst,
+            SBInstruction::sb
+                | SBInstruction::sh
+                | SBInstruction::sw
+                | SBInstruction::fsw
+                | SBInstruction::fsd
+        ),
+        Instruction::UJType { inst, .. } => *inst == UJInstruction::jal,
+        Instruction::RType { .. } | Instruction::FType { .. } => false,
+    }
+}
+
+// Register-to-register operations get their own closure, everything else goes
+// through the interpreter's execute stage
+fn compile(instruction: Instruction) -> Op {
+    macro_rules! imm_op {
+        ($rd:expr, $rs1:expr, $imm:expr, |$a:ident, $b:ident| $result:expr) => {{
+            let (rd, rs1, $b) = ($rd, $rs1, $imm);
+            Box::new(move |cpu: &mut Cpu| {
+                let $a = cpu.regfile.read(rs1);
+                cpu.regfile.write(rd, $result);
+                Ok(())
+            })
+        }};
+    }
+    macro_rules! reg_op {
+        ($rd:expr, $rs1:expr, $rs2:expr, |$a:ident, $b:ident| $result:expr) => {{
+            let (rd, rs1, rs2) = ($rd, $rs1, $rs2);
+            Box::new(move |cpu: &mut Cpu| {
+                let ($a, $b) = (cpu.regfile.read(rs1), cpu.regfile.read(rs2));
+                cpu.regfile.write(rd, $result);
+                Ok(())
+            })
+        }};
+    }
+
+    match instruction {
+        Instruction::IType { rd, rs1, imm, inst } => match inst {
+            IInstruction::addi => imm_op!(rd, rs1, imm, |a, b| a.wrapping_add(b)),
+            IInstruction::xori => imm_op!(rd, rs1, imm, |a, b| a ^ b),
+            IInstruction::ori => imm_op!(rd, rs1, imm, |a, b| a | b),
+            IInstruction::andi => imm_op!(rd, rs1, imm, |a, b| a & b),
+            IInstruction::slli => imm_op!(rd, rs1, imm & 0x1f, |a, b| a << b),
+            IInstruction::srli => imm_op!(rd, rs1, imm & 0x1f, |a, b| ((a as u32) >> b) as i32),
+            IInstruction::srai => imm_op!(rd, rs1, imm & 0x1f, |a, b| a >> b),
+            inst => Box::new(move |cpu: &mut Cpu| {
+                exec(
+                    cpu,
+                    Instruction::IType {
+                        rd,
+                        rs1,
+                        imm,
+                        inst: inst.clone(),
+                    },
+                )
+            }),
+        },
+        Instruction::RType { rd, rs1, rs2, inst } => match inst {
+            RInstruction::add => reg_op!(rd, rs1, rs2, |a, b| a.wrapping_add(b)),
+            RInstruction::sub => reg_op!(rd, rs1, rs2, |a, b| a.wrapping_sub(b)),
+            RInstruction::xor => reg_op!(rd, rs1, rs2, |a, b| a ^ b),
+            RInstruction::or => reg_op!(rd, rs1, rs2, |a, b| a | b),
+            RInstruction::and => reg_op!(rd, rs1, rs2, |a, b| a & b),
+            inst => Box::new(move |cpu: &mut Cpu| {
+                exec(
+                    cpu,
+                    Instruction::RType {
+                        rd,
+                        rs1,
+                        rs2,
+                        inst: inst.clone(),
+                    },
+                )
+            }),
+        },
+        Instruction::UJType {
+            imm,
+            rd,
+            inst: UJInstruction::lui,
+        } => Box::new(move |cpu: &mut Cpu| {
+            cpu.regfile.write(rd, imm << 12);
+            Ok(())
+        }),
+        instruction => Box::new(move |cpu: &mut Cpu| exec(cpu, instruction.clone())),
+    }
+}
+
+impl Cpu {
+    // Translate the instructions at pc, which lives at `paddr`. Returns None
+    // if the first instruction already needs the interpreter.
+    fn compile_block(&mut self, paddr: usize) -> Option<Block> {
+        let mut instructions = Vec::new();
+        let mut addr = paddr;
+        while instructions.len() < MAX_BLOCK_LEN {
+            let lower = match self.bus.load::<u16>(addr) {
+                Ok(lower) => lower as u32,
+                Err(_) => break,
+            };
+            let raw = match is_compressed(lower) {
+                true => lower,
+                // Instructions that span two pages are left to the interpreter
+                false if addr % PAGE_SIZE > PAGE_SIZE - 4 => break,
+                false => match self.bus.load::<u16>(addr + 2) {
+                    Ok(upper) => (upper as u32) << 16 | lower,
+                    Err(_) => break,
+                },
+            };
+            let instruction = match self.decode_enabled(raw) {
+                Ok(instruction) if !needs_interpreter(&instruction) => instruction,
+                _ => break,
+            };
+            let len = if is_compressed(raw) { 2 } else { 4 };
+            let last = ends_block(&instruction);
+            instructions.push(CompiledInstruction {
+                len,
+                op: compile(instruction),
+            });
+            addr += len;
+            if last || addr.is_multiple_of(PAGE_SIZE) {
+                break;
+            }
+        }
+        if instructions.is_empty() {
+            return None;
+        }
+        Some(Block {
+            paddr,
+            size: addr - paddr,
+            instructions,
+            successor: RefCell::new(Weak::new()),
+        })
+    }
+
+    // Block at pc, or None if the interpreter has to handle the next instruction
+    fn next_block(&mut self, previous: Option<&Block>) -> Option<Rc<Block>> {
+        // Blocks stay within a page, so the MMU only needs to see the start
+        let paddr = self
+            .translate(self.pc, u16::WIDTH, AccessType::Fetch)
+            .ok()?;
+        let successor = previous
+            .and_then(|previous| previous.successor.borrow().upgrade())
+            .filter(|next| next.paddr == paddr);
+        let block = match successor {
+            Some(next) => next,
+            None => {
+                let block = match self.blocks.get(paddr) {
+                    Some(block) => block.clone(),
+                    None => {
+                        if !self.is_cacheable(paddr) {
+                            return None;
+                        }
+                        let block = Rc::new(self.compile_block(paddr)?);
+                        self.blocks.insert(paddr, block.clone());
+                        block
+                    }
+                };
+                if let Some(previous) = previous {
+                    *previous.successor.borrow_mut() = Rc::downgrade(&block);
+                }
+                block
+            }
+        };
+        // PMP regions can be smaller than a page. If one of them covers only
+        // part of the block, the interpreter finds the instruction that faults.
+        if !self
+            .csrfile
+            .pmp
+            .check(paddr, block.size, AccessType::Fetch, &self.mode)
+        {
+            return None;
+        }
+        Some(block)
+    }
+
+    // Execute up to `limit` instructions as translated blocks, chaining from one
+    // block to the next. Interrupts are only taken between blocks. Anything
+    // that can't be translated runs one instruction on the interpreter.
+    // Returns the number of instructions, including ones that trapped.
+    pub fn step_block(&mut self, limit: u64) -> (u64, Option<HaltReason>) {
+        let mut executed = 0;
+        let mut previous: Option<Rc<Block>> = None;
+        // Per-instruction debugger history and WFI need the interpreter
+        while !self.waiting && self.debug.history_size == 0 {
+            let block = match self.next_block(previous.as_deref()) {
+                Some(block) if executed + block.len() <= limit => block,
+                _ => break,
+            };
+            // Devices see the whole block at once
+            self.bus.tick(&mut self.csrfile, block.len() as u32);
+            if let Err(interrupt) = self.csrfile.pending_interrupt(&self.mode) {
+                return (executed + 1, self.finish(Err(interrupt)));
+            }
+
+            let generation = self.code_generation;
+            for (index, instruction) in block.instructions.iter().enumerate() {
+                self.next_pc = self.pc + instruction.len;
+                let result = (instruction.op)(self);
+                let done = index as u32 + 1;
+                if result.is_err() || self.halt.is_some() {
+                    // Like the interpreter, only completed instructions count as cycles
+                    self.csrfile.counters.retire(done - result.is_err() as u32);
+                    return (executed + done as u64, self.finish(result));
+                }
+                self.pc = self.next_pc;
+                // The block overwrote itself or other translated code
+                if self.code_generation != generation {
+                    self.csrfile.counters.retire(done);
+                    return (executed + done as u64, None);
+                }
+            }
+            self.csrfile.counters.retire(block.len() as u32);
+            executed += block.len();
+            previous = Some(block);
+        }
+
+        if executed == 0 {
+            return (1, self.step());
+        }
+        (executed, None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::csr::ArchCSRs;
+    use crate::cpu::tests::program;
+    use crate::cpu::{ExecMode, RAM_START};
+
+    #[test]
+    fn test_block_boundaries() {
+        // addi a0, a0, 1; c.addi a0, 1; bne a0, a1, -6; csrr a2, mscratch
+        let mut kernel = program(&[0x00150513]);
+        kernel.extend_from_slice(&0x0505u16.to_le_bytes());
+        kernel.extend(program(&[0xfeb51de3, 0x34002673]));
+        let mut cpu = Cpu::new(kernel, 0x1000).unwrap();
+        cpu.write_register(11, 4);
+
+        let block = cpu.compile_block(RAM_START).unwrap();
+        assert_eq!(block.len(), 3);
+        assert!(cpu.compile_block(RAM_START + 10).is_none());
+
+        // Two loop iterations, then the CSR access is left to the interpreter
+        assert_eq!(cpu.step_block(100), (6, None));
+        assert_eq!(cpu.pc, RAM_START + 10);
+        assert_eq!(cpu.step_block(100), (1, None));
+        assert_eq!(cpu.read_register(10), 4);
+        assert_eq!(cpu.pc, RAM_START + 14);
+    }
+    #[test]
+    fn test_self_modifying_block() {
+        // Overwrite the addi after the store with addi a0, a0, 16:
+        // lui a1, 0x1050; addi a1, a1, 0x513; auipc a2, 0; sw a1, 8(a2);
+        // addi a0, a0, 1; j .
+        let kernel = program(&[
+            0x010505b7, 0x51358593, 0x00000617, 0x00b62423, 0x00150513, 0x0000006f,
+        ]);
+        let mut cpu = Cpu::new(kernel, 0x1000).unwrap();
+
+        // The block ends early once it changed itself
+        assert_eq!(cpu.step_block(100), (4, None));
+        cpu.step_block(100);
+        assert_eq!(cpu.read_register(10), 16);
+        assert_eq!(cpu.pc, RAM_START + 20);
+    }
+    #[test]
+    fn test_pmp_inside_block() {
+        // Three times addi a0, a0, 1, where the last one is in a 4-byte PMP
+        // region without execute permission
+        let mut cpu = Cpu::new(program(&[0x00150513; 3]), 0x1000).unwrap();
+        let boundary = ((RAM_START + 8) >> 2) as i32;
+        cpu.csrfile.write(ArchCSRs::pmpaddr0 as i32, boundary);
+        cpu.csrfile.write(ArchCSRs::pmpaddr1 as i32, boundary);
+        // Entry 0: TOR, R+X, entry 1: NA4, no permissions
+        cpu.csrfile.write(ArchCSRs::pmpcfg0 as i32, 0x100d);
+        cpu.mode = ExecMode::USER;
+
+        for _ in 0..3 {
+            cpu.step_block(100);
+        }
+        assert_eq!(cpu.read_register(10), 2);
+        assert_eq!(cpu.csrfile.read(ArchCSRs::mcause as i32), 1);
+        assert_eq!(
+            cpu.csrfile.read(ArchCSRs::mepc as i32),
+            (RAM_START + 8) as i32
+        );
+    }
+}
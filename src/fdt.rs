@@ -0,0 +1,472 @@
+// Reading, patching and writing flattened device tree blobs
+// https://devicetree-specification.readthedocs.io/en/stable/flattened-format.html
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_NOP: u32 = 0x4;
+const FDT_END: u32 = 0x9;
+
+const FDT_VERSION: u32 = 17;
+const FDT_LAST_COMPATIBLE_VERSION: u32 = 16;
+const HEADER_SIZE: usize = 40;
+// Entries of the memory reservation block, which ends with an empty one
+const RESERVATION_SIZE: usize = 16;
+
+fn read_u32(dtb: &[u8], offset: usize) -> Option<u32> {
+    let bytes = dtb.get(offset..offset + 4)?;
+    Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn align(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+fn c_string(dtb: &[u8], offset: usize) -> Option<&[u8]> {
+    let bytes = dtb.get(offset..)?;
+    let len = bytes.iter().position(|b| *b == 0)?;
+    Some(&bytes[..len])
+}
+
+// Locations (offset, length) of the values of all properties called `name`
+fn find_properties(dtb: &[u8], name: &str) -> Vec<(usize, usize)> {
+    let mut found = Vec::new();
+    if read_u32(dtb, 0) != Some(FDT_MAGIC) {
+        return found;
+    }
+    let (Some(structs), Some(strings)) = (read_u32(dtb, 8), read_u32(dtb, 12)) else {
+        return found;
+    };
+    let strings = strings as usize;
+    let mut offset = structs as usize;
+    while let Some(token) = read_u32(dtb, offset) {
+        offset += 4;
+        match token {
+            FDT_BEGIN_NODE => match c_string(dtb, offset) {
+                Some(node) => offset = align(offset + node.len() + 1),
+                None => break,
+            },
+            FDT_PROP => {
+                let (Some(len), Some(nameoff)) = (read_u32(dtb, offset), read_u32(dtb, offset + 4))
+                else {
+                    break;
+                };
+                let value = offset + 8;
+                if c_string(dtb, strings + nameoff as usize) == Some(name.as_bytes()) {
+                    found.push((value, len as usize));
+                }
+                offset = align(value + len as usize);
+            }
+            FDT_END_NODE | FDT_NOP => (),
+            FDT_END => break,
+            // Unknown token, the blob is corrupt
+            _ => break,
+        }
+    }
+    found
+}
+
+// First 32 or 64 bit integer property called `name`
+pub fn read_int_property(dtb: &[u8], name: &str) -> Option<u64> {
+    find_properties(dtb, name)
+        .into_iter()
+        .find_map(|(offset, len)| match len {
+            4 => read_u32(dtb, offset).map(u64::from),
+            8 => Some((read_u32(dtb, offset)? as u64) << 32 | read_u32(dtb, offset + 4)? as u64),
+            _ => None,
+        })
+}
+
+// Overwrites every 32 or 64 bit integer property called `name` in place,
+// returns the number of properties that were changed
+pub fn write_int_property(dtb: &mut [u8], name: &str, value: u64) -> usize {
+    let mut written = 0;
+    for (offset, len) in find_properties(dtb, name) {
+        let bytes = match len {
+            4 if value <= u32::MAX as u64 => (value as u32).to_be_bytes().to_vec(),
+            8 => value.to_be_bytes().to_vec(),
+            _ => continue,
+        };
+        if let Some(dst) = dtb.get_mut(offset..offset + len) {
+            dst.copy_from_slice(&bytes);
+            written += 1;
+        }
+    }
+    written
+}
+
+// Builds a blob node by node, in the order of the structure block
+pub struct FdtWriter {
+    reservations: Vec<(u64, u64)>,
+    structs: Vec<u8>,
+    strings: Vec<u8>,
+    depth: usize,
+}
+
+impl FdtWriter {
+    pub fn new() -> Self {
+        Self {
+            reservations: Vec::new(),
+            structs: Vec::new(),
+            strings: Vec::new(),
+            depth: 0,
+        }
+    }
+
+    // Memory that the kernel must not use, given as address and size
+    pub fn reserve(&mut self, addr: u64, size: u64) {
+        self.reservations.push((addr, size));
+    }
+
+    fn token(&mut self, token: u32) {
+        self.structs.extend_from_slice(&token.to_be_bytes());
+    }
+
+    fn pad(&mut self) {
+        self.structs.resize(align(self.structs.len()), 0);
+    }
+
+    // Property names are shared through the strings block
+    fn string_offset(&mut self, name: &str) -> usize {
+        let mut offset = 0;
+        while let Some(string) = c_string(&self.strings, offset) {
+            if string == name.as_bytes() {
+                return offset;
+            }
+            offset += string.len() + 1;
+        }
+        self.strings.extend_from_slice(name.as_bytes());
+        self.strings.push(0);
+        offset
+    }
+
+    // The root node has an empty name
+    pub fn begin_node(&mut self, name: &str) {
+        self.token(FDT_BEGIN_NODE);
+        self.structs.extend_from_slice(name.as_bytes());
+        self.structs.push(0);
+        self.pad();
+        self.depth += 1;
+    }
+
+    pub fn end_node(&mut self) {
+        self.token(FDT_END_NODE);
+        self.depth -= 1;
+    }
+
+    pub fn property(&mut self, name: &str, value: &[u8]) {
+        let nameoff = self.string_offset(name);
+        self.token(FDT_PROP);
+        self.token(value.len() as u32);
+        self.token(nameoff as u32);
+        self.structs.extend_from_slice(value);
+        self.pad();
+    }
+
+    pub fn property_empty(&mut self, name: &str) {
+        self.property(name, &[]);
+    }
+
+    pub fn property_u32(&mut self, name: &str, value: u32) {
+        self.property_cells(name, &[value]);
+    }
+
+    pub fn property_cells(&mut self, name: &str, cells: &[u32]) {
+        let value: Vec<u8> = cells.iter().flat_map(|cell| cell.to_be_bytes()).collect();
+        self.property(name, &value);
+    }
+
+    pub fn property_string(&mut self, name: &str, value: &str) {
+        self.property_strings(name, &[value]);
+    }
+
+    pub fn property_strings(&mut self, name: &str, values: &[&str]) {
+        let mut value = Vec::new();
+        for string in values {
+            value.extend_from_slice(string.as_bytes());
+            value.push(0);
+        }
+        self.property(name, &value);
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        assert_eq!(self.depth, 0, "Unbalanced device tree nodes");
+        self.token(FDT_END);
+        let structs = HEADER_SIZE + (self.reservations.len() + 1) * RESERVATION_SIZE;
+        let strings = structs + self.structs.len();
+        let header = [
+            FDT_MAGIC,
+            (strings + self.strings.len()) as u32,
+            structs as u32,
+            strings as u32,
+            HEADER_SIZE as u32,
+            FDT_VERSION,
+            FDT_LAST_COMPATIBLE_VERSION,
+            0, // boot_cpuid_phys
+            self.strings.len() as u32,
+            self.structs.len() as u32,
+        ];
+        let mut dtb: Vec<u8> = header.iter().flat_map(|word| word.to_be_bytes()).collect();
+        for (addr, size) in self.reservations {
+            dtb.extend_from_slice(&addr.to_be_bytes());
+            dtb.extend_from_slice(&size.to_be_bytes());
+        }
+        dtb.resize(structs, 0);
+        dtb.extend_from_slice(&self.structs);
+        dtb.extend_from_slice(&self.strings);
+        dtb
+    }
+}
+
+impl Default for FdtWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// A whole device tree, for edits that change the size of the blob
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceTree {
+    pub reservations: Vec<(u64, u64)>,
+    pub root: Node,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Node {
+    pub name: String,
+    pub properties: Vec<(String, Vec<u8>)>,
+    pub children: Vec<Node>,
+}
+
+impl Node {
+    pub fn property(&self, name: &str) -> Option<&[u8]> {
+        self.properties
+            .iter()
+            .find(|(property, _)| property == name)
+            .map(|(_, value)| value.as_slice())
+    }
+
+    pub fn set_property(&mut self, name: &str, value: Vec<u8>) {
+        match self
+            .properties
+            .iter_mut()
+            .find(|(property, _)| property == name)
+        {
+            Some((_, old)) => *old = value,
+            None => self.properties.push((name.to_string(), value)),
+        }
+    }
+
+    // Child with the given name, which is added if it doesn't exist yet
+    pub fn child_mut(&mut self, name: &str) -> &mut Node {
+        let index = match self.children.iter().position(|child| child.name == name) {
+            Some(index) => index,
+            None => {
+                self.children.push(Node {
+                    name: name.to_string(),
+                    ..Default::default()
+                });
+                self.children.len() - 1
+            }
+        };
+        &mut self.children[index]
+    }
+
+    fn write(&self, writer: &mut FdtWriter) {
+        writer.begin_node(&self.name);
+        for (name, value) in &self.properties {
+            writer.property(name, value);
+        }
+        for child in &self.children {
+            child.write(writer);
+        }
+        writer.end_node();
+    }
+}
+
+impl DeviceTree {
+    pub fn parse(dtb: &[u8]) -> Option<Self> {
+        if read_u32(dtb, 0)? != FDT_MAGIC {
+            return None;
+        }
+        let structs = read_u32(dtb, 8)? as usize;
+        let strings = read_u32(dtb, 12)? as usize;
+        let mut reservations = Vec::new();
+        let mut offset = read_u32(dtb, 16)? as usize;
+        loop {
+            let addr = (read_u32(dtb, offset)? as u64) << 32 | read_u32(dtb, offset + 4)? as u64;
+            let size =
+                (read_u32(dtb, offset + 8)? as u64) << 32 | read_u32(dtb, offset + 12)? as u64;
+            if addr == 0 && size == 0 {
+                break;
+            }
+            reservations.push((addr, size));
+            offset += RESERVATION_SIZE;
+        }
+
+        // Nodes that are still open, the root is at the bottom
+        let mut open: Vec<Node> = Vec::new();
+        let mut offset = structs;
+        loop {
+            let token = read_u32(dtb, offset)?;
+            offset += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let name = c_string(dtb, offset)?;
+                    offset = align(offset + name.len() + 1);
+                    open.push(Node {
+                        name: String::from_utf8_lossy(name).into_owned(),
+                        ..Default::default()
+                    });
+                }
+                FDT_END_NODE => {
+                    let node = open.pop()?;
+                    match open.last_mut() {
+                        Some(parent) => parent.children.push(node),
+                        None => {
+                            return Some(Self {
+                                reservations,
+                                root: node,
+                            })
+                        }
+                    }
+                }
+                FDT_PROP => {
+                    let len = read_u32(dtb, offset)? as usize;
+                    let name = c_string(dtb, strings + read_u32(dtb, offset + 4)? as usize)?;
+                    let value = dtb.get(offset + 8..offset + 8 + len)?.to_vec();
+                    offset = align(offset + 8 + len);
+                    let name = String::from_utf8_lossy(name).into_owned();
+                    open.last_mut()?.properties.push((name, value));
+                }
+                FDT_NOP => (),
+                // FDT_END before the root node was closed, or corrupt
+                _ => return None,
+            }
+        }
+    }
+
+    pub fn to_blob(&self) -> Vec<u8> {
+        let mut writer = FdtWriter::new();
+        for (addr, size) in &self.reservations {
+            writer.reserve(*addr, *size);
+        }
+        self.root.write(&mut writer);
+        writer.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // / { cpus { timebase-frequency = <10000000>; }; };
+    fn dtb() -> Vec<u8> {
+        let strings = b"timebase-frequency\0";
+        let mut structs = Vec::new();
+        for word in [FDT_BEGIN_NODE, 0, FDT_BEGIN_NODE] {
+            structs.extend_from_slice(&word.to_be_bytes());
+        }
+        structs.extend_from_slice(b"cpus\0\0\0\0");
+        for word in [
+            FDT_PROP,
+            4,
+            0,
+            10_000_000,
+            FDT_END_NODE,
+            FDT_END_NODE,
+            FDT_END,
+        ] {
+            structs.extend_from_slice(&word.to_be_bytes());
+        }
+
+        // Followed by an empty memory reservation block
+        let header_size = HEADER_SIZE + RESERVATION_SIZE;
+        let mut dtb = Vec::new();
+        let header = [
+            FDT_MAGIC,
+            (header_size + structs.len() + strings.len()) as u32,
+            header_size as u32,
+            (header_size + structs.len()) as u32,
+            HEADER_SIZE as u32,
+        ];
+        for word in header {
+            dtb.extend_from_slice(&word.to_be_bytes());
+        }
+        dtb.resize(header_size, 0);
+        dtb.extend_from_slice(&structs);
+        dtb.extend_from_slice(strings);
+        dtb
+    }
+
+    #[test]
+    fn test_read_write_property() {
+        let mut dtb = dtb();
+        assert_eq!(
+            read_int_property(&dtb, "timebase-frequency"),
+            Some(10_000_000)
+        );
+        assert_eq!(read_int_property(&dtb, "clock-frequency"), None);
+
+        assert_eq!(write_int_property(&mut dtb, "timebase-frequency", 1234), 1);
+        assert_eq!(read_int_property(&dtb, "timebase-frequency"), Some(1234));
+        // Doesn't fit into the existing 32 bit cell
+        assert_eq!(
+            write_int_property(&mut dtb, "timebase-frequency", 1 << 32),
+            0
+        );
+    }
+    #[test]
+    fn test_writer() {
+        let mut writer = FdtWriter::new();
+        writer.begin_node("");
+        writer.property_u32("#address-cells", 1);
+        writer.begin_node("cpus");
+        writer.property_u32("timebase-frequency", 10_000_000);
+        writer.property_string("compatible", "riscv");
+        writer.end_node();
+        writer.begin_node("memory@80000000");
+        writer.property_cells("reg", &[0, 0x8000_0000]);
+        writer.end_node();
+        writer.end_node();
+        let dtb = writer.finish();
+
+        assert_eq!(read_u32(&dtb, 4), Some(dtb.len() as u32));
+        assert_eq!(read_int_property(&dtb, "#address-cells"), Some(1));
+        assert_eq!(
+            read_int_property(&dtb, "timebase-frequency"),
+            Some(10_000_000)
+        );
+        assert_eq!(read_int_property(&dtb, "reg"), Some(0x8000_0000));
+        assert_eq!(find_properties(&dtb, "compatible"), vec![(120, 6)]);
+        assert_eq!(&dtb[120..126], b"riscv\0");
+    }
+    #[test]
+    fn test_edit_tree() {
+        let mut tree = DeviceTree::parse(&dtb()).unwrap();
+        assert!(tree.reservations.is_empty());
+        assert_eq!(tree.root.children[0].name, "cpus");
+        tree.reservations.push((0x8000_0000, 0x1000));
+        tree.root
+            .child_mut("chosen")
+            .set_property("bootargs", b"console=ttyS0\0".to_vec());
+        let blob = tree.to_blob();
+
+        let parsed = DeviceTree::parse(&blob).unwrap();
+        assert_eq!(parsed, tree);
+        assert_eq!(
+            parsed.root.children[1].property("bootargs"),
+            Some(&b"console=ttyS0\0"[..])
+        );
+        assert_eq!(
+            read_int_property(&blob, "timebase-frequency"),
+            Some(10_000_000)
+        );
+    }
+    #[test]
+    fn test_invalid_blob() {
+        assert_eq!(read_int_property(&[0u8; 8], "timebase-frequency"), None);
+        assert_eq!(DeviceTree::parse(&dtb()[..60]), None);
+    }
+}
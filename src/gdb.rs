@@ -0,0 +1,638 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use num_traits::FromPrimitive;
+use tracing::{info, warn};
+
+use crate::cpu::debug::{DebugEvent, WatchKind, Watchpoint};
+use crate::cpu::instructions::pretty_register;
+use crate::cpu::{Cpu, ExecMode, HaltReason};
+
+// GDB's rv32 register numbers: x0-x31, pc, then CSRs offset by 65
+const PC_REGNUM: usize = 32;
+const CSR_REGNUM: usize = 65;
+const PRIV_REGNUM: usize = CSR_REGNUM + 4096;
+
+// Largest packet that GDB may send, as advertised in qSupported. Memory reads
+// take two hex digits per byte, so they are limited to half of it.
+const PACKET_SIZE: usize = 0x1000;
+
+// CSRs exposed in the target description
+const CSRS: [(&str, u32); 24] = [
+    ("sstatus", 0x100),
+    ("sie", 0x104),
+    ("stvec", 0x105),
+    ("sscratch", 0x140),
+    ("sepc", 0x141),
+    ("scause", 0x142),
+    ("stval", 0x143),
+    ("sip", 0x144),
+    ("satp", 0x180),
+    ("mstatus", 0x300),
+    ("misa", 0x301),
+    ("medeleg", 0x302),
+    ("mideleg", 0x303),
+    ("mie", 0x304),
+    ("mtvec", 0x305),
+    ("mscratch", 0x340),
+    ("mepc", 0x341),
+    ("mcause", 0x342),
+    ("mtval", 0x343),
+    ("mip", 0x344),
+    ("cycle", 0xc00),
+    ("time", 0xc01),
+    ("instret", 0xc02),
+    ("mhartid", 0xf14),
+];
+
+const EBREAK: u32 = 0x0010_0073;
+const C_EBREAK: u16 = 0x9002;
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+
+// Check for a Ctrl-C from GDB every so many instructions while running
+const INTERRUPT_POLL: u64 = 0x1000;
+
+// How a GDB session ended, the caller decides what a kill means
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Session {
+    Detached,
+    Killed,
+}
+
+enum Action {
+    Reply(String),
+    Resume { step: bool },
+    Detach,
+    Kill,
+}
+
+struct GdbStub<'a> {
+    cpu: &'a mut Cpu,
+    // Original memory contents behind software breakpoints
+    breakpoints: HashMap<usize, Vec<u8>>,
+    hw_breakpoints: HashSet<usize>,
+}
+
+pub fn serve(cpu: &mut Cpu, port: u16) -> io::Result<Session> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    info!("Waiting for GDB connection on port {}", port);
+    let (mut stream, addr) = listener.accept()?;
+    stream.set_nodelay(true)?;
+    info!("GDB connected from {}", addr);
+
+    let mut stub = GdbStub::new(cpu);
+    let mut session = Session::Detached;
+    loop {
+        let packet = match read_packet(&mut stream)? {
+            Some(packet) => packet,
+            None => {
+                warn!("GDB disconnected");
+                break;
+            }
+        };
+        match stub.handle(&packet) {
+            Action::Reply(reply) => write_packet(&mut stream, &reply)?,
+            Action::Resume { step } => {
+                let reply = stub.resume(&mut stream, step)?;
+                write_packet(&mut stream, &reply)?;
+            }
+            Action::Detach => {
+                write_packet(&mut stream, "OK")?;
+                break;
+            }
+            Action::Kill => {
+                session = Session::Killed;
+                break;
+            }
+        }
+    }
+    stub.detach();
+    Ok(session)
+}
+
+impl<'a> GdbStub<'a> {
+    fn new(cpu: &'a mut Cpu) -> Self {
+        cpu.debug.attached = true;
+        Self {
+            cpu,
+            breakpoints: HashMap::new(),
+            hw_breakpoints: HashSet::new(),
+        }
+    }
+
+    fn detach(&mut self) {
+        let addrs: Vec<usize> = self.breakpoints.keys().cloned().collect();
+        for addr in addrs {
+            self.remove_breakpoint(addr);
+        }
+        self.cpu.debug.watchpoints.clear();
+        self.cpu.debug.attached = false;
+    }
+
+    fn handle(&mut self, packet: &str) -> Action {
+        let (command, args) = packet.split_at(packet.len().min(1));
+        let reply = match command {
+            "?" => stop_reply(SIGTRAP),
+            "g" => (0..=PC_REGNUM)
+                .map(|num| hex_u32(self.read_register(num).unwrap()))
+                .collect(),
+            "G" => {
+                let values = decode_hex(args).unwrap_or_default();
+                for (num, bytes) in values.chunks_exact(4).enumerate().take(PC_REGNUM + 1) {
+                    let value = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+                    self.write_register(num, value);
+                }
+                ok()
+            }
+            "p" => usize::from_str_radix(args, 16)
+                .ok()
+                .and_then(|num| self.read_register(num))
+                .map_or_else(error, hex_u32),
+            "P" => self.write_register_packet(args).unwrap_or_else(error),
+            "m" => self.read_memory(args).unwrap_or_else(error),
+            "M" => self.write_memory(args).unwrap_or_else(error),
+            "c" | "s" => {
+                // An optional address to resume at
+                if let Ok(addr) = usize::from_str_radix(args, 16) {
+                    self.cpu.pc = addr;
+                }
+                return Action::Resume {
+                    step: command == "s",
+                };
+            }
+            "Z" | "z" => self
+                .breakpoint_packet(command == "Z", args)
+                .unwrap_or_default(),
+            "q" => self.query(args),
+            "H" => ok(),
+            "D" => return Action::Detach,
+            "k" => return Action::Kill,
+            _ => "".to_string(),
+        };
+        Action::Reply(reply)
+    }
+
+    fn query(&self, query: &str) -> String {
+        if query.starts_with("Supported") {
+            return format!("PacketSize={:x};qXfer:features:read+", PACKET_SIZE);
+        }
+        if let Some(range) = query.strip_prefix("Xfer:features:read:target.xml:") {
+            let xml = target_xml();
+            return match parse_pair(range) {
+                Some((offset, length)) if offset < xml.len() => {
+                    let end = (offset + length).min(xml.len());
+                    let more = if end < xml.len() { "m" } else { "l" };
+                    format!("{}{}", more, &xml[offset..end])
+                }
+                Some(_) => "l".to_string(),
+                None => error(),
+            };
+        }
+        match query {
+            "Attached" => "1".to_string(),
+            "C" => "QC1".to_string(),
+            "fThreadInfo" => "m1".to_string(),
+            "sThreadInfo" => "l".to_string(),
+            _ => "".to_string(),
+        }
+    }
+
+    fn read_register(&self, num: usize) -> Option<u32> {
+        match num {
+            0..=31 => Some(self.cpu.read_register(num)),
+            PC_REGNUM => Some(self.cpu.pc as u32),
+            PRIV_REGNUM => Some(self.cpu.mode() as u32),
+            _ if (CSR_REGNUM..PRIV_REGNUM).contains(&num) => {
+                Some(self.cpu.read_csr((num - CSR_REGNUM) as u32))
+            }
+            _ => None,
+        }
+    }
+
+    fn write_register(&mut self, num: usize, value: u32) -> bool {
+        match num {
+            0..=31 => self.cpu.write_register(num, value),
+            PC_REGNUM => self.cpu.pc = value as usize,
+            PRIV_REGNUM => match ExecMode::from_u32(value) {
+                Some(mode) => self.cpu.set_mode(mode),
+                None => return false,
+            },
+            _ if (CSR_REGNUM..PRIV_REGNUM).contains(&num) => {
+                self.cpu.write_csr((num - CSR_REGNUM) as u32, value)
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    // P<num>=<value>
+    fn write_register_packet(&mut self, args: &str) -> Option<String> {
+        let (num, value) = args.split_once('=')?;
+        let num = usize::from_str_radix(num, 16).ok()?;
+        let bytes = decode_hex(value)?;
+        if bytes.len() != 4 {
+            return None;
+        }
+        let value = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        self.write_register(num, value).then(ok)
+    }
+
+    // m<addr>,<length>
+    fn read_memory(&mut self, args: &str) -> Option<String> {
+        let (addr, length) = parse_pair(args)?;
+        if length > PACKET_SIZE / 2 || !in_address_space(addr, length) {
+            return None;
+        }
+        let mut bytes = Vec::with_capacity(length);
+        for offset in 0..length {
+            match self.cpu.read_memory(addr + offset) {
+                Ok(byte) => bytes.push(byte),
+                Err(_) => break,
+            }
+        }
+        // Partial reads are fine as long as at least one byte was read
+        if bytes.is_empty() && length > 0 {
+            return None;
+        }
+        Some(encode_hex(&bytes))
+    }
+
+    // M<addr>,<length>:<data>
+    fn write_memory(&mut self, args: &str) -> Option<String> {
+        let (range, data) = args.split_once(':')?;
+        let (addr, length) = parse_pair(range)?;
+        let bytes = decode_hex(data)?;
+        if bytes.len() != length || !in_address_space(addr, length) {
+            return None;
+        }
+        for (offset, byte) in bytes.iter().enumerate() {
+            self.cpu.write_memory(addr + offset, *byte).ok()?;
+        }
+        Some(ok())
+    }
+
+    // Z<type>,<addr>,<kind> inserts and z<type>,<addr>,<kind> removes
+    fn breakpoint_packet(&mut self, insert: bool, args: &str) -> Option<String> {
+        let (kind, rest) = args.split_once(',')?;
+        let (addr, length) = parse_pair(rest)?;
+        let watch = |kind| Watchpoint {
+            addr,
+            len: length,
+            kind,
+        };
+        let done = match (kind, insert) {
+            ("0", true) => self.insert_breakpoint(addr, length),
+            ("0", false) => self.remove_breakpoint(addr),
+            ("1", true) => {
+                self.hw_breakpoints.insert(addr);
+                true
+            }
+            ("1", false) => self.hw_breakpoints.remove(&addr),
+            ("2", _) => self.update_watchpoint(insert, watch(WatchKind::Write)),
+            ("3", _) => self.update_watchpoint(insert, watch(WatchKind::Read)),
+            ("4", _) => self.update_watchpoint(insert, watch(WatchKind::Access)),
+            // Unsupported breakpoint types get an empty reply
+            _ => return None,
+        };
+        Some(if done { ok() } else { error() })
+    }
+
+    // Software breakpoints patch an ebreak into memory
+    fn insert_breakpoint(&mut self, addr: usize, length: usize) -> bool {
+        if self.breakpoints.contains_key(&addr) {
+            return true;
+        }
+        let ebreak = match length {
+            2 => C_EBREAK.to_le_bytes().to_vec(),
+            4 => EBREAK.to_le_bytes().to_vec(),
+            _ => return false,
+        };
+        let mut original = Vec::with_capacity(length);
+        for offset in 0..length {
+            match self.cpu.read_memory(addr + offset) {
+                Ok(byte) => original.push(byte),
+                Err(_) => return false,
+            }
+        }
+        for (offset, byte) in ebreak.iter().enumerate() {
+            if self.cpu.write_memory(addr + offset, *byte).is_err() {
+                return false;
+            }
+        }
+        self.breakpoints.insert(addr, original);
+        true
+    }
+
+    fn remove_breakpoint(&mut self, addr: usize) -> bool {
+        match self.breakpoints.remove(&addr) {
+            Some(original) => original
+                .iter()
+                .enumerate()
+                .all(|(offset, byte)| self.cpu.write_memory(addr + offset, *byte).is_ok()),
+            None => false,
+        }
+    }
+
+    fn update_watchpoint(&mut self, insert: bool, watch: Watchpoint) -> bool {
+        let watchpoints = &mut self.cpu.debug.watchpoints;
+        if insert {
+            watchpoints.push(watch);
+            return true;
+        }
+        match watchpoints.iter().position(|w| *w == watch) {
+            Some(index) => {
+                watchpoints.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn resume(&mut self, stream: &mut TcpStream, step: bool) -> io::Result<String> {
+        if step {
+            return Ok(halt_reply(self.cpu.step()));
+        }
+        let mut count: u64 = 0;
+        loop {
+            if let Some(halt) = self.cpu.step() {
+                return Ok(halt_reply(Some(halt)));
+            }
+            if self.hw_breakpoints.contains(&self.cpu.pc) {
+                return Ok(stop_reply(SIGTRAP));
+            }
+            count += 1;
+            if count.is_multiple_of(INTERRUPT_POLL) && interrupted(stream)? {
+                return Ok(stop_reply(SIGINT));
+            }
+        }
+    }
+}
+
+fn target_xml() -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\"?>\
+         <!DOCTYPE target SYSTEM \"gdb-target.dtd\">\
+         <target version=\"1.0\">\
+         <architecture>riscv:rv32</architecture>\
+         <feature name=\"org.gnu.gdb.riscv.cpu\">",
+    );
+    for num in 0..32 {
+        xml += &format!(
+            "<reg name=\"{}\" bitsize=\"32\" type=\"int\" regnum=\"{}\"/>",
+            pretty_register(&num),
+            num
+        );
+    }
+    xml += &format!(
+        "<reg name=\"pc\" bitsize=\"32\" type=\"code_ptr\" regnum=\"{}\"/>",
+        PC_REGNUM
+    );
+    xml += "</feature><feature name=\"org.gnu.gdb.riscv.csr\">";
+    for (name, addr) in CSRS.iter() {
+        xml += &format!(
+            "<reg name=\"{}\" bitsize=\"32\" regnum=\"{}\"/>",
+            name,
+            CSR_REGNUM + *addr as usize
+        );
+    }
+    xml += &format!(
+        "</feature><feature name=\"org.gnu.gdb.riscv.virtual\">\
+         <reg name=\"priv\" bitsize=\"32\" regnum=\"{}\"/>\
+         </feature></target>",
+        PRIV_REGNUM
+    );
+    xml
+}
+
+fn stop_reply(signal: u8) -> String {
+    format!("S{:02x}", signal)
+}
+
+fn halt_reply(halt: Option<HaltReason>) -> String {
+    match halt {
+        Some(HaltReason::Debug(DebugEvent::Watchpoint(watch))) => {
+            let kind = match watch.kind {
+                WatchKind::Write => "watch",
+                WatchKind::Read => "rwatch",
+                WatchKind::Access => "awatch",
+            };
+            format!("T{:02x}{}:{:x};", SIGTRAP, kind, watch.addr)
+        }
+        // The riscv-tests exit status becomes the process exit code
+        Some(HaltReason::TestPassed) => "W00".to_string(),
+        Some(HaltReason::TestFailed(code)) => format!("W{:02x}", code as u8),
+        Some(HaltReason::Fatal(message)) => {
+            warn!("{}", message);
+            stop_reply(SIGILL)
+        }
+        _ => stop_reply(SIGTRAP),
+    }
+}
+
+fn ok() -> String {
+    "OK".to_string()
+}
+
+fn error() -> String {
+    "E01".to_string()
+}
+
+// Whether the range fits into the hart's 32-bit address space
+fn in_address_space(addr: usize, length: usize) -> bool {
+    addr.checked_add(length)
+        .is_some_and(|end| end as u64 <= 1 << 32)
+}
+
+// Parse "<hex>,<hex>"
+fn parse_pair(args: &str) -> Option<(usize, usize)> {
+    let (first, second) = args.split_once(',')?;
+    Some((
+        usize::from_str_radix(first, 16).ok()?,
+        usize::from_str_radix(second, 16).ok()?,
+    ))
+}
+
+// Registers are sent in target byte order
+fn hex_u32(value: u32) -> String {
+    encode_hex(&value.to_le_bytes())
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn checksum(data: &str) -> u8 {
+    data.bytes().fold(0, |sum, byte| sum.wrapping_add(byte))
+}
+
+fn read_byte(stream: &mut TcpStream) -> io::Result<Option<u8>> {
+    let mut byte = [0u8];
+    match stream.read(&mut byte)? {
+        0 => Ok(None),
+        _ => Ok(Some(byte[0])),
+    }
+}
+
+// Read the next packet, acknowledging it. Returns None when GDB hangs up.
+fn read_packet(stream: &mut TcpStream) -> io::Result<Option<String>> {
+    loop {
+        // Skip acks and anything else before the start of a packet
+        match read_byte(stream)? {
+            Some(b'$') => {}
+            Some(_) => continue,
+            None => return Ok(None),
+        }
+        let mut data = Vec::new();
+        loop {
+            match read_byte(stream)? {
+                Some(b'#') => break,
+                Some(byte) => data.push(byte),
+                None => return Ok(None),
+            }
+        }
+        let mut sum = [0u8; 2];
+        stream.read_exact(&mut sum)?;
+
+        let data = String::from_utf8_lossy(&data).into_owned();
+        let expected = std::str::from_utf8(&sum)
+            .ok()
+            .and_then(|sum| u8::from_str_radix(sum, 16).ok());
+        if expected == Some(checksum(&data)) {
+            stream.write_all(b"+")?;
+            return Ok(Some(data));
+        }
+        stream.write_all(b"-")?;
+    }
+}
+
+fn write_packet(stream: &mut TcpStream, data: &str) -> io::Result<()> {
+    let packet = format!("${}#{:02x}", data, checksum(data));
+    stream.write_all(packet.as_bytes())?;
+    stream.flush()
+}
+
+// GDB sends a raw 0x03 byte to interrupt a running target
+fn interrupted(stream: &mut TcpStream) -> io::Result<bool> {
+    stream.set_nonblocking(true)?;
+    let result = match read_byte(stream) {
+        Ok(byte) => Ok(byte == Some(0x03)),
+        Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(false),
+        Err(e) => Err(e),
+    };
+    stream.set_nonblocking(false)?;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::tests::program;
+
+    const RAM_START: usize = 0x8000_0000;
+
+    fn reply(stub: &mut GdbStub, packet: &str) -> String {
+        match stub.handle(packet) {
+            Action::Reply(reply) => reply,
+            _ => panic!("Expected a reply to {}", packet),
+        }
+    }
+
+    #[test]
+    fn test_registers() {
+        let mut cpu = Cpu::new(vec![0u8; 16], 1024).unwrap();
+        let mut stub = GdbStub::new(&mut cpu);
+
+        assert_eq!(reply(&mut stub, "P1=78563412"), "OK");
+        assert_eq!(reply(&mut stub, "p1"), "78563412");
+        // pc is register 32
+        assert_eq!(reply(&mut stub, "p20"), "00000080");
+        // mscratch through the CSR register numbers
+        assert_eq!(reply(&mut stub, "P381=efbeadde"), "OK");
+        assert_eq!(stub.cpu.read_csr(0x340), 0xdead_beef);
+
+        let registers = reply(&mut stub, "g");
+        assert_eq!(registers.len(), 33 * 8);
+        assert_eq!(&registers[8..16], "78563412");
+    }
+    #[test]
+    fn test_memory() {
+        let mut cpu = Cpu::new(vec![0x13, 0x00, 0x00, 0x00], 1024).unwrap();
+        let mut stub = GdbStub::new(&mut cpu);
+
+        assert_eq!(reply(&mut stub, "m80000000,4"), "13000000");
+        assert_eq!(reply(&mut stub, "M80000004,2:abcd"), "OK");
+        assert_eq!(reply(&mut stub, "m80000004,2"), "abcd");
+        assert_eq!(reply(&mut stub, "m0,4"), "E01");
+        // Oversized or wrapping requests are refused
+        assert_eq!(reply(&mut stub, "m0,ffffffffffffffff"), "E01");
+        assert_eq!(reply(&mut stub, "m80000000,801"), "E01");
+        assert_eq!(reply(&mut stub, "mffffffffffffffff,2"), "E01");
+        assert_eq!(reply(&mut stub, "Mffffffffffffffff,2:abcd"), "E01");
+    }
+    #[test]
+    fn test_software_breakpoint() {
+        // addi x1, x1, 1; addi x1, x1, 1
+        let mut cpu = Cpu::new(program(&[0x00108093, 0x00108093]), 1024).unwrap();
+        let mut stub = GdbStub::new(&mut cpu);
+
+        assert_eq!(reply(&mut stub, "Z0,80000004,4"), "OK");
+        assert_eq!(stub.cpu.step(), None);
+        // The ebreak stops without entering the trap handler
+        assert_eq!(
+            stub.cpu.step(),
+            Some(HaltReason::Debug(DebugEvent::Breakpoint))
+        );
+        assert_eq!(stub.cpu.pc, RAM_START + 4);
+
+        assert_eq!(reply(&mut stub, "z0,80000004,4"), "OK");
+        assert_eq!(reply(&mut stub, "m80000004,4"), "93801000");
+        assert_eq!(stub.cpu.step(), None);
+        assert_eq!(stub.cpu.read_register(1), 2);
+    }
+    #[test]
+    fn test_watchpoint() {
+        // sw x0, 0x100(a0)
+        let ram: Vec<u8> = 0x10052023u32.to_le_bytes().to_vec();
+        let mut cpu = Cpu::new(ram, 1024).unwrap();
+        cpu.write_register(10, RAM_START as u32);
+        let mut stub = GdbStub::new(&mut cpu);
+
+        assert_eq!(reply(&mut stub, "Z2,80000100,4"), "OK");
+        let halt = stub.cpu.step();
+        assert_eq!(halt_reply(halt), "T05watch:80000100;");
+        assert_eq!(stub.cpu.pc, RAM_START);
+
+        assert_eq!(reply(&mut stub, "z2,80000100,4"), "OK");
+        assert_eq!(stub.cpu.step(), None);
+        assert_eq!(stub.cpu.pc, RAM_START + 4);
+    }
+    #[test]
+    fn test_target_xml() {
+        let mut cpu = Cpu::new(vec![], 1024).unwrap();
+        let mut stub = GdbStub::new(&mut cpu);
+
+        let first = reply(&mut stub, "qXfer:features:read:target.xml:0,40");
+        assert_eq!(&first[..1], "m");
+        assert_eq!(first.len(), 0x41);
+        let length = target_xml().len();
+        let last = reply(
+            &mut stub,
+            &format!("qXfer:features:read:target.xml:{:x},1000", length - 8),
+        );
+        assert_eq!(last, "l/target>");
+    }
+}
@@ -0,0 +1,15 @@
+// Opcode and mode names follow the spelling and bit grouping of the RISC-V spec
+#![allow(clippy::upper_case_acronyms, clippy::unusual_byte_groupings)]
+
+pub mod board;
+pub mod bus;
+pub mod cpu;
+pub mod fdt;
+pub mod gdb;
+pub mod machine;
+pub mod monitor;
+pub mod terminal;
+pub mod trap;
+
+pub use cpu::HaltReason;
+pub use machine::{BuildError, Engine, Machine, MachineBuilder, StopReason};
@@ -0,0 +1,802 @@
+use core::fmt;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use tracing::{info, warn};
+
+use crate::bus::clint::{TimeSource, DEFAULT_TIMEBASE_FREQUENCY};
+use crate::bus::map::MapError;
+use crate::bus::uart::{UartInput, UartOutput};
+use crate::bus::{clint, plic, uart};
+use crate::bus::{BusError, Device, DeviceLayout};
+use crate::cpu::csr::{isa_string, EXT_OPTIONAL};
+use crate::cpu::mmu::PAGE_SIZE;
+use crate::cpu::{Cpu, ElfError, HaltReason, RAM_START};
+use crate::fdt::{self, DeviceTree, FdtWriter};
+
+pub const RAM_SIZE: usize = 64 * 1024 * 1024;
+
+// Why `Machine::run_until` handed control back to the caller
+#[derive(Debug, Clone, PartialEq)]
+pub enum StopReason {
+    LimitReached,
+    Halted(HaltReason),
+}
+
+// How instructions are executed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Engine {
+    // One instruction at a time
+    Interpreter,
+    // Basic blocks translated into closures, see `Cpu::step_block`
+    Threaded,
+}
+
+// Why `MachineBuilder::build` failed
+#[derive(Debug, Clone, PartialEq)]
+pub enum BuildError {
+    Elf(ElfError),
+    Map(MapError),
+    // Images that don't fit into RAM next to each other, or a broken DTB
+    Boot(String),
+    // Given to the builder or read from the DTB
+    TimebaseFrequency(u64),
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Elf(e) => write!(f, "{}", e),
+            Self::Map(e) => write!(f, "{}", e),
+            Self::Boot(e) => write!(f, "{}", e),
+            Self::TimebaseFrequency(frequency) => write!(
+                f,
+                "Timebase frequency {} Hz is not between 1 and {} Hz",
+                frequency,
+                u32::MAX
+            ),
+        }
+    }
+}
+
+impl From<ElfError> for BuildError {
+    fn from(e: ElfError) -> Self {
+        Self::Elf(e)
+    }
+}
+
+impl From<MapError> for BuildError {
+    fn from(e: MapError) -> Self {
+        Self::Map(e)
+    }
+}
+
+pub struct MachineBuilder {
+    ram_size: usize,
+    ram_base: usize,
+    // Named RAM regions besides the main one
+    ram_regions: Vec<(String, usize, usize)>,
+    layout: DeviceLayout,
+    extensions: u32,
+    kernel: Vec<u8>,
+    elf: Option<Vec<u8>>,
+    dtb: Option<Vec<u8>>,
+    initrd: Option<Vec<u8>>,
+    bootargs: String,
+    uart_output: Option<UartOutput>,
+    uart_input: Option<UartInput>,
+    time_source: TimeSource,
+    timebase_frequency: Option<u64>,
+    engine: Engine,
+    test: bool,
+}
+
+impl MachineBuilder {
+    pub fn new() -> Self {
+        Self {
+            ram_size: RAM_SIZE,
+            ram_base: RAM_START,
+            ram_regions: Vec::new(),
+            layout: DeviceLayout::default(),
+            extensions: EXT_OPTIONAL,
+            kernel: Vec::new(),
+            elf: None,
+            dtb: None,
+            initrd: None,
+            bootargs: String::new(),
+            uart_output: None,
+            uart_input: None,
+            time_source: TimeSource::WallClock,
+            timebase_frequency: None,
+            engine: Engine::Interpreter,
+            test: false,
+        }
+    }
+
+    pub fn ram_size(mut self, ram_size: usize) -> Self {
+        self.ram_size = ram_size;
+        self
+    }
+
+    pub fn ram_base(mut self, ram_base: usize) -> Self {
+        self.ram_base = ram_base;
+        self
+    }
+
+    // Further RAM, which images are not loaded into
+    pub fn ram_region(mut self, name: &str, base: usize, size: usize) -> Self {
+        self.ram_regions.push((name.to_string(), base, size));
+        self
+    }
+
+    pub fn layout(mut self, layout: DeviceLayout) -> Self {
+        self.layout = layout;
+        self
+    }
+
+    // Optional extensions (M, A, F, D, C and the Zb* ones), all of them by default
+    pub fn extensions(mut self, extensions: u32) -> Self {
+        self.extensions = extensions;
+        self
+    }
+
+    // Raw image that is copied to the start of RAM
+    pub fn kernel(mut self, kernel: Vec<u8>) -> Self {
+        self.kernel = kernel;
+        self
+    }
+
+    pub fn elf(mut self, elf: Vec<u8>) -> Self {
+        self.elf = Some(elf);
+        self
+    }
+
+    // Placed at the end of RAM with a pointer to it in a1
+    pub fn dtb(mut self, dtb: Vec<u8>) -> Self {
+        self.dtb = Some(dtb);
+        self
+    }
+
+    // Placed below the DTB, which tells the kernel where it is
+    pub fn initrd(mut self, initrd: Vec<u8>) -> Self {
+        self.initrd = Some(initrd);
+        self
+    }
+
+    // Kernel command line, also set in a DTB that is given
+    pub fn bootargs(mut self, bootargs: &str) -> Self {
+        self.bootargs = bootargs.to_string();
+        self
+    }
+
+    // Device tree that describes the configured machine, for kernels that
+    // come without a DTB of their own
+    pub fn generate_dtb(&self) -> Vec<u8> {
+        const CPU_INTC_PHANDLE: u32 = 1;
+        const PLIC_PHANDLE: u32 = 2;
+        // Causes of the interrupts that the CLINT and the PLIC raise at the hart
+        const MSI: u32 = 3;
+        const MTI: u32 = 7;
+        const SEI: u32 = 9;
+        const MEI: u32 = 11;
+        // Not used for anything, but required by Linux' 8250 driver
+        const UART_CLOCK: u32 = 3_686_400;
+
+        let mut fdt = FdtWriter::new();
+        fdt.begin_node("");
+        fdt.property_u32("#address-cells", 1);
+        fdt.property_u32("#size-cells", 1);
+        fdt.property_string("compatible", "riscv-emu");
+        fdt.property_string("model", "riscv_emu");
+
+        fdt.begin_node("chosen");
+        fdt.property_string("bootargs", &self.bootargs);
+        if let Some(base) = self.layout.uart {
+            fdt.property_string("stdout-path", &format!("/soc/serial@{:x}", base));
+        }
+        fdt.end_node();
+
+        let ram = (self.ram_base, self.ram_size);
+        let extra_ram = self
+            .ram_regions
+            .iter()
+            .map(|(_, base, size)| (*base, *size));
+        for (base, size) in std::iter::once(ram).chain(extra_ram) {
+            fdt.begin_node(&format!("memory@{:x}", base));
+            fdt.property_string("device_type", "memory");
+            fdt.property_cells("reg", &[base as u32, size as u32]);
+            fdt.end_node();
+        }
+
+        fdt.begin_node("cpus");
+        fdt.property_u32("#address-cells", 1);
+        fdt.property_u32("#size-cells", 0);
+        let timebase_frequency = self
+            .timebase_frequency
+            .unwrap_or(DEFAULT_TIMEBASE_FREQUENCY);
+        fdt.property_u32("timebase-frequency", timebase_frequency as u32);
+        fdt.begin_node("cpu@0");
+        fdt.property_string("device_type", "cpu");
+        fdt.property_u32("reg", 0);
+        fdt.property_string("status", "okay");
+        fdt.property_string("compatible", "riscv");
+        fdt.property_string("riscv,isa", &isa_string(self.extensions));
+        fdt.property_string("mmu-type", "riscv,sv32");
+        fdt.begin_node("interrupt-controller");
+        fdt.property_u32("#interrupt-cells", 1);
+        fdt.property_empty("interrupt-controller");
+        fdt.property_string("compatible", "riscv,cpu-intc");
+        fdt.property_u32("phandle", CPU_INTC_PHANDLE);
+        fdt.end_node();
+        fdt.end_node();
+        fdt.end_node();
+
+        fdt.begin_node("soc");
+        fdt.property_u32("#address-cells", 1);
+        fdt.property_u32("#size-cells", 1);
+        fdt.property_string("compatible", "simple-bus");
+        fdt.property_empty("ranges");
+        if let Some(base) = self.layout.clint {
+            fdt.begin_node(&format!("clint@{:x}", base));
+            fdt.property_strings("compatible", &["sifive,clint0", "riscv,clint0"]);
+            fdt.property_cells("reg", &[base as u32, clint::SIZE as u32]);
+            fdt.property_cells(
+                "interrupts-extended",
+                &[CPU_INTC_PHANDLE, MSI, CPU_INTC_PHANDLE, MTI],
+            );
+            fdt.end_node();
+        }
+        if let Some(base) = self.layout.plic {
+            fdt.begin_node(&format!("plic@{:x}", base));
+            fdt.property_strings("compatible", &["sifive,plic-1.0.0", "riscv,plic0"]);
+            fdt.property_cells("reg", &[base as u32, plic::SIZE as u32]);
+            fdt.property_u32("#address-cells", 0);
+            fdt.property_u32("#interrupt-cells", 1);
+            fdt.property_empty("interrupt-controller");
+            fdt.property_cells(
+                "interrupts-extended",
+                &[CPU_INTC_PHANDLE, MEI, CPU_INTC_PHANDLE, SEI],
+            );
+            fdt.property_u32("riscv,ndev", plic::SOURCES as u32 - 1);
+            fdt.property_u32("phandle", PLIC_PHANDLE);
+            fdt.end_node();
+        }
+        if let Some(base) = self.layout.uart {
+            fdt.begin_node(&format!("serial@{:x}", base));
+            fdt.property_string("compatible", "ns16550a");
+            fdt.property_cells("reg", &[base as u32, uart::SIZE as u32]);
+            fdt.property_u32("clock-frequency", UART_CLOCK);
+            if self.layout.plic.is_some() {
+                fdt.property_u32("interrupt-parent", PLIC_PHANDLE);
+                fdt.property_u32("interrupts", self.layout.uart_irq as u32);
+            }
+            fdt.end_node();
+        }
+        fdt.end_node();
+
+        fdt.end_node();
+        fdt.finish()
+    }
+
+    pub fn uart_output(mut self, output: UartOutput) -> Self {
+        self.uart_output = Some(output);
+        self
+    }
+
+    pub fn uart_input(mut self, input: UartInput) -> Self {
+        self.uart_input = Some(input);
+        self
+    }
+
+    pub fn time_source(mut self, time_source: TimeSource) -> Self {
+        self.time_source = time_source;
+        self
+    }
+
+    // Rate of mtime in Hz, written into the DTB's timebase-frequency. Without
+    // it, the DTB's value is used.
+    pub fn timebase_frequency(mut self, timebase_frequency: u64) -> Self {
+        self.timebase_frequency = Some(timebase_frequency);
+        self
+    }
+
+    pub fn engine(mut self, engine: Engine) -> Self {
+        self.engine = engine;
+        self
+    }
+
+    // Stop on the ecall that riscv-tests use to report their result
+    pub fn test(mut self, test: bool) -> Self {
+        self.test = test;
+        self
+    }
+
+    pub fn build(self) -> Result<Machine, BuildError> {
+        let kernel_size = self.kernel.len();
+        let mut cpu = Cpu::with_layout(self.kernel, self.ram_size, self.ram_base, self.layout)?;
+        for (name, base, size) in self.ram_regions {
+            cpu.add_ram(&name, base, size)?;
+        }
+        cpu.set_extensions(self.extensions);
+        cpu.test = self.test;
+        if let Some(output) = self.uart_output {
+            cpu.set_uart_output(output);
+        }
+        if let Some(input) = self.uart_input {
+            cpu.set_uart_input(input);
+        }
+        let mut kernel_end = self.ram_base + kernel_size;
+        if let Some(elf) = self.elf {
+            kernel_end = kernel_end.max(cpu.load_elf(elf)?);
+        }
+
+        let mut dtb = self.dtb;
+        if dtb.is_none() && self.initrd.is_some() {
+            return Err(BuildError::Boot(
+                "An initrd can only be passed to the kernel in a DTB".to_string(),
+            ));
+        }
+        // Bootargs and the initrd go into /chosen, which may change the size of the DTB
+        if let Some(blob) = dtb.as_ref() {
+            if !self.bootargs.is_empty() || self.initrd.is_some() {
+                let mut tree = DeviceTree::parse(blob)
+                    .ok_or_else(|| BuildError::Boot("Invalid DTB".to_string()))?;
+                let chosen = tree.root.child_mut("chosen");
+                if !self.bootargs.is_empty() {
+                    let mut bootargs = self.bootargs.clone().into_bytes();
+                    bootargs.push(0);
+                    chosen.set_property("bootargs", bootargs);
+                }
+                if self.initrd.is_some() {
+                    chosen.set_property("linux,initrd-start", vec![0; 4]);
+                    chosen.set_property("linux,initrd-end", vec![0; 4]);
+                }
+                dtb = Some(tree.to_blob());
+            }
+        }
+
+        // The guest learns the rate of mtime from the DTB, so both must agree
+        let timebase_frequency = match (self.timebase_frequency, dtb.as_mut()) {
+            (Some(frequency), Some(dtb)) => {
+                if fdt::write_int_property(dtb, "timebase-frequency", frequency) == 0 {
+                    warn!(
+                        "DTB has no timebase-frequency property that can hold {}",
+                        frequency
+                    );
+                }
+                frequency
+            }
+            (Some(frequency), None) => frequency,
+            (None, Some(dtb)) => fdt::read_int_property(dtb, "timebase-frequency")
+                .unwrap_or(DEFAULT_TIMEBASE_FREQUENCY),
+            (None, None) => DEFAULT_TIMEBASE_FREQUENCY,
+        };
+        if !clint::valid_timebase_frequency(timebase_frequency) {
+            return Err(BuildError::TimebaseFrequency(timebase_frequency));
+        }
+        cpu.set_timebase_frequency(timebase_frequency);
+        cpu.set_time_source(self.time_source);
+        if let Some(mut dtb) = dtb {
+            // The DTB goes to the top of RAM, the initrd right below it
+            let ram_end = self.ram_base + self.ram_size;
+            let dtb_start = ram_end
+                .checked_sub(dtb.len())
+                .map(|start| start & !7)
+                .filter(|start| *start >= kernel_end)
+                .ok_or_else(|| BuildError::Boot("The DTB overlaps the kernel".to_string()))?;
+            if let Some(initrd) = &self.initrd {
+                let initrd_start = dtb_start
+                    .checked_sub(initrd.len())
+                    .map(|start| start & !(PAGE_SIZE - 1))
+                    .filter(|start| *start >= kernel_end)
+                    .ok_or_else(|| {
+                        BuildError::Boot("The initrd overlaps the kernel".to_string())
+                    })?;
+                let initrd_end = initrd_start + initrd.len();
+                info!(
+                    "Loading initrd at {:#10x} with size {}",
+                    initrd_start,
+                    initrd.len()
+                );
+                cpu.load_image(initrd_start, initrd);
+                // Same size as the placeholders, so the DTB doesn't move
+                fdt::write_int_property(&mut dtb, "linux,initrd-start", initrd_start as u64);
+                fdt::write_int_property(&mut dtb, "linux,initrd-end", initrd_end as u64);
+            }
+            cpu.load_dtb(dtb_start, &dtb);
+        }
+        Ok(Machine {
+            cpu,
+            engine: self.engine,
+            instructions: 0,
+        })
+    }
+}
+
+impl Default for MachineBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct Machine {
+    cpu: Cpu,
+    engine: Engine,
+    instructions: u64,
+}
+
+impl Machine {
+    pub fn builder() -> MachineBuilder {
+        MachineBuilder::new()
+    }
+
+    // Execute at most `limit` instructions
+    pub fn run_until(&mut self, limit: u64) -> StopReason {
+        let mut remaining = limit;
+        while remaining > 0 {
+            let (executed, halt) = match self.engine {
+                Engine::Interpreter => (1, self.cpu.step()),
+                Engine::Threaded => self.cpu.step_block(remaining),
+            };
+            self.instructions += executed;
+            remaining -= executed;
+            if let Some(halt) = halt {
+                return StopReason::Halted(halt);
+            }
+        }
+        info!("Stopped after {} instructions", limit);
+        StopReason::LimitReached
+    }
+
+    // Number of steps taken so far, including ones that trapped
+    pub fn instructions(&self) -> u64 {
+        self.instructions
+    }
+
+    // Timer ticks that the hart spent waiting in WFI
+    pub fn idle_ticks(&self) -> u64 {
+        self.cpu.idle_ticks()
+    }
+
+    pub fn pc(&self) -> usize {
+        self.cpu.pc
+    }
+
+    pub fn set_pc(&mut self, pc: usize) {
+        self.cpu.pc = pc;
+    }
+
+    pub fn read_register(&self, num: usize) -> u32 {
+        self.cpu.read_register(num)
+    }
+
+    pub fn write_register(&mut self, num: usize, value: u32) {
+        self.cpu.write_register(num, value);
+    }
+
+    pub fn read_csr(&self, addr: u32) -> u32 {
+        self.cpu.read_csr(addr)
+    }
+
+    pub fn write_csr(&mut self, addr: u32, value: u32) {
+        self.cpu.write_csr(addr, value);
+    }
+
+    // Physical memory accesses that bypass the MMU and PMP
+    pub fn read_memory(&mut self, addr: usize, data: &mut [u8]) -> Result<(), BusError> {
+        for (offset, byte) in data.iter_mut().enumerate() {
+            *byte = self.cpu.read_memory(addr + offset)?;
+        }
+        Ok(())
+    }
+
+    pub fn write_memory(&mut self, addr: usize, data: &[u8]) -> Result<(), BusError> {
+        for (offset, byte) in data.iter().enumerate() {
+            self.cpu.write_memory(addr + offset, *byte)?;
+        }
+        Ok(())
+    }
+
+    // Add a device to the memory map. It sees absolute addresses.
+    pub fn register_device(
+        &mut self,
+        name: &str,
+        base: usize,
+        size: usize,
+        device: Rc<RefCell<dyn Device>>,
+    ) -> Result<(), MapError> {
+        self.cpu.register_device(name, base, size, device)
+    }
+
+    // Direct access to the core, e.g. for attaching a debugger
+    pub fn cpu(&mut self) -> &mut Cpu {
+        &mut self.cpu
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::board::Board;
+    use crate::cpu::csr::MISA_OPTIONAL;
+    use crate::cpu::tests::program;
+
+    #[test]
+    fn test_uart_output() {
+        // lui a0, 0x10000; addi a1, zero, 'h'; sb a1, 0(a0)
+        let kernel = program(&[0x10000537, 0x06800593, 0x00b50023]);
+        let written = Rc::new(RefCell::new(Vec::new()));
+        let sink = written.clone();
+        let mut machine = Machine::builder()
+            .ram_size(0x1000)
+            .kernel(kernel)
+            .uart_output(Box::new(move |c| sink.borrow_mut().push(c)))
+            .build()
+            .unwrap();
+
+        assert_eq!(machine.run_until(2), StopReason::LimitReached);
+        assert_eq!(machine.pc(), RAM_START + 8);
+        assert!(written.borrow().is_empty());
+        assert_eq!(machine.run_until(1), StopReason::LimitReached);
+        assert_eq!(*written.borrow(), b"h");
+    }
+    #[test]
+    fn test_board() {
+        let board = Board::from_toml(
+            "isa = \"rv32i\"\n[[memory]]\nbase = 0x8000_0000\nsize = 0x1000\n\
+             [[devices]]\ntype = \"ns16550a\"\nbase = 0x2000_0000",
+        )
+        .unwrap();
+        // lui a0, 0x20000; addi a1, zero, 'h'; sb a1, 0(a0); mul a2, a1, a1
+        let kernel = program(&[0x20000537, 0x06800593, 0x00b50023, 0x02b58633]);
+        let written = Rc::new(RefCell::new(Vec::new()));
+        let sink = written.clone();
+        let mut machine = board
+            .configure(Machine::builder())
+            .kernel(kernel)
+            .uart_output(Box::new(move |c| sink.borrow_mut().push(c)))
+            .build()
+            .unwrap();
+
+        assert_eq!(machine.run_until(4), StopReason::LimitReached);
+        assert_eq!(*written.borrow(), b"h");
+        // M is disabled, so mul is illegal
+        assert_eq!(machine.read_csr(0x342), 2);
+        assert_eq!(machine.read_csr(0x301) & MISA_OPTIONAL, 0);
+        assert_eq!(
+            Machine::builder()
+                .ram_base(0x1000_0000)
+                .ram_size(0x1000)
+                .build()
+                .err(),
+            Some(BuildError::Map(MapError::Overlap(
+                "uart".to_string(),
+                "ram".to_string()
+            )))
+        );
+        assert_eq!(
+            Machine::builder()
+                .ram_size(0x100)
+                .kernel(vec![0; 0x1000])
+                .build()
+                .err(),
+            Some(BuildError::Boot("kernel larger than RAM".to_string()))
+        );
+    }
+    #[test]
+    fn test_generated_dtb() {
+        let builder = Machine::builder()
+            .ram_size(0x10000)
+            .timebase_frequency(10_000_000)
+            .bootargs("console=ttyS0");
+        let dtb = builder.generate_dtb();
+        assert_eq!(
+            fdt::read_int_property(&dtb, "timebase-frequency"),
+            Some(10_000_000)
+        );
+        assert_eq!(fdt::read_int_property(&dtb, "interrupts"), Some(10));
+        assert!(dtb.windows(14).any(|w| w == b"console=ttyS0\0"));
+        assert!(dtb
+            .windows(27)
+            .any(|w| w == b"rv32imafdc_zba_zbb_zbc_zbs\0"));
+
+        let mut machine = builder.dtb(dtb.clone()).build().unwrap();
+        let mut loaded = vec![0; dtb.len()];
+        let dtb_start = machine.read_register(11) as usize;
+        machine.read_memory(dtb_start, &mut loaded).unwrap();
+        assert_eq!(loaded, dtb);
+    }
+    #[test]
+    fn test_initrd() {
+        let dtb = Machine::builder().ram_size(0x10000).generate_dtb();
+        let mut machine = Machine::builder()
+            .ram_size(0x10000)
+            .kernel(vec![0x13; 0x100])
+            .dtb(dtb.clone())
+            .initrd(vec![0xaa; 0x1800])
+            .bootargs("root=/dev/ram")
+            .build()
+            .unwrap();
+
+        let dtb_start = machine.read_register(11) as usize;
+        // The DTB grew by the properties in /chosen
+        let mut loaded = vec![0; RAM_START + 0x10000 - dtb_start];
+        machine.read_memory(dtb_start, &mut loaded).unwrap();
+        let tree = DeviceTree::parse(&loaded).unwrap();
+        let chosen = tree
+            .root
+            .children
+            .iter()
+            .find(|n| n.name == "chosen")
+            .unwrap();
+        assert_eq!(chosen.property("bootargs"), Some(&b"root=/dev/ram\0"[..]));
+        let start = fdt::read_int_property(&loaded, "linux,initrd-start").unwrap() as usize;
+        let end = fdt::read_int_property(&loaded, "linux,initrd-end").unwrap() as usize;
+        assert_eq!((start % PAGE_SIZE, end - start), (0, 0x1800));
+        assert!(end <= dtb_start);
+        let mut initrd = [0; 2];
+        machine.read_memory(end - 1, &mut initrd).unwrap();
+        assert_eq!(initrd, [0xaa, 0]);
+
+        let result = Machine::builder()
+            .ram_size(0x10000)
+            .kernel(vec![0x13; 0xf000])
+            .dtb(dtb)
+            .initrd(vec![0xaa; 0x1000])
+            .build();
+        assert!(matches!(result.err(), Some(BuildError::Boot(_))));
+    }
+    #[test]
+    fn test_ram_base_and_memory() {
+        let mut machine = Machine::builder()
+            .ram_base(0x2000_0000)
+            .ram_size(0x1000)
+            .build()
+            .unwrap();
+        assert_eq!(machine.pc(), 0x2000_0000);
+
+        assert_eq!(machine.write_memory(0x2000_0010, &[1, 2, 3, 4]), Ok(()));
+        let mut data = [0u8; 4];
+        assert_eq!(machine.read_memory(0x2000_0010, &mut data), Ok(()));
+        assert_eq!(data, [1, 2, 3, 4]);
+        assert_eq!(
+            machine.read_memory(RAM_START, &mut data),
+            Err(BusError::AddressUnmapped(RAM_START))
+        );
+
+        machine.write_register(5, 0xdead_beef);
+        assert_eq!(machine.read_register(5), 0xdead_beef);
+    }
+    #[test]
+    fn test_riscv_tests_result() {
+        let elf = include_bytes!("../tests/rv32ui-p-add").to_vec();
+        let mut machine = Machine::builder()
+            .ram_size(0x2000)
+            .elf(elf)
+            .test(true)
+            .build()
+            .unwrap();
+        assert_eq!(
+            machine.run_until(100_000),
+            StopReason::Halted(HaltReason::TestPassed)
+        );
+        assert!(machine.instructions() < 100_000);
+    }
+    #[test]
+    fn test_threaded_engine() {
+        for test in [
+            include_bytes!("../tests/rv32ui-p-add").to_vec(),
+            include_bytes!("../tests/rv32uc-p-rvc").to_vec(),
+            include_bytes!("../tests/rv32si-p-dirty").to_vec(),
+        ] {
+            let mut machine = Machine::builder()
+                .ram_size(0x4000)
+                .elf(test)
+                .engine(Engine::Threaded)
+                .test(true)
+                .build()
+                .unwrap();
+            assert_eq!(
+                machine.run_until(100_000),
+                StopReason::Halted(HaltReason::TestPassed)
+            );
+        }
+    }
+    #[test]
+    fn test_wfi_idle() {
+        // wfi with all interrupts disabled
+        let mut machine = Machine::builder()
+            .ram_size(0x1000)
+            .kernel(program(&[0x10500073]))
+            .build()
+            .unwrap();
+        assert_eq!(
+            machine.run_until(10),
+            StopReason::Halted(HaltReason::WaitForInterrupt)
+        );
+        assert_eq!(machine.pc(), RAM_START + 4);
+    }
+    #[test]
+    fn test_virtual_time_is_deterministic() {
+        // Count loop iterations until mtime passes 1000:
+        // lui a0, 0x1100c; addi a1, zero, 1000; addi a2, a2, 1;
+        // lw a3, 0xff8(a0); bltu a3, a1, -8; ebreak
+        let kernel = program(&[
+            0x1100c537, 0x3e800593, 0x00160613, 0xff852683, 0xfeb6ece3, 0x00100073,
+        ]);
+        let iterations = || {
+            let mut machine = Machine::builder()
+                .ram_size(0x1000)
+                .kernel(kernel.clone())
+                .time_source(TimeSource::Virtual(7))
+                .build()
+                .unwrap();
+            machine.run_until(2000);
+            machine.read_register(12)
+        };
+        // mtime is 7 * n when the n-th instruction executes
+        assert_eq!(iterations(), 48);
+        assert_eq!(iterations(), 48);
+    }
+    #[test]
+    fn test_wfi_timer_wakeup() {
+        // Set mtimecmp to 1000 and enable MTIE, then wfi; addi a4, zero, 1
+        let kernel = program(&[
+            0x11004537, 0x3e800593, 0x00b52023, 0x00052223, 0x08000613, 0x30461073, 0x10500073,
+            0x00100713,
+        ]);
+        let mut machine = Machine::builder()
+            .ram_size(0x1000)
+            .kernel(kernel)
+            .time_source(TimeSource::Virtual(1))
+            .build()
+            .unwrap();
+        assert_eq!(machine.run_until(8), StopReason::LimitReached);
+        assert!(machine.cpu().is_waiting());
+        assert_eq!(machine.pc(), RAM_START + 28);
+
+        // mtime jumped from 8 straight to mtimecmp, the pending MTIP wakes the
+        // hart even though mstatus.MIE is clear
+        assert_eq!(machine.idle_ticks(), 992);
+        assert_eq!(machine.run_until(1), StopReason::LimitReached);
+        assert!(!machine.cpu().is_waiting());
+        assert_eq!(machine.read_register(14), 1);
+    }
+    #[test]
+    fn test_modified_code() {
+        // addi a0, a0, 1; j -4
+        let mut machine = Machine::builder()
+            .ram_size(0x1000)
+            .kernel(program(&[0x00150513, 0xffdff06f]))
+            .build()
+            .unwrap();
+        machine.run_until(2);
+        assert_eq!(machine.read_register(10), 1);
+
+        // The cached decoding of the addi must not survive the write
+        let addi_16 = 0x01050513u32.to_le_bytes();
+        assert_eq!(machine.write_memory(RAM_START, &addi_16), Ok(()));
+        machine.run_until(2);
+        assert_eq!(machine.read_register(10), 17);
+    }
+    #[test]
+    fn test_invalid_timebase_frequency() {
+        for frequency in [0, 1 << 32].iter() {
+            let result = Machine::builder().timebase_frequency(*frequency).build();
+            assert_eq!(
+                result.err(),
+                Some(BuildError::TimebaseFrequency(*frequency))
+            );
+        }
+        // Also when it comes from the DTB
+        let mut dtb = Machine::builder().ram_size(0x10000).generate_dtb();
+        fdt::write_int_property(&mut dtb, "timebase-frequency", 0);
+        let result = Machine::builder().ram_size(0x10000).dtb(dtb).build();
+        assert_eq!(result.err(), Some(BuildError::TimebaseFrequency(0)));
+    }
+    #[test]
+    fn test_elf_error() {
+        let result = Machine::builder().elf(vec![0u8; 4]).build();
+        assert!(matches!(result, Err(BuildError::Elf(ElfError::Parse(_)))));
+    }
+}
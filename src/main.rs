@@ -1,14 +1,18 @@
 use clap::Parser;
-use std::{fs, vec};
-
-use cpu::Cpu;
-use tracing::Level;
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::time::Instant;
+
+use riscv_emu::board::Board;
+use riscv_emu::bus::clint::{self, TimeSource};
+use riscv_emu::gdb::Session;
+use riscv_emu::machine::RAM_SIZE;
+use riscv_emu::terminal::{self, RawTerminal};
+use riscv_emu::{gdb, monitor, Engine, HaltReason, Machine, StopReason};
+use tracing::{error, info, warn, Level};
 use tracing_subscriber::FmtSubscriber;
 
-mod bus;
-mod cpu;
-mod trap;
-
 fn parse_level(s: &str) -> Result<Level, String> {
     s.parse::<Level>().map_err(|_| {
         format!(
@@ -18,34 +22,91 @@ fn parse_level(s: &str) -> Result<Level, String> {
     })
 }
 
+fn parse_engine(s: &str) -> Result<Engine, String> {
+    match s {
+        "interpreter" => Ok(Engine::Interpreter),
+        "threaded" => Ok(Engine::Threaded),
+        _ => Err(format!(
+            "'{}' is not a valid engine. Possible values are: interpreter, threaded.",
+            s
+        )),
+    }
+}
+
+fn parse_timebase_frequency(s: &str) -> Result<u64, String> {
+    match s.parse::<u64>() {
+        Ok(frequency) if clint::valid_timebase_frequency(frequency) => Ok(frequency),
+        _ => Err(format!(
+            "'{}' is not a valid timebase frequency. It has to be between 1 and {} Hz.",
+            s,
+            u32::MAX
+        )),
+    }
+}
+
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
+    /// Machine description (TOML, or JSON with a .json extension) with memory,
+    /// devices, ISA and boot images. Images given on the command line take precedence.
+    #[arg(long)]
+    machine: Option<PathBuf>,
+
     #[arg(short, long)]
     kernel: Option<String>,
 
     #[arg(short, long)]
     elf: Option<String>,
 
+    /// Device tree for the kernel. Without it, raw kernels get one that is
+    /// generated from the machine configuration.
     #[arg(long)]
     dtb: Option<String>,
 
-    #[arg(short, long, default_value_t = 0)]
-    delay: u64,
+    /// Write the generated device tree to this file and exit
+    #[arg(long)]
+    dump_dtb: Option<PathBuf>,
+
+    /// Kernel command line, stored in /chosen/bootargs of the device tree
+    #[arg(long)]
+    append: Option<String>,
+
+    /// Initial ramdisk, loaded below the device tree and announced in /chosen
+    #[arg(long)]
+    initrd: Option<PathBuf>,
 
     #[arg(short, long, default_value_t = 0)]
     instructions: u64,
 
+    /// Advance mtime by this many ticks per instruction instead of following
+    /// the host clock, which makes runs reproducible
+    #[arg(long)]
+    virtual_time: Option<u64>,
+
+    /// Rate of mtime in Hz, also patched into the DTB (default: the DTB's value or 1 MHz)
+    #[arg(long, value_parser = parse_timebase_frequency)]
+    timebase_frequency: Option<u64>,
+
     #[arg(short, long, default_value_t = false)]
     test: bool,
 
+    /// Execution engine: interpreter or threaded (translated basic blocks)
+    #[arg(long, default_value = "interpreter", value_parser = parse_engine)]
+    engine: Engine,
+
+    /// Wait for a GDB connection on this TCP port before running
+    #[arg(long)]
+    gdb: Option<u16>,
+
+    /// Start an interactive monitor instead of running freely
+    #[arg(long, default_value_t = false)]
+    monitor: bool,
+
     #[arg(long, default_value_t = Level::INFO, value_parser = parse_level)]
     log_level: Level,
 }
 
-const RAM_SIZE: usize = 64 * 1024 * 1024; // 256 KiB
-
-fn load_from_bin(bin_path: &String) -> Vec<u8> {
+fn load_from_bin<P: AsRef<std::path::Path>>(bin_path: P) -> Vec<u8> {
     fs::read(bin_path).unwrap()
 }
 
@@ -60,25 +121,121 @@ fn main() {
     tracing::subscriber::set_global_default(subscriber)
         .expect("msg: Failed to set global subscriber");
 
-    let mut kernel = vec![];
+    let mut builder = Machine::builder()
+        .ram_size(RAM_SIZE)
+        .engine(args.engine)
+        .test(args.test);
+
+    let mut kernel = args.kernel.map(PathBuf::from);
+    let mut elf = args.elf.map(PathBuf::from);
+    let mut dtb = args.dtb.map(PathBuf::from);
+    let mut initrd = args.initrd;
+    if let Some(path) = args.machine {
+        let board = Board::load(&path).unwrap_or_else(|e| {
+            error!("{}", e);
+            std::process::exit(1);
+        });
+        builder = board.configure(builder);
+        kernel = kernel.or(board.boot.kernel);
+        elf = elf.or(board.boot.elf);
+        dtb = dtb.or(board.boot.dtb);
+        initrd = initrd.or(board.boot.initrd);
+    }
 
-    if let Some(kernel_path) = args.kernel {
-        kernel = load_from_bin(&kernel_path);
+    if let Some(ticks) = args.virtual_time {
+        builder = builder.time_source(TimeSource::Virtual(ticks));
+    }
+    if let Some(frequency) = args.timebase_frequency {
+        builder = builder.timebase_frequency(frequency);
     }
 
-    let mut cpu = Cpu::new(kernel, RAM_SIZE);
-    cpu.delay = args.delay;
-    cpu.instruction_count = args.instructions;
-    cpu.test = args.test;
+    let has_kernel = kernel.is_some();
+    if let Some(kernel_path) = kernel {
+        builder = builder.kernel(load_from_bin(kernel_path));
+    }
+    let mut symbols = HashMap::new();
+    if let Some(elf_path) = elf {
+        let elf_bytes = load_from_bin(elf_path);
+        symbols = monitor::elf_symbols(&elf_bytes);
+        builder = builder.elf(elf_bytes);
+    }
+    if let Some(initrd_path) = &initrd {
+        builder = builder.initrd(load_from_bin(initrd_path));
+    }
+    if let Some(bootargs) = &args.append {
+        builder = builder.bootargs(bootargs);
+    }
+    if let Some(dump_path) = args.dump_dtb {
+        if let Err(e) = fs::write(&dump_path, builder.generate_dtb()) {
+            error!("Failed to write {}: {}", dump_path.display(), e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    match dtb {
+        Some(dtb_path) => builder = builder.dtb(load_from_bin(dtb_path)),
+        None if has_kernel || initrd.is_some() => {
+            let generated = builder.generate_dtb();
+            builder = builder.dtb(generated);
+        }
+        None => {}
+    }
 
-    if let Some(elf_path) = args.elf {
-        cpu.load_elf(load_from_bin(&elf_path));
+    // The monitor needs stdin for itself
+    if !args.monitor {
+        builder = builder.uart_input(terminal::stdin_input());
     }
-    if let Some(dtb_path) = args.dtb {
-        cpu.load_dtb(load_from_bin(&dtb_path));
+
+    let mut machine = builder.build().unwrap_or_else(|e| {
+        error!("Failed to build the machine: {}", e);
+        std::process::exit(1);
+    });
+
+    if let Some(port) = args.gdb {
+        if gdb::serve(machine.cpu(), port).expect("GDB connection failed") == Session::Killed {
+            return;
+        }
     }
 
-    loop {
-        let _ = cpu.step();
+    if args.monitor {
+        monitor::run(machine.cpu(), symbols).expect("Monitor failed");
+        return;
     }
+
+    let limit = match args.instructions {
+        0 => u64::MAX,
+        limit => limit,
+    };
+    let raw_terminal = RawTerminal::enable();
+    let start = Instant::now();
+    let reason = machine.run_until(limit);
+    let elapsed = start.elapsed();
+    drop(raw_terminal);
+    info!(
+        "Executed {} instructions in {:.2?} ({:.2} MIPS)",
+        machine.instructions(),
+        elapsed,
+        machine.instructions() as f64 / elapsed.as_secs_f64() / 1e6
+    );
+    info!("Idled for {} timer ticks in WFI", machine.idle_ticks());
+
+    let exit_code = match reason {
+        StopReason::LimitReached => {
+            machine.cpu().dump_state();
+            warn!("Limit of {} instructions reached. Exiting.", limit);
+            0
+        }
+        StopReason::Halted(HaltReason::TestPassed) => 0,
+        StopReason::Halted(HaltReason::TestFailed(code)) => code,
+        StopReason::Halted(HaltReason::WaitForInterrupt) => {
+            warn!("Waiting for an interrupt with all interrupts disabled. Exiting.");
+            0
+        }
+        StopReason::Halted(halt) => {
+            machine.cpu().dump_state();
+            error!("Stopped by {:?}", halt);
+            1
+        }
+    };
+    std::process::exit(exit_code);
 }
@@ -0,0 +1,407 @@
+use std::collections::{BTreeSet, HashMap};
+use std::io::{self, BufRead, Write};
+
+use goblin::elf::Elf;
+use num_traits::FromPrimitive;
+
+use crate::cpu::csr::ArchCSRs;
+use crate::cpu::decoder::{decode, is_compressed};
+use crate::cpu::instructions::pretty_register;
+use crate::cpu::Cpu;
+
+// Number of executed instructions kept for the history command
+const HISTORY_SIZE: usize = 64;
+
+const HELP: &str = "\
+step [n]             execute n instructions (default 1)
+continue             run until a breakpoint is hit
+break <addr|symbol>  set a breakpoint
+delete <addr|symbol> remove a breakpoint
+info                 list breakpoints
+regs                 print registers
+csr <name|addr>      print a CSR
+x <addr> [n]         examine n words of memory
+dis [addr] [n]       disassemble n instructions (default at pc)
+set <reg|pc> <value> write a register
+write <addr> <value> write a word to memory
+history [n]          show the last n executed instructions
+quit                 exit the emulator";
+
+pub struct Monitor<'a> {
+    cpu: &'a mut Cpu,
+    symbols: HashMap<String, usize>,
+    breakpoints: BTreeSet<usize>,
+}
+
+// Collect the function and object symbols of an ELF image
+pub fn elf_symbols(elf_bytes: &[u8]) -> HashMap<String, usize> {
+    let mut symbols = HashMap::new();
+    if let Ok(elf) = Elf::parse(elf_bytes) {
+        for sym in elf.syms.iter() {
+            if let Some(name) = elf.strtab.get_at(sym.st_name) {
+                if !name.is_empty() && sym.st_value != 0 {
+                    symbols.insert(name.to_string(), sym.st_value as usize);
+                }
+            }
+        }
+    }
+    symbols
+}
+
+pub fn run(cpu: &mut Cpu, symbols: HashMap<String, usize>) -> io::Result<()> {
+    let mut monitor = Monitor::new(cpu, symbols);
+    let stdin = io::stdin();
+    let mut stdout = io::stdout();
+    loop {
+        write!(stdout, "({:#010x}) > ", monitor.cpu.pc)?;
+        stdout.flush()?;
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            break;
+        }
+        if !monitor.execute(line.trim(), &mut stdout)? {
+            break;
+        }
+    }
+    Ok(())
+}
+
+impl<'a> Monitor<'a> {
+    pub fn new(cpu: &'a mut Cpu, symbols: HashMap<String, usize>) -> Self {
+        // ebreak in the guest drops back into the monitor
+        cpu.debug.attached = true;
+        cpu.debug.history_size = HISTORY_SIZE;
+        Self {
+            cpu,
+            symbols,
+            breakpoints: BTreeSet::new(),
+        }
+    }
+
+    // Run a single command. Returns false once the user wants to quit.
+    pub fn execute(&mut self, line: &str, out: &mut impl Write) -> io::Result<bool> {
+        let args: Vec<&str> = line.split_whitespace().collect();
+        let (command, args) = match args.split_first() {
+            Some((command, args)) => (*command, args),
+            None => return Ok(true),
+        };
+        let result = match command {
+            "s" | "step" => self.step(args, out),
+            "c" | "continue" => self.resume(None, out),
+            "b" | "break" => self.update_breakpoint(true, args, out),
+            "d" | "delete" => self.update_breakpoint(false, args, out),
+            "i" | "info" => self.info(out),
+            "r" | "regs" => self.print_registers(out),
+            "csr" => self.print_csr(args, out),
+            "x" => self.examine(args, out),
+            "dis" => self.disassemble(args, out),
+            "set" => self.set_register(args, out),
+            "w" | "write" => self.write_memory(args, out),
+            "h" | "history" => self.history(args, out),
+            "q" | "quit" => return Ok(false),
+            "help" => writeln!(out, "{}", HELP).map_err(|e| e.to_string()),
+            _ => Err(format!("Unknown command '{}', try 'help'", command)),
+        };
+        if let Err(message) = result {
+            writeln!(out, "{}", message)?;
+        }
+        Ok(true)
+    }
+
+    // Numbers are hex with an optional 0x prefix, unless they name a symbol
+    fn parse_addr(&self, arg: Option<&&str>) -> Result<usize, String> {
+        let arg = arg.ok_or("Missing address")?;
+        if let Some(addr) = self.symbols.get(*arg) {
+            return Ok(*addr);
+        }
+        parse_hex(arg).map(|value| value as usize)
+    }
+
+    fn parse_count(arg: Option<&&str>) -> Result<usize, String> {
+        match arg {
+            Some(arg) => arg
+                .parse::<usize>()
+                .map_err(|_| format!("Invalid count '{}'", arg)),
+            None => Ok(1),
+        }
+    }
+
+    fn step(&mut self, args: &[&str], out: &mut impl Write) -> Result<(), String> {
+        let count = Self::parse_count(args.first())?;
+        self.resume(Some(count), out)
+    }
+
+    // Run for a number of instructions, or until a breakpoint if there is no limit
+    fn resume(&mut self, count: Option<usize>, out: &mut impl Write) -> Result<(), String> {
+        let mut executed = 0;
+        loop {
+            if let Some(halt) = self.cpu.step() {
+                writeln!(out, "Stopped by {:?}", halt).map_err(|e| e.to_string())?;
+                break;
+            }
+            executed += 1;
+            if count.is_some_and(|count| executed >= count) {
+                break;
+            }
+            if self.breakpoints.contains(&self.cpu.pc) {
+                writeln!(out, "Breakpoint at {:#010x}", self.cpu.pc).map_err(|e| e.to_string())?;
+                break;
+            }
+        }
+        self.print_instructions(self.cpu.pc, 1, out)
+    }
+
+    fn update_breakpoint(
+        &mut self,
+        insert: bool,
+        args: &[&str],
+        out: &mut impl Write,
+    ) -> Result<(), String> {
+        let addr = self.parse_addr(args.first())?;
+        if insert {
+            self.breakpoints.insert(addr);
+            writeln!(out, "Breakpoint at {:#010x}", addr).map_err(|e| e.to_string())
+        } else if self.breakpoints.remove(&addr) {
+            Ok(())
+        } else {
+            Err(format!("No breakpoint at {:#010x}", addr))
+        }
+    }
+
+    fn info(&self, out: &mut impl Write) -> Result<(), String> {
+        for addr in self.breakpoints.iter() {
+            writeln!(out, "Breakpoint at {:#010x}", addr).map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+
+    fn print_registers(&self, out: &mut impl Write) -> Result<(), String> {
+        let mut text = format!("pc  : {:#010x}  mode: {:?}\n", self.cpu.pc, self.cpu.mode());
+        for num in 0..32 {
+            text += &format!(
+                "{:<4}: {:#010x}{}",
+                pretty_register(&num),
+                self.cpu.read_register(num),
+                if num % 4 == 3 { "\n" } else { "  " }
+            );
+        }
+        write!(out, "{}", text).map_err(|e| e.to_string())
+    }
+
+    fn print_csr(&self, args: &[&str], out: &mut impl Write) -> Result<(), String> {
+        let arg = args.first().ok_or("Missing CSR")?;
+        let addr = match csr_by_name(arg) {
+            Some(addr) => addr,
+            None => parse_hex(arg)?,
+        };
+        writeln!(out, "{:#05x}: {:#010x}", addr, self.cpu.read_csr(addr)).map_err(|e| e.to_string())
+    }
+
+    fn read_word(&mut self, addr: usize) -> Result<u32, String> {
+        let mut bytes = [0u8; 4];
+        for (offset, byte) in bytes.iter_mut().enumerate() {
+            *byte = self
+                .cpu
+                .read_memory(addr + offset)
+                .map_err(|e| e.to_string())?;
+        }
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn examine(&mut self, args: &[&str], out: &mut impl Write) -> Result<(), String> {
+        let addr = self.parse_addr(args.first())?;
+        let count = Self::parse_count(args.get(1))?;
+        for i in 0..count {
+            let word_addr = addr + i * 4;
+            if i % 4 == 0 {
+                write!(out, "{:#010x}:", word_addr).map_err(|e| e.to_string())?;
+            }
+            write!(out, " {:08x}", self.read_word(word_addr)?).map_err(|e| e.to_string())?;
+            if i % 4 == 3 || i == count - 1 {
+                writeln!(out).map_err(|e| e.to_string())?;
+            }
+        }
+        Ok(())
+    }
+
+    fn disassemble(&mut self, args: &[&str], out: &mut impl Write) -> Result<(), String> {
+        let addr = match args.first() {
+            Some(_) => self.parse_addr(args.first())?,
+            None => self.cpu.pc,
+        };
+        let count = Self::parse_count(args.get(1))?;
+        self.print_instructions(addr, count, out)
+    }
+
+    fn print_instructions(
+        &mut self,
+        mut addr: usize,
+        count: usize,
+        out: &mut impl Write,
+    ) -> Result<(), String> {
+        for _ in 0..count {
+            // Only the lower half is guaranteed to exist for compressed instructions
+            let lower = self.read_word(addr).or_else(|_| {
+                let low = self.cpu.read_memory(addr).map_err(|e| e.to_string())?;
+                let high = self.cpu.read_memory(addr + 1).map_err(|e| e.to_string())?;
+                Ok::<u32, String>(u32::from_le_bytes([low, high, 0, 0]))
+            })?;
+            let instruction = if is_compressed(lower) {
+                lower & 0xffff
+            } else {
+                lower
+            };
+            writeln!(out, "{}", disassembled(addr, instruction)).map_err(|e| e.to_string())?;
+            addr += if is_compressed(instruction) { 2 } else { 4 };
+        }
+        Ok(())
+    }
+
+    fn set_register(&mut self, args: &[&str], out: &mut impl Write) -> Result<(), String> {
+        let name = args.first().ok_or("Missing register")?;
+        let value = parse_hex(args.get(1).ok_or("Missing value")?)?;
+        if *name == "pc" {
+            self.cpu.pc = value as usize;
+        } else {
+            let num = register_by_name(name).ok_or(format!("Unknown register '{}'", name))?;
+            self.cpu.write_register(num, value);
+        }
+        writeln!(out, "{} = {:#010x}", name, value).map_err(|e| e.to_string())
+    }
+
+    fn write_memory(&mut self, args: &[&str], out: &mut impl Write) -> Result<(), String> {
+        let addr = self.parse_addr(args.first())?;
+        let value = parse_hex(args.get(1).ok_or("Missing value")?)?;
+        for (offset, byte) in value.to_le_bytes().iter().enumerate() {
+            self.cpu
+                .write_memory(addr + offset, *byte)
+                .map_err(|e| e.to_string())?;
+        }
+        writeln!(out, "{:#010x} = {:#010x}", addr, value).map_err(|e| e.to_string())
+    }
+
+    fn history(&self, args: &[&str], out: &mut impl Write) -> Result<(), String> {
+        let count = match args.first() {
+            Some(_) => Self::parse_count(args.first())?,
+            None => HISTORY_SIZE,
+        };
+        let history = &self.cpu.debug.history;
+        for (pc, instruction) in history.iter().skip(history.len().saturating_sub(count)) {
+            writeln!(out, "{}", disassembled(*pc, *instruction)).map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+}
+
+fn disassembled(addr: usize, instruction: u32) -> String {
+    match decode(&instruction) {
+        Ok(decoded) => format!("{:#010x} | {:#010x} | {}", addr, instruction, decoded),
+        Err(_) => format!("{:#010x} | {:#010x} | <illegal>", addr, instruction),
+    }
+}
+
+fn parse_hex(arg: &str) -> Result<u32, String> {
+    let digits = arg.strip_prefix("0x").unwrap_or(arg);
+    u32::from_str_radix(digits, 16).map_err(|_| format!("Invalid number '{}'", arg))
+}
+
+// Accepts ABI names as well as x0-x31
+fn register_by_name(name: &str) -> Option<usize> {
+    if let Some(num) = name.strip_prefix('x').and_then(|num| num.parse().ok()) {
+        return (num < 32).then_some(num);
+    }
+    (0..32).find(|num| pretty_register(num) == name)
+}
+
+fn csr_by_name(name: &str) -> Option<u32> {
+    (0..0x1000)
+        .find(|addr| ArchCSRs::from_u32(*addr).is_some_and(|csr| format!("{:?}", csr) == name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::tests::program;
+
+    const RAM_START: usize = 0x8000_0000;
+
+    fn output(monitor: &mut Monitor, line: &str) -> String {
+        let mut out = Vec::new();
+        assert!(monitor.execute(line, &mut out).unwrap());
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_step_and_history() {
+        // addi ra, ra, 1 (x3)
+        let mut cpu = Cpu::new(program(&[0x00108093; 3]), 1024).unwrap();
+        let mut monitor = Monitor::new(&mut cpu, HashMap::new());
+
+        assert_eq!(
+            output(&mut monitor, "step 2"),
+            "0x80000008 | 0x00108093 | addi - ra, ra, 0x1 (I) \n"
+        );
+        assert_eq!(monitor.cpu.read_register(1), 2);
+        assert_eq!(output(&mut monitor, "history 1").lines().count(), 1);
+        assert_eq!(output(&mut monitor, "history").lines().count(), 2);
+    }
+    #[test]
+    fn test_breakpoint_symbol() {
+        let mut cpu = Cpu::new(program(&[0x00108093; 4]), 1024).unwrap();
+        let symbols = HashMap::from([("loop".to_string(), RAM_START + 8)]);
+        let mut monitor = Monitor::new(&mut cpu, symbols);
+
+        assert_eq!(
+            output(&mut monitor, "break loop"),
+            "Breakpoint at 0x80000008\n"
+        );
+        output(&mut monitor, "continue");
+        assert_eq!(monitor.cpu.pc, RAM_START + 8);
+        assert_eq!(output(&mut monitor, "delete loop"), "");
+        assert_eq!(
+            output(&mut monitor, "delete loop"),
+            "No breakpoint at 0x80000008\n"
+        );
+    }
+    #[test]
+    fn test_memory_and_registers() {
+        let mut cpu = Cpu::new(vec![0u8; 16], 1024).unwrap();
+        let mut monitor = Monitor::new(&mut cpu, HashMap::new());
+
+        output(&mut monitor, "write 0x80000004 deadbeef");
+        assert_eq!(
+            output(&mut monitor, "x 80000000 2"),
+            "0x80000000: 00000000 deadbeef\n"
+        );
+        output(&mut monitor, "set a0 0x1234");
+        output(&mut monitor, "set x11 0x10");
+        assert_eq!(monitor.cpu.read_register(10), 0x1234);
+        assert_eq!(monitor.cpu.read_register(11), 0x10);
+        assert_eq!(output(&mut monitor, "csr misa"), "0x301: 0x4014112d\n");
+        assert_eq!(
+            output(&mut monitor, "set foo 1"),
+            "Unknown register 'foo'\n"
+        );
+    }
+    #[test]
+    fn test_disassemble_compressed() {
+        // c.li ra, 1; addi sp, sp, 1
+        let ram: Vec<u8> = [0x4085u16, 0x0113, 0x0011]
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect();
+        let mut cpu = Cpu::new(ram, 1024).unwrap();
+        let mut monitor = Monitor::new(&mut cpu, HashMap::new());
+
+        let listing = output(&mut monitor, "dis 80000000 2");
+        let lines: Vec<&str> = listing.lines().collect();
+        assert_eq!(
+            lines[0],
+            "0x80000000 | 0x00004085 | addi - ra, zero, 0x1 (I) "
+        );
+        assert_eq!(
+            lines[1],
+            "0x80000002 | 0x00110113 | addi - sp, sp, 0x1 (I) "
+        );
+    }
+}
@@ -0,0 +1,85 @@
+use std::sync::OnceLock;
+
+use crate::bus::uart::UartInput;
+
+// Terminal settings to restore on exit, also from the SIGINT handler
+static ORIGINAL: OnceLock<libc::termios> = OnceLock::new();
+
+// Puts the host terminal into non-canonical mode without echo, so that
+// keystrokes go straight to the guest. Settings are restored on drop.
+pub struct RawTerminal;
+
+impl RawTerminal {
+    // Returns None if stdin is not a terminal
+    pub fn enable() -> Option<Self> {
+        unsafe {
+            if libc::isatty(libc::STDIN_FILENO) == 0 {
+                return None;
+            }
+            let mut termios: libc::termios = std::mem::zeroed();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut termios) != 0 {
+                return None;
+            }
+            ORIGINAL.get_or_init(|| termios);
+
+            // Ctrl-C still raises SIGINT, output processing stays on for newlines
+            termios.c_lflag &= !(libc::ICANON | libc::ECHO);
+            termios.c_cc[libc::VMIN] = 0;
+            termios.c_cc[libc::VTIME] = 0;
+            if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios) != 0 {
+                return None;
+            }
+            libc::signal(
+                libc::SIGINT,
+                restore_and_exit as extern "C" fn(libc::c_int) as libc::sighandler_t,
+            );
+        }
+        Some(Self)
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        restore();
+    }
+}
+
+fn restore() {
+    if let Some(original) = ORIGINAL.get() {
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, original);
+        }
+    }
+}
+
+extern "C" fn restore_and_exit(signal: libc::c_int) {
+    // Both calls are async-signal-safe
+    restore();
+    unsafe { libc::_exit(128 + signal) };
+}
+
+// Non-blocking reads from stdin for the UART receiver
+pub fn stdin_input() -> UartInput {
+    Box::new(|| {
+        let mut poll = libc::pollfd {
+            fd: libc::STDIN_FILENO,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let mut byte = 0u8;
+        unsafe {
+            if libc::poll(&mut poll, 1, 0) <= 0 || poll.revents & libc::POLLIN == 0 {
+                return None;
+            }
+            if libc::read(
+                libc::STDIN_FILENO,
+                &mut byte as *mut u8 as *mut libc::c_void,
+                1,
+            ) != 1
+            {
+                return None;
+            }
+        }
+        Some(byte)
+    })
+}
@@ -9,8 +9,17 @@ pub enum RVException {
     StoreAddressMisaligned(usize),
     StoreAccessFault(usize),
     EnvironmentCallU,
+    EnvironmentCallS,
     EnvironmentCallM,
-    TimerInterrupt,
+    InstructionPageFault(usize),
+    LoadPageFault(usize),
+    StorePageFault(usize),
+    SupervisorSoftwareInterrupt,
+    MachineSoftwareInterrupt,
+    SupervisorTimerInterrupt,
+    MachineTimerInterrupt,
+    SupervisorExternalInterrupt,
+    MachineExternalInterrupt,
 }
 
 impl RVException {
@@ -25,8 +34,17 @@ impl RVException {
             Self::StoreAddressMisaligned(_) => 6,
             Self::StoreAccessFault(_) => 7,
             Self::EnvironmentCallU => 8,
+            Self::EnvironmentCallS => 9,
             Self::EnvironmentCallM => 11,
-            Self::TimerInterrupt => 0x8000_0007,
+            Self::InstructionPageFault(_) => 12,
+            Self::LoadPageFault(_) => 13,
+            Self::StorePageFault(_) => 15,
+            Self::SupervisorSoftwareInterrupt => 0x8000_0001,
+            Self::MachineSoftwareInterrupt => 0x8000_0003,
+            Self::SupervisorTimerInterrupt => 0x8000_0005,
+            Self::MachineTimerInterrupt => 0x8000_0007,
+            Self::SupervisorExternalInterrupt => 0x8000_0009,
+            Self::MachineExternalInterrupt => 0x8000_000b,
         }
     }
 }